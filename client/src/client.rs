@@ -7,71 +7,424 @@ use futures::{
     Sink, SinkExt, Stream, StreamExt,
     stream::{FusedStream, SplitSink, SplitStream},
 };
-use shared_types::messages::ServerMessage;
-use tokio::net::TcpStream;
+use shared_types::{
+    messages::{
+        ClientRequest, DIRECT_MESSAGE_COMMAND, DOWNLOAD_COMMAND, FileMetadata, Frame,
+        ServerMessage, command_target,
+    },
+    protocol::{
+        ACCOUNT_ACTION_KEY, AccountAction, CAPABILITIES_KEY, Capabilities, Capability,
+        ENCODED_REQUESTS_PROTOCOL_VERSION, LAST_SEQUENCE_KEY, NEW_PASSWORD_KEY, PASSWORD_KEY,
+        PROTOCOL_VERSION, PROTOCOL_VERSION_KEY, REFUSAL_KEY, RESUMED_KEY, Refusal,
+        SESSION_TOKEN_KEY, USERNAME_KEY, decode_header_text, encode_header_text,
+        is_supported_protocol_version, parse_protocol_version,
+    },
+    transfer::{FileChunk, encode_frame},
+    wire_format::{EncodeError, SUBPROTOCOL_HEADER, WireFormat},
+};
+use tokio::{io::AsyncRead, net::TcpStream};
 use tokio_tungstenite::{
     MaybeTlsStream, WebSocketStream, connect_async,
-    tungstenite::{Message as WSMessage, client::IntoClientRequest, http::HeaderValue},
+    tungstenite::{
+        Error as WSError, Message as WSMessage,
+        client::IntoClientRequest,
+        http::{HeaderValue, Response},
+    },
 };
 
-use crate::error::ClientError;
+use crate::{
+    error::ClientError,
+    transfer::{FileTransfer, InMemoryFile},
+};
 
 pub async fn connect(
     username: String,
     maybe_password: Option<String>,
     server_address: String,
+) -> Result<ChatSession, ClientError> {
+    connect_with_wire_formats(username, maybe_password, server_address, &[]).await
+}
+
+/// Same as [connect] but asks the server for one of `wire_formats`, in
+/// order of preference. Without any the session uses JSON
+pub async fn connect_with_wire_formats(
+    username: String,
+    maybe_password: Option<String>,
+    server_address: String,
+    wire_formats: &[WireFormat],
+) -> Result<ChatSession, ClientError> {
+    let options = ConnectOptions {
+        wire_formats: wire_formats.to_vec(),
+        ..Default::default()
+    };
+    connect_with_options(username, maybe_password, server_address, options).await
+}
+
+/// How to sign in to a server with accounts
+#[derive(Debug, Clone)]
+pub enum Account {
+    Login {
+        password: String,
+    },
+    /// Create an account, nobody else can use the username afterwards
+    Register {
+        password: String,
+    },
+    /// Log in and replace the password for future logins
+    ChangePassword {
+        password: String,
+        new_password: String,
+    },
+}
+
+impl Account {
+    fn action(&self) -> AccountAction {
+        match self {
+            Account::Login { .. } => AccountAction::Login,
+            Account::Register { .. } => AccountAction::Register,
+            Account::ChangePassword { .. } => AccountAction::ChangePassword,
+        }
+    }
+}
+
+/// Where a previous session left off, see [ChatSession::resume]
+#[derive(Debug, Clone)]
+pub struct Resume {
+    pub session_token: String,
+    /// Sequence number of the last message received, see [ServerMessage::sequence]
+    pub last_sequence: u64,
+}
+
+/// Optional parts of the handshake, see [connect_with_options]
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Formats to ask the server for in order of preference, JSON without any
+    pub wire_formats: Vec<WireFormat>,
+    /// Join as a guest without one
+    pub account: Option<Account>,
+    /// Pick up a previous session, the server replays what was missed since.
+    /// If it can't the account or guest sign in is used instead
+    pub resume: Option<Resume>,
+}
+
+/// Same as [connect] with everything [ConnectOptions] allows for.
+/// `maybe_password` is still the password shared by everyone on the server,
+/// account passwords go in [ConnectOptions::account]
+pub async fn connect_with_options(
+    username: String,
+    maybe_password: Option<String>,
+    server_address: String,
+    options: ConnectOptions,
 ) -> Result<ChatSession, ClientError> {
     let mut req = server_address
         .into_client_request()
         .map_err(ClientError::CreateWSConnection)?;
 
+    req.headers_mut().append(
+        USERNAME_KEY,
+        HeaderValue::from_str(&encode_header_text(&username))?,
+    );
+    if let Some(account) = &options.account {
+        req.headers_mut().append(
+            ACCOUNT_ACTION_KEY,
+            HeaderValue::from_static(account.action().code()),
+        );
+        let (Account::Login { password }
+        | Account::Register { password }
+        | Account::ChangePassword { password, .. }) = account;
+        req.headers_mut().append(
+            PASSWORD_KEY,
+            HeaderValue::from_str(&encode_header_text(password))
+                .expect("encoded password to be visible ascii"),
+        );
+        if let Account::ChangePassword { new_password, .. } = account {
+            req.headers_mut().append(
+                NEW_PASSWORD_KEY,
+                HeaderValue::from_str(&encode_header_text(new_password))
+                    .expect("encoded password to be visible ascii"),
+            );
+        }
+    }
     req.headers_mut()
-        .append("username", HeaderValue::from_str(&username)?);
+        .append(PROTOCOL_VERSION_KEY, HeaderValue::from(PROTOCOL_VERSION));
+    req.headers_mut().append(
+        CAPABILITIES_KEY,
+        HeaderValue::from_str(&client_capabilities().to_string())
+            .expect("capability names to be visible ascii"),
+    );
+    if let Some(resume) = &options.resume {
+        req.headers_mut().append(
+            SESSION_TOKEN_KEY,
+            HeaderValue::from_str(&resume.session_token)?,
+        );
+        req.headers_mut()
+            .append(LAST_SEQUENCE_KEY, HeaderValue::from(resume.last_sequence));
+    }
+    // Servers that don't know about wire formats refuse the header
+    if !options.wire_formats.is_empty() {
+        req.headers_mut().append(
+            SUBPROTOCOL_HEADER,
+            HeaderValue::from_str(&WireFormat::subprotocol_header(&options.wire_formats))?,
+        );
+    }
 
-    let (ws_stream, resp) = connect_async(req)
-        .await
-        .map_err(ClientError::CreateWSConnection)?;
+    let (ws_stream, resp) = connect_async(req).await.map_err(|error| match error {
+        WSError::Http(response) => refused_connection_error(&response),
+        error => ClientError::CreateWSConnection(error),
+    })?;
+
+    let server_protocol_version = resp
+        .headers()
+        .get(PROTOCOL_VERSION_KEY)
+        .map(|protocol_version| protocol_version.to_str().unwrap_or_default());
+    let protocol_version = parse_protocol_version(server_protocol_version)
+        .filter(|protocol_version| is_supported_protocol_version(*protocol_version))
+        .ok_or_else(|| {
+            ClientError::IncompatibleServer(server_protocol_version.unwrap_or("none").into())
+        })?;
+    let capabilities = match resp.headers().get(CAPABILITIES_KEY) {
+        Some(capabilities) => {
+            Capabilities::from_header_value(capabilities.to_str().unwrap_or_default())
+        }
+        None => Capabilities::default(),
+    }
+    .intersection(&client_capabilities());
+
+    // Servers from before accounts use the name as it was asked for
+    let username = resp
+        .headers()
+        .get(USERNAME_KEY)
+        .and_then(|username| decode_header_text(username.as_bytes()).ok())
+        .unwrap_or(username);
+
+    let session_token = resp
+        .headers()
+        .get(SESSION_TOKEN_KEY)
+        .and_then(|session_token| Some(session_token.to_str().ok()?.to_string()));
+    let is_resumed = resp.headers().contains_key(RESUMED_KEY);
+    // Resumed sessions continue from the last message received before, the
+    // messages since then are replayed
+    let last_sequence = match (&options.resume, is_resumed) {
+        (Some(resume), true) => resume.last_sequence,
+        _ => resp
+            .headers()
+            .get(LAST_SEQUENCE_KEY)
+            .and_then(|sequence| sequence.to_str().ok()?.parse().ok())
+            .unwrap_or_default(),
+    };
+
+    // Tungstenite already checked the server picked one of the formats asked for
+    let wire_format = resp
+        .headers()
+        .get(SUBPROTOCOL_HEADER)
+        .and_then(|subprotocol| WireFormat::from_subprotocol(subprotocol.to_str().ok()?))
+        .unwrap_or_default();
 
     for (header, value) in resp.headers() {
-        if let Ok(string_value) = value.to_str() {
-            if header == shared_types::crypt::CRYPT_VALIDATION_KEY {
-                // Decrypt and compare value
-                // First layer: Base64
-                let encrypted_test_value = BASE64_STANDARD.decode(string_value)?;
-                // Second layer: simple_crypt (Contingent on Some(password))
-                let test_value = if let Some(ref password) = maybe_password {
-                    simple_crypt::decrypt(encrypted_test_value.as_slice(), password.as_bytes())
-                        .map_err(|_| ClientError::BadPassword)?
-                } else {
-                    // If there isnt then just compare base64 decoded val
-                    encrypted_test_value
-                };
-                if String::from_utf8_lossy(&test_value) != shared_types::crypt::CRYPT_VALIDATION_VAL
-                {
-                    return Err(ClientError::BadPassword);
-                }
+        if let Ok(string_value) = value.to_str()
+            && header == shared_types::crypt::CRYPT_VALIDATION_KEY
+        {
+            // Decrypt and compare value
+            // First layer: Base64
+            let encrypted_test_value = BASE64_STANDARD.decode(string_value)?;
+            // Second layer: simple_crypt (Contingent on Some(password))
+            let test_value = if let Some(ref password) = maybe_password {
+                simple_crypt::decrypt(encrypted_test_value.as_slice(), password.as_bytes())
+                    .map_err(|_| ClientError::BadPassword)?
+            } else {
+                // If there isnt then just compare base64 decoded val
+                encrypted_test_value
+            };
+            if String::from_utf8_lossy(&test_value) != shared_types::crypt::CRYPT_VALIDATION_VAL {
+                return Err(ClientError::BadPassword);
             }
         }
     }
 
     Ok(ChatSession {
         inner: ws_stream,
+        username,
+        session_token,
+        is_resumed,
+        last_sequence,
         password: maybe_password,
+        wire_format,
+        protocol_version,
+        capabilities,
+        maybe_pending_file: None,
     })
 }
 
+/// The server says why it refused the handshake in the refusal header and
+/// explains it in the body
+fn refused_connection_error(response: &Response<Option<Vec<u8>>>) -> ClientError {
+    let reason =
+        String::from_utf8_lossy(response.body().as_deref().unwrap_or_default()).into_owned();
+    let maybe_refusal = response
+        .headers()
+        .get(REFUSAL_KEY)
+        .and_then(|refusal| Refusal::from_code(refusal.to_str().ok()?));
+
+    match maybe_refusal {
+        Some(Refusal::InvalidUsername | Refusal::UsernameTaken) => {
+            ClientError::UsernameRefused(reason)
+        }
+        Some(Refusal::AccountRequired | Refusal::BadCredentials | Refusal::AccountRefused) => {
+            ClientError::AccountRefused(reason)
+        }
+        Some(Refusal::Forbidden) => ClientError::Forbidden(reason),
+        Some(Refusal::ServerFull) => ClientError::ServerFull(reason),
+        Some(Refusal::TooManyConnections) => ClientError::TooManyConnections(reason),
+        _ => ClientError::ConnectionRefused {
+            status: response.status().as_u16(),
+            reason,
+        },
+    }
+}
+
+/// Everything this client knows how to handle
+fn client_capabilities() -> Capabilities {
+    // Messages aren't encrypted yet, so encryption isn't asked for
+    [Capability::Files].into_iter().collect()
+}
+
 /// Struct that controls a sinlge chat session
 #[derive(Debug)]
 pub struct ChatSession {
     inner: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    username: String,
+    /// Not handed out by servers from before sessions could be resumed
+    session_token: Option<String>,
+    is_resumed: bool,
+    /// Of the last message received with a sequence number
+    last_sequence: u64,
     password: Option<String>,
+    wire_format: WireFormat,
+    protocol_version: u32,
+    /// Supported by both this client and the server
+    capabilities: Capabilities,
+    /// File sent as a whole whose chunks are still going out, one for every
+    /// time the connection is ready for more
+    maybe_pending_file: Option<InMemoryFile>,
+}
+
+impl ChatSession {
+    /// Name the server knows this user by, accounts keep the spelling they were registered with
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Whether the server accepted the session to resume, missed messages
+    /// arrive before anything else
+    pub fn is_resumed(&self) -> bool {
+        self.is_resumed
+    }
+
+    /// Where this session is at, to pass to [ConnectOptions::resume] once
+    /// the connection is lost. Once the session is split the reader has to
+    /// keep track of [ServerMessage::sequence] itself
+    pub fn resume(&self) -> Option<Resume> {
+        Some(Resume {
+            session_token: self.session_token.clone()?,
+            last_sequence: self.last_sequence,
+        })
+    }
+
+    /// How messages are encoded, as agreed on with the server
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    /// Version the server speaks
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Features both this client and the server support
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Send the chunks of the pending file one at a time, each once the
+    /// connection is ready for it
+    fn poll_send_pending_file(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), ClientError>> {
+        let (protocol_version, wire_format) = (self.protocol_version, self.wire_format);
+        while let Some(pending_file) = self.maybe_pending_file.as_mut() {
+            std::task::ready!(self.inner.poll_ready_unpin(cx)).map_err(ClientError::SendMessage)?;
+            let encode_result = match pending_file.next_chunk() {
+                Some((header, data)) => encode_request(
+                    protocol_version,
+                    wire_format,
+                    ClientRequest::FileChunk(header),
+                    data,
+                ),
+                None => {
+                    self.maybe_pending_file = None;
+                    break;
+                }
+            };
+            let ws_message = encode_result.inspect_err(|_| self.maybe_pending_file = None)?;
+            self.inner
+                .start_send_unpin(ws_message)
+                .map_err(ClientError::SendMessage)?;
+        }
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn encode_request(
+        &self,
+        request: ClientRequest,
+        data: &[u8],
+    ) -> Result<WSMessage, EncodeError> {
+        encode_request(self.protocol_version, self.wire_format, request, data)
+    }
+}
+
+/// Encode the request the way the protocol version of the connection expects
+fn encode_request(
+    protocol_version: u32,
+    wire_format: WireFormat,
+    request: ClientRequest,
+    data: &[u8],
+) -> Result<WSMessage, EncodeError> {
+    Ok(if protocol_version < ENCODED_REQUESTS_PROTOCOL_VERSION {
+        // Older servers only know raw text, commands and bare chunk frames
+        match request {
+            ClientRequest::Text(text) => WSMessage::text(text),
+            ClientRequest::DirectMessage { recipient, text } => WSMessage::text(format!(
+                "{DIRECT_MESSAGE_COMMAND} {} {text}",
+                command_target(&recipient)
+            )),
+            ClientRequest::DownloadFile { sha256 } => {
+                WSMessage::text(format!("{DOWNLOAD_COMMAND} {sha256}"))
+            }
+            ClientRequest::FileChunk(header) => {
+                WSMessage::binary(encode_frame(wire_format, &header, data)?)
+            }
+        }
+    } else {
+        match request.encode(wire_format, data)? {
+            Frame::Text(encoded_request) => WSMessage::text(encoded_request),
+            Frame::Binary(frame) => WSMessage::binary(frame),
+        }
+    })
 }
 
 /// The allowed types of messages that can be sent to the server
 #[derive(Debug)]
 pub enum ClientMessage {
     Text(String),
+    /// Whole file that is already in memory, sent as chunks
     File(String, Vec<u8>),
+    /// One chunk of a [FileTransfer]
+    FileChunk(FileChunk),
+    /// Recipient and text of a message only that user will see
+    DirectMessage(String, String),
+    /// Ask for the contents of the stored file with this SHA-256 hash
+    DownloadFile(String),
     // Treating disconnecting as a pseudo-message simplifies some logic
     Disconnect,
 }
@@ -89,6 +442,14 @@ impl ClientMessage {
     pub fn file(filename: impl ToString, file_as_bytes: impl Into<Vec<u8>>) -> Self {
         Self::File(filename.to_string(), file_as_bytes.into())
     }
+
+    pub fn direct_message(recipient: impl ToString, msg: impl ToString) -> Self {
+        Self::DirectMessage(recipient.to_string(), msg.to_string())
+    }
+
+    pub fn download_file(sha256: impl ToString) -> Self {
+        Self::DownloadFile(sha256.to_string())
+    }
 }
 
 impl Stream for ChatSession {
@@ -98,7 +459,8 @@ impl Stream for ChatSession {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.inner
+        let poll_result = self
+            .inner
             .poll_next_unpin(cx)
             .map(|ws_message| match ws_message {
                 None => None,
@@ -116,10 +478,26 @@ impl Stream for ChatSession {
                         Err(err) => Some(Err(ClientError::ParseIncomingMessage(err))),
                     }
                 }
+                // File contents, or any message with a binary wire format
+                Some(Ok(WSMessage::Binary(frame))) => Some(
+                    ServerMessage::decode_binary(self.wire_format, &frame)
+                        .map_err(ClientError::DecodeIncomingFrame),
+                ),
+                Some(Ok(WSMessage::Close(Some(close_frame)))) => Some(Err(
+                    ClientError::ClosedByServer(close_frame.reason.into_owned()),
+                )),
                 Some(Err(err)) => Some(Err(ClientError::ReceiveIncomingMessage(err))),
-                // Catches all messages that are not text
+                // Catches all messages that are not text or binary
                 _ => Some(Err(ClientError::IncomingMessageFormat)),
-            })
+            });
+        if let std::task::Poll::Ready(Some(Ok(ServerMessage {
+            sequence: Some(sequence),
+            ..
+        }))) = poll_result
+        {
+            self.last_sequence = self.last_sequence.max(sequence);
+        }
+        poll_result
     }
 }
 
@@ -132,44 +510,51 @@ impl FusedStream for ChatSession {
 impl Sink<ClientMessage> for ChatSession {
     type Error = ClientError;
 
+    /// Only ready once every chunk of a file sent before is out
     fn poll_ready(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::ready!(self.poll_send_pending_file(cx))?;
         self.inner
             .poll_ready_unpin(cx)
             .map_err(ClientError::SendMessage)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: ClientMessage) -> Result<(), Self::Error> {
-        match item {
-            ClientMessage::Text(msg) => {
-                let converted_to_ws_message = WSMessage::text(msg);
-                self.inner
-                    .start_send_unpin(converted_to_ws_message)
-                    .map_err(ClientError::SendMessage)
-            }
+        let ws_message = match item {
+            ClientMessage::Text(msg) => self.encode_request(ClientRequest::Text(msg), &[])?,
+            // Chunks go out as the connection is ready for them
             ClientMessage::File(filename, file_as_bytes) => {
-                let filename_to_ws_message = WSMessage::text(filename);
-                let file_to_ws_message = WSMessage::binary(file_as_bytes);
-                // Server expects filename to be sent immediately after the
-                // binary message
-                self.inner
-                    .start_send_unpin(file_to_ws_message)
-                    .and_then(|_| self.inner.start_send_unpin(filename_to_ws_message))
-                    .map_err(ClientError::SendMessage)
+                self.maybe_pending_file = Some(InMemoryFile::new(filename, file_as_bytes));
+                return Ok(());
             }
-            ClientMessage::Disconnect => self
-                .inner
-                .start_send_unpin(WSMessage::Close(None))
-                .map_err(ClientError::SendDisconnect),
-        }
+            ClientMessage::FileChunk(FileChunk { header, data }) => {
+                self.encode_request(ClientRequest::FileChunk(header), &data)?
+            }
+            ClientMessage::DirectMessage(recipient, text) => {
+                self.encode_request(ClientRequest::DirectMessage { recipient, text }, &[])?
+            }
+            ClientMessage::DownloadFile(sha256) => {
+                self.encode_request(ClientRequest::DownloadFile { sha256 }, &[])?
+            }
+            ClientMessage::Disconnect => {
+                return self
+                    .inner
+                    .start_send_unpin(WSMessage::Close(None))
+                    .map_err(ClientError::SendDisconnect);
+            }
+        };
+        self.inner
+            .start_send_unpin(ws_message)
+            .map_err(ClientError::SendMessage)
     }
 
     fn poll_flush(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::ready!(self.poll_send_pending_file(cx))?;
         self.inner
             .poll_flush_unpin(cx)
             .map_err(ClientError::SendMessage)
@@ -179,6 +564,7 @@ impl Sink<ClientMessage> for ChatSession {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::ready!(self.poll_send_pending_file(cx))?;
         self.inner
             .poll_close_unpin(cx)
             .map_err(ClientError::SendMessage)
@@ -196,10 +582,29 @@ pub trait ChatWrite {
         &mut self,
         message: T,
     ) -> impl Future<Output = Result<(), ClientError>> + Send;
-    fn send_file<S: ToString + Send, B: Into<Vec<u8>> + Send>(
+    /// Stream `total_size` bytes from `reader` to the server as a new
+    /// transfer, returning what the server will announce the file as
+    fn send_file<S: ToString + Send, R: AsyncRead + Unpin + Send>(
         &mut self,
         filename: S,
-        file_as_bytes: B,
+        total_size: u64,
+        reader: R,
+    ) -> impl Future<Output = Result<FileMetadata, ClientError>> + Send;
+    /// Send a single chunk of a [FileTransfer], for callers that want to
+    /// track progress or resume transfers themselves
+    fn send_file_chunk(
+        &mut self,
+        file_chunk: FileChunk,
+    ) -> impl Future<Output = Result<(), ClientError>> + Send;
+    fn send_direct_message<R: ToString + Send, T: ToString + Send>(
+        &mut self,
+        recipient: R,
+        message: T,
+    ) -> impl Future<Output = Result<(), ClientError>> + Send;
+    /// The contents arrive in pieces as [shared_types::messages::MessageContents::FileContents]
+    fn request_download<S: ToString + Send>(
+        &mut self,
+        sha256: S,
     ) -> impl Future<Output = Result<(), ClientError>> + Send;
     fn disconnect(&mut self) -> impl Future<Output = Result<(), ClientError>> + Send;
 }
@@ -209,16 +614,36 @@ impl ChatWrite for ChatSessionWriter {
         self.send(ClientMessage::Text(message.to_string())).await
     }
 
-    async fn send_file<S: ToString + Send, B: Into<Vec<u8>> + Send>(
+    async fn send_file<S: ToString + Send, R: AsyncRead + Unpin + Send>(
         &mut self,
         filename: S,
-        file_as_bytes: B,
+        total_size: u64,
+        reader: R,
+    ) -> Result<FileMetadata, ClientError> {
+        let mut file_transfer = FileTransfer::new(filename, total_size, reader);
+        while let Some(file_chunk) = file_transfer.next_chunk().await? {
+            self.send(ClientMessage::FileChunk(file_chunk)).await?;
+        }
+        Ok(file_transfer
+            .file_metadata()
+            .expect("metadata to be known after the last chunk"))
+    }
+
+    async fn send_file_chunk(&mut self, file_chunk: FileChunk) -> Result<(), ClientError> {
+        self.send(ClientMessage::FileChunk(file_chunk)).await
+    }
+
+    async fn send_direct_message<R: ToString + Send, T: ToString + Send>(
+        &mut self,
+        recipient: R,
+        message: T,
     ) -> Result<(), ClientError> {
-        self.send(ClientMessage::File(
-            filename.to_string(),
-            file_as_bytes.into(),
-        ))
-        .await
+        self.send(ClientMessage::direct_message(recipient, message))
+            .await
+    }
+
+    async fn request_download<S: ToString + Send>(&mut self, sha256: S) -> Result<(), ClientError> {
+        self.send(ClientMessage::download_file(sha256)).await
     }
 
     async fn disconnect(&mut self) -> Result<(), ClientError> {
@@ -231,16 +656,36 @@ impl ChatWrite for ChatSession {
         self.send(ClientMessage::Text(message.to_string())).await
     }
 
-    async fn send_file<S: ToString + Send, B: Into<Vec<u8>> + Send>(
+    async fn send_file<S: ToString + Send, R: AsyncRead + Unpin + Send>(
         &mut self,
         filename: S,
-        file_as_bytes: B,
+        total_size: u64,
+        reader: R,
+    ) -> Result<FileMetadata, ClientError> {
+        let mut file_transfer = FileTransfer::new(filename, total_size, reader);
+        while let Some(file_chunk) = file_transfer.next_chunk().await? {
+            self.send(ClientMessage::FileChunk(file_chunk)).await?;
+        }
+        Ok(file_transfer
+            .file_metadata()
+            .expect("metadata to be known after the last chunk"))
+    }
+
+    async fn send_file_chunk(&mut self, file_chunk: FileChunk) -> Result<(), ClientError> {
+        self.send(ClientMessage::FileChunk(file_chunk)).await
+    }
+
+    async fn send_direct_message<R: ToString + Send, T: ToString + Send>(
+        &mut self,
+        recipient: R,
+        message: T,
     ) -> Result<(), ClientError> {
-        self.send(ClientMessage::File(
-            filename.to_string(),
-            file_as_bytes.into(),
-        ))
-        .await
+        self.send(ClientMessage::direct_message(recipient, message))
+            .await
+    }
+
+    async fn request_download<S: ToString + Send>(&mut self, sha256: S) -> Result<(), ClientError> {
+        self.send(ClientMessage::download_file(sha256)).await
     }
 
     async fn disconnect(&mut self) -> Result<(), ClientError> {
@@ -1,10 +1,32 @@
 use thiserror::Error;
+use tokio_tungstenite::tungstenite::{Error as WSError, error::ProtocolError};
 
 #[derive(Error, Debug)]
 pub enum ClientError {
     #[error("Could not create a WS connection to the server: {0}")]
     CreateWSConnection(#[source] tokio_tungstenite::tungstenite::Error),
 
+    #[error("Server refused the connection ({status}): {reason}")]
+    ConnectionRefused { status: u16, reason: String },
+
+    #[error("Server refused the username: {0}")]
+    UsernameRefused(String),
+
+    #[error("Server refused to sign in to the account: {0}")]
+    AccountRefused(String),
+
+    #[error("Server does not let you in: {0}")]
+    Forbidden(String),
+
+    #[error("Server has no room for you: {0}")]
+    ServerFull(String),
+
+    #[error("Server refused another connection from this address: {0}")]
+    TooManyConnections(String),
+
+    #[error("Server speaks protocol version '{0}' which this client does not support")]
+    IncompatibleServer(String),
+
     #[error("Could not create the request needed to establish the WS connection")]
     CreateWSRequest(#[from] tokio_tungstenite::tungstenite::http::Error),
 
@@ -14,12 +36,24 @@ pub enum ClientError {
     #[error("Could not interpret message from server")]
     ParseIncomingMessage(#[from] serde_json::error::Error),
 
+    #[error("Could not interpret binary frame from server")]
+    DecodeIncomingFrame(#[source] shared_types::transfer::FrameDecodeError),
+
+    #[error("Server closed the connection: {0}")]
+    ClosedByServer(String),
+
     #[error("Message from server was not the expected format")]
     IncomingMessageFormat,
 
+    #[error("Could not encode message for the server")]
+    EncodeMessage(#[from] shared_types::wire_format::EncodeError),
+
     #[error("Could not send message to server")]
     SendMessage(#[source] tokio_tungstenite::tungstenite::Error),
 
+    #[error("Could not read the file being sent")]
+    ReadFile(#[source] std::io::Error),
+
     #[error("Could not properly send disconnect message to server")]
     SendDisconnect(#[source] tokio_tungstenite::tungstenite::Error),
 
@@ -32,3 +66,23 @@ pub enum ClientError {
     #[error("Password decrypted test string did not match expected string")]
     BadPassword,
 }
+
+impl ClientError {
+    /// Whether the connection to the server is gone, rather than a single
+    /// message failing. Whatever was cut off can be resumed once reconnected
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            ClientError::ReceiveIncomingMessage(error)
+            | ClientError::SendMessage(error)
+            | ClientError::SendDisconnect(error) => matches!(
+                error,
+                WSError::ConnectionClosed
+                    | WSError::AlreadyClosed
+                    | WSError::Io(_)
+                    | WSError::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+            ),
+            ClientError::ClosedByServer(_) => true,
+            _ => false,
+        }
+    }
+}
@@ -1,7 +1,14 @@
 mod client;
 mod error;
+mod transfer;
 
 pub use client::connect;
+pub use client::connect_with_options;
+pub use client::connect_with_wire_formats;
+
+pub use client::Account;
+pub use client::ConnectOptions;
+pub use client::Resume;
 
 pub use client::ChatSession;
 pub use client::ChatSessionReader;
@@ -11,3 +18,5 @@ pub use client::ChatWrite;
 pub use client::ClientMessage;
 
 pub use error::ClientError;
+
+pub use transfer::FileTransfer;
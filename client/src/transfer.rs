@@ -0,0 +1,155 @@
+use sha2::{Digest, Sha256};
+use shared_types::{
+    messages::FileMetadata,
+    transfer::{CHUNK_SIZE, ChunkHeader, FileChunk, TransferId, chunk_count, mime_type},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::ClientError;
+
+/// # File Transfer
+/// Reads a file from any [AsyncRead] source one chunk at a time, so the
+/// whole file never has to be held in memory while it is being sent
+#[derive(Debug)]
+pub struct FileTransfer<R> {
+    transfer_id: TransferId,
+    filename: String,
+    total_size: u64,
+    reader: R,
+    hasher: Sha256,
+    /// Known once the last chunk was read
+    sha256: Option<String>,
+    next_chunk_index: u64,
+    /// The server already has every chunk before this one
+    resume_chunk_index: u64,
+}
+
+impl<R: AsyncRead + Unpin> FileTransfer<R> {
+    /// Start a new transfer of `total_size` bytes read from `reader`
+    pub fn new(filename: impl ToString, total_size: u64, reader: R) -> Self {
+        Self::resume(TransferId::new_v4(), filename, total_size, reader, 0)
+    }
+
+    /// Continue a transfer the server already received `received_bytes` of.
+    /// The reader must start at the beginning of the file again since the
+    /// hash sent with the last chunk covers all of it
+    pub fn resume(
+        transfer_id: TransferId,
+        filename: impl ToString,
+        total_size: u64,
+        reader: R,
+        received_bytes: u64,
+    ) -> Self {
+        Self {
+            transfer_id,
+            filename: filename.to_string(),
+            total_size,
+            reader,
+            hasher: Sha256::new(),
+            sha256: None,
+            next_chunk_index: 0,
+            resume_chunk_index: received_bytes / CHUNK_SIZE,
+        }
+    }
+
+    pub fn transfer_id(&self) -> TransferId {
+        self.transfer_id
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    /// Bytes read from the source so far, including chunks skipped when resuming
+    pub fn read_bytes(&self) -> u64 {
+        (self.next_chunk_index * CHUNK_SIZE).min(self.total_size)
+    }
+
+    /// What the server announces the file as, `None` until the whole file was read
+    pub fn file_metadata(&self) -> Option<FileMetadata> {
+        Some(FileMetadata {
+            name: self.filename.clone(),
+            size: self.total_size,
+            mime_type: mime_type(&self.filename),
+            sha256: self.sha256.clone()?,
+        })
+    }
+
+    /// Read the next chunk the server still needs, `None` once the whole file was read
+    pub async fn next_chunk(&mut self) -> Result<Option<FileChunk>, ClientError> {
+        while self.next_chunk_index < chunk_count(self.total_size) {
+            let mut header = ChunkHeader {
+                transfer_id: self.transfer_id,
+                filename: self.filename.clone(),
+                total_size: self.total_size,
+                chunk_index: self.next_chunk_index,
+                sha256: None,
+            };
+            let mut data = vec![0; header.expected_data_len() as usize];
+            self.reader
+                .read_exact(&mut data)
+                .await
+                .map_err(ClientError::ReadFile)?;
+            self.hasher.update(&data);
+            self.next_chunk_index += 1;
+
+            if header.is_last_chunk() {
+                let sha256 = format!("{:x}", self.hasher.clone().finalize());
+                self.sha256 = Some(sha256.clone());
+                header.sha256 = Some(sha256);
+            }
+            // Chunks the server already has are still read for the hash
+            if header.chunk_index >= self.resume_chunk_index {
+                return Ok(Some(FileChunk { header, data }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A file that is already in memory, handed out one chunk at a time so each
+/// one waits for room on the connection. Chunk data is borrowed from the
+/// file contents rather than copied
+#[derive(Debug)]
+pub(crate) struct InMemoryFile {
+    transfer_id: TransferId,
+    filename: String,
+    contents: Vec<u8>,
+    sha256: String,
+    next_chunk_index: u64,
+}
+
+impl InMemoryFile {
+    pub(crate) fn new(filename: String, contents: Vec<u8>) -> Self {
+        Self {
+            transfer_id: TransferId::new_v4(),
+            filename,
+            sha256: format!("{:x}", Sha256::digest(&contents)),
+            contents,
+            next_chunk_index: 0,
+        }
+    }
+
+    /// Header and data of the next chunk, `None` once every chunk was taken.
+    /// An empty file still has its single empty chunk
+    pub(crate) fn next_chunk(&mut self) -> Option<(ChunkHeader, &[u8])> {
+        let total_size = self.contents.len() as u64;
+        if self.next_chunk_index >= chunk_count(total_size) {
+            return None;
+        }
+        let mut header = ChunkHeader {
+            transfer_id: self.transfer_id,
+            filename: self.filename.clone(),
+            total_size,
+            chunk_index: self.next_chunk_index,
+            sha256: None,
+        };
+        if header.is_last_chunk() {
+            header.sha256 = Some(self.sha256.clone());
+        }
+        let chunk_start = (self.next_chunk_index * CHUNK_SIZE) as usize;
+        let chunk_end = chunk_start + header.expected_data_len() as usize;
+        self.next_chunk_index += 1;
+        Some((header, &self.contents[chunk_start..chunk_end]))
+    }
+}
@@ -1,15 +1,36 @@
 mod chat_worker;
+mod clipboard;
+mod image_preview;
+mod status_badge;
+mod uploads;
+mod user_list;
 use anyhow::{Context, Result, bail};
 use chat_worker::ChatSender;
-use client::ChatWrite;
+use client::{Account, ChatWrite, ConnectOptions, Resume};
+use clipboard::PastedImage;
 use futures::Stream;
 use iced::{
-    Length, Task,
-    widget::{button, column, row, scrollable, text, text_input},
+    ContentFit, Element, Length, Task,
+    widget::{
+        button, column, container, image, mouse_area, row, scrollable, stack, text, text_input,
+    },
+};
+use image_preview::ImagePreview;
+use rfd::AsyncFileDialog;
+use shared_types::{
+    messages::{
+        FileMetadata, KICK_COMMAND, MessageContents, OnlineUser, ServerMessage, command_target,
+    },
+    protocol::{Capabilities, Capability},
+    transfer::TransferId,
 };
-use shared_types::messages::{MessageContents, ServerMessage};
 use size::Size as PrettyFileSize;
-use std::{fs::File, io::Read};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::Duration,
+};
+use uploads::{Upload, UploadId, UploadSource};
 
 use crate::AppUpdateMessage;
 
@@ -17,41 +38,225 @@ use super::ErrorPopupMessage;
 
 //TODO: Maybe make this adjustable?
 const MAXIMUM_FILE_SIZE_BYTES: PrettyFileSize = PrettyFileSize::from_const(1_073_741_824); // 1 GB
+/// Automatic reconnects stop after this many failed attempts in a row
+const MAXIMUM_RECONNECT_ATTEMPTS: u32 = 5;
+const MAXIMUM_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Identifies which of the open chat sessions a message belongs to
+pub(crate) type SessionId = usize;
+
+/// Everything needed to (re)connect to a server
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionDetails {
+    pub(crate) username: String,
+    pub(crate) password: Option<String>,
+    pub(crate) server_addr: String,
+    /// Signs in to the account with this password, joins as a guest without one
+    pub(crate) account_password: Option<String>,
+}
+
+impl ConnectionDetails {
+    /// Only the first connection registers, reconnects log in to the account it made
+    pub(crate) fn connect_options(&self, is_registering: bool) -> ConnectOptions {
+        let account = self.account_password.clone().map(|password| {
+            if is_registering {
+                Account::Register { password }
+            } else {
+                Account::Login { password }
+            }
+        });
+        ConnectOptions {
+            account,
+            ..Default::default()
+        }
+    }
+}
+
+/// Where a chat session is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SessionState {
+    /// Waiting for the chat worker to start
+    Connecting,
+    Connected,
+    /// A new connection to the same server is being made
+    Reconnecting,
+    /// The connection ended, the history is kept until the user
+    /// reconnects or closes the session
+    Disconnected,
+}
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub(crate) struct ChatPage {
+    session_id: SessionId,
+    connection_details: ConnectionDetails,
+    /// Shown in the session tabs, e.g. `username@server`
+    title: String,
+    state: SessionState,
+    /// Reader and worker tasks of the current connection, a new connection
+    /// can only be made once both of them have stopped
+    is_reader_running: bool,
+    is_worker_running: bool,
+    /// Set when the user asked to leave so the session gets closed instead
+    /// of offering to reconnect
+    is_disconnect_requested: bool,
+    /// Seconds left until the next automatic reconnect, `None` when
+    /// no reconnect is scheduled
+    reconnect_countdown: Option<u64>,
+    /// Reconnects tried since the connection was last established
+    reconnect_attempts: u32,
+    /// Features both the client and the server of the current connection support
+    capabilities: Capabilities,
+    /// Where the current connection is at, so a reconnect can pick up the missed messages
+    maybe_resume: Option<Resume>,
+    unread_messages: usize,
     chat_messages: Vec<ServerMessage>,
     chat_input: String,
     chat_sender: Option<ChatSender>,
+    online_users: Vec<OnlineUser>,
+    /// When set, sent messages only go to this user
+    direct_message_recipient: Option<String>,
+    /// Contents of files downloaded from the server, keyed by their SHA-256
+    downloaded_files: HashMap<String, Vec<u8>>,
+    /// Files requested from the server that haven't fully arrived yet, with
+    /// the contents received so far
+    pending_downloads: HashMap<String, Vec<u8>>,
+    /// Decoded previews of downloaded images, keyed by their SHA-256
+    image_previews: HashMap<String, ImagePreview>,
+    expanded_image: Option<String>,
+    /// Image pasted from the clipboard, only sent once the user confirms
+    pasted_image: Option<PastedImage>,
+    /// Something that worked out, such as where a file was saved, shown
+    /// until dismissed
+    notice: Option<String>,
+    uploads: BTreeMap<UploadId, Upload>,
+    next_upload_id: UploadId,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum ChatPageMessage {
-    ResetChatWorker,
     WorkerReady(ChatSender),
+    WorkerStopped,
+    ConnectionClosed,
+    Reconnect,
+    ReconnectFailed(String),
+    /// Sent every second while a reconnect is scheduled
+    ReconnectCountdownTick,
     UpdateChatInput(String),
     SendMessage(String),
     AddMessageToHistory(ServerMessage),
     AttemptSendFile,
+    /// Picked in the file dialog or dropped onto the window
+    UploadFile(PathBuf),
+    /// Total size of the file, known once the upload opened it
+    UploadStarted(UploadId, u64),
+    /// Every chunk was handed to the worker, waiting on the server to store them
+    UploadSent(UploadId, FileMetadata),
+    UploadFinished(UploadId),
+    /// A chunk of the upload could not be sent, for the reason given
+    UploadFailed(TransferId, String),
+    CancelUpload(UploadId),
+    PasteFromClipboard,
+    ClipboardImageReady(PastedImage),
+    SendPastedImage,
+    CancelPastedImage,
+    ShowNotice(String),
+    DismissNotice,
+    /// Ask the server for the contents of the file with this SHA-256
+    DownloadFile(String),
+    SaveFile(usize),
+    ImagePreviewReady(String, ImagePreview),
+    ExpandImage(String),
+    CloseExpandedImage,
+    MentionUser(String),
+    StartDirectMessage(String),
+    CancelDirectMessage,
+    /// Ask the server to disconnect the user, only offered to moderators and admins
+    KickUser(String),
     Disconnect,
 }
 
-impl From<ChatPageMessage> for AppUpdateMessage {
-    fn from(val: ChatPageMessage) -> Self {
-        AppUpdateMessage::ChatPageMessage(val)
+impl ChatPageMessage {
+    /// Whether this adds something to the chat history the user hasn't seen
+    pub(crate) fn is_new_chat_message(&self) -> bool {
+        matches!(
+            self,
+            ChatPageMessage::AddMessageToHistory(ServerMessage {
+                contents: MessageContents::Text(_)
+                    | MessageContents::File(_)
+                    | MessageContents::DirectMessage { .. },
+                ..
+            })
+        )
+    }
+
+    /// Wrap the message so the main app can route it to the right session
+    pub(crate) fn for_session(self, session_id: SessionId) -> AppUpdateMessage {
+        AppUpdateMessage::ChatPageMessage(session_id, self)
     }
 }
 
 impl ChatPage {
-    pub(crate) fn view(&self) -> iced::Element<ChatPageMessage> {
-        let messages = self.chat_messages.iter().fold(
+    pub(crate) fn new(session_id: SessionId, connection_details: ConnectionDetails) -> Self {
+        Self {
+            session_id,
+            title: format!(
+                "{}@{}",
+                connection_details.username, connection_details.server_addr
+            ),
+            connection_details,
+            state: SessionState::Connecting,
+            is_reader_running: false,
+            is_worker_running: false,
+            is_disconnect_requested: false,
+            reconnect_countdown: None,
+            reconnect_attempts: 0,
+            capabilities: Capabilities::default(),
+            maybe_resume: None,
+            unread_messages: 0,
+            chat_messages: vec![],
+            chat_input: String::new(),
+            chat_sender: None,
+            online_users: vec![],
+            direct_message_recipient: None,
+            downloaded_files: HashMap::new(),
+            pending_downloads: HashMap::new(),
+            image_previews: HashMap::new(),
+            expanded_image: None,
+            pasted_image: None,
+            notice: None,
+            uploads: BTreeMap::new(),
+            next_upload_id: 0,
+        }
+    }
+
+    pub(crate) fn view(&self) -> iced::Element<'_, ChatPageMessage> {
+        let messages = self.chat_messages.iter().enumerate().fold(
             column![].align_x(iced::Alignment::Start).spacing(2),
-            |col, message| {
+            |col, (message_idx, message)| {
                 let message_author = text(format!("{}:", message.author));
-                let message_contents = match &message.contents {
-                    MessageContents::Text(txt) => text(txt),
-                    // TODO: Add functionality to actually download file
-                    MessageContents::File { name: _, contents: _ } => text("This is a file wow"),
+                let message_contents: Element<ChatPageMessage> = match &message.contents {
+                    MessageContents::Text(txt) => text(txt).into(),
+                    MessageContents::DirectMessage {
+                        recipient,
+                        text: txt,
+                    } => {
+                        if recipient == self.username() {
+                            text(format!("(private) {txt}")).into()
+                        } else {
+                            text(format!("(to {recipient}) {txt}")).into()
+                        }
+                    }
+                    // Never added to the history, shown elsewhere instead
+                    MessageContents::UserList(_)
+                    | MessageContents::TransferProgress { .. }
+                    | MessageContents::TransferRefused { .. }
+                    | MessageContents::FileContents { .. }
+                    | MessageContents::DownloadFailed { .. } => {
+                        return col;
+                    }
+                    MessageContents::File(file_metadata) => {
+                        self.file_view(message_idx, file_metadata)
+                    }
                 };
                 let message_row = row!(message_author, message_contents)
                     .spacing(2)
@@ -69,7 +274,11 @@ impl ChatPage {
             .padding(5);
 
         let chat_open_file_button = button(text("Open"))
-            .on_press(ChatPageMessage::AttemptSendFile)
+            .on_press_maybe(
+                self.capabilities
+                    .contains(Capability::Files)
+                    .then_some(ChatPageMessage::AttemptSendFile),
+            )
             .padding(5);
 
         let chat_submit_button = button(text("Send"))
@@ -78,26 +287,118 @@ impl ChatPage {
         let chat_disconnect_button = button(text("Disconnect"))
             .padding(5)
             .on_press(ChatPageMessage::Disconnect);
-        let controls = row!(
-            chat_input,
-            chat_submit_button,
-            chat_open_file_button,
-            chat_disconnect_button
-        )
+        let controls: Element<ChatPageMessage> = match self.state {
+            SessionState::Connected => row!(
+                chat_input,
+                chat_submit_button,
+                chat_open_file_button,
+                chat_disconnect_button
+            )
+            .into(),
+            SessionState::Connecting | SessionState::Reconnecting => {
+                text("Messages can be sent once connected").into()
+            }
+            SessionState::Disconnected => row!(
+                text("Messages can be sent once reconnected"),
+                button(text("Close"))
+                    .on_press(ChatPageMessage::Disconnect)
+                    .padding(5)
+            )
+            .spacing(5)
+            .align_y(iced::Alignment::Center)
+            .into(),
+        };
+        let controls = match &self.direct_message_recipient {
+            Some(recipient) => column!(
+                row!(
+                    text(format!("Direct message to {recipient}")),
+                    button(text("Cancel"))
+                        .on_press(ChatPageMessage::CancelDirectMessage)
+                        .padding(2)
+                )
+                .spacing(5)
+                .align_y(iced::Alignment::Center),
+                controls
+            )
+            .spacing(2),
+            None => column!(controls),
+        }
         .height(Length::FillPortion(1));
 
-        column!(chat, controls).into()
+        let chat_page = row!(
+            column!(
+                self.status_badge_view(),
+                chat,
+                self.uploads_view(),
+                self.notice_view(),
+                controls
+            )
+            .width(Length::FillPortion(4)),
+            container(self.user_list_view()).width(Length::FillPortion(1))
+        )
+        .spacing(5);
+
+        if let Some(pasted_image) = &self.pasted_image {
+            let confirm_paste = container(
+                column!(
+                    text("Send this image?"),
+                    image(pasted_image.preview.thumbnail.clone()),
+                    row!(
+                        button(text("Send"))
+                            .on_press(ChatPageMessage::SendPastedImage)
+                            .padding(5),
+                        button(text("Cancel"))
+                            .on_press(ChatPageMessage::CancelPastedImage)
+                            .padding(5)
+                    )
+                    .spacing(5)
+                )
+                .spacing(10)
+                .align_x(iced::Alignment::Center),
+            )
+            .center(Length::Fill)
+            .style(container::dark);
+            return stack![chat_page, confirm_paste].into();
+        }
+
+        match self
+            .expanded_image
+            .as_ref()
+            .and_then(|sha256| self.image_previews.get(sha256))
+        {
+            Some(preview) => {
+                let expanded_image = mouse_area(
+                    container(
+                        image(preview.full_size.clone())
+                            .content_fit(ContentFit::Contain)
+                            .width(Length::Fill)
+                            .height(Length::Fill),
+                    )
+                    .padding(20)
+                    .style(container::dark),
+                )
+                .on_press(ChatPageMessage::CloseExpandedImage);
+                stack![chat_page, expanded_image].into()
+            }
+            None => chat_page.into(),
+        }
     }
 
     pub(crate) fn update(&mut self, message: ChatPageMessage) -> impl Into<Task<AppUpdateMessage>> {
         match message {
             ChatPageMessage::SendMessage(message_text) => {
                 if let Some(ref mut sender) = self.chat_sender {
-                    match sender.try_send(client::ClientMessage::text(message_text)) {
+                    let client_message = match &self.direct_message_recipient {
+                        Some(recipient) => {
+                            client::ClientMessage::direct_message(recipient, message_text)
+                        }
+                        None => client::ClientMessage::text(message_text),
+                    };
+                    match sender.try_send(client_message.into()) {
                         Ok(_) => {
                             self.chat_input.clear();
                             return Task::none();
-                        },
+                        }
                         Err(err) => {
                             return Task::done(ErrorPopupMessage::AddError(err.to_string()).into());
                         }
@@ -105,54 +406,120 @@ impl ChatPage {
                 }
             }
             ChatPageMessage::AttemptSendFile => {
-                let maybe_file = rfd::FileDialog::new().pick_file();
-
-                let process_file_result: Result<(String, Vec<u8>)> = maybe_file
-                    .context("File dialog did not return a ")
-                    // Make into (name, path) tuple
-                    .and_then(|path_buf| {
-                        Ok((
-                            // If not file indicates logic error with my code and RFD
-                            // need something stricter than '?' operator so using expect
-                            path_buf
-                                .file_name()
-                                .expect("file dialog to only give file")
-                                .to_string_lossy() // Not big deal if name is a bit mangled
-                                .into(),
-                            File::open(path_buf)?,
-                        ))
-                    })
-                    // Make into (name, file contents) tuple
-                    .and_then(|(filename, mut file)| {
-                        
-                        let file_metadata = file
-                            .metadata()
-                            .context("Could not get file metadata to check for file size")?;
-                        // Need some sort of protection against overflowing memory
-                        // by accident by selecting a very large file
-                        let file_size = PrettyFileSize::from_bytes(file_metadata.len());
-                        if file_size <= MAXIMUM_FILE_SIZE_BYTES {
-                            let mut buf = vec![];
-                            let _ = file.read_to_end(&mut buf).context("Error while reading file")?;
-                            Ok((filename, buf))
-                        } else {
-                            bail!("Selected file was too large: {file_size} (Maximum: {MAXIMUM_FILE_SIZE_BYTES})");
-                        }
+                let session_id = self.session_id;
+                return Task::future(AsyncFileDialog::new().pick_file()).then(
+                    move |maybe_file_handle| match maybe_file_handle {
+                        Some(file_handle) => Task::done(
+                            ChatPageMessage::UploadFile(file_handle.path().to_path_buf())
+                                .for_session(session_id),
+                        ),
+                        // User closed the dialog without picking a file
+                        None => Task::none(),
+                    },
+                );
+            }
+            ChatPageMessage::UploadFile(path) => return self.add_upload(UploadSource::Path(path)),
+            ChatPageMessage::UploadStarted(upload_id, total_bytes) => {
+                if let Some(upload) = self.uploads.get_mut(&upload_id) {
+                    upload.total_bytes = total_bytes;
+                }
+            }
+            ChatPageMessage::UploadSent(upload_id, file_metadata) => {
+                return self.upload_sent(upload_id, file_metadata);
+            }
+            ChatPageMessage::UploadFinished(upload_id) => _ = self.uploads.remove(&upload_id),
+            ChatPageMessage::UploadFailed(transfer_id, reason) => {
+                return self.refuse_upload(transfer_id, &reason);
+            }
+            ChatPageMessage::CancelUpload(upload_id) => {
+                if let Some(upload) = self.uploads.remove(&upload_id) {
+                    upload.cancel();
+                }
+            }
+            ChatPageMessage::PasteFromClipboard => {
+                // Nothing to offer sending to if the session is not connected
+                if self.chat_sender.is_some() {
+                    let session_id = self.session_id;
+                    return Task::future(clipboard::read_clipboard_image()).then(
+                        move |read_result| match read_result {
+                            Ok(Some(pasted_image)) => Task::done(
+                                ChatPageMessage::ClipboardImageReady(pasted_image)
+                                    .for_session(session_id),
+                            ),
+                            // Pasted text is handled by the chat input itself
+                            Ok(None) => Task::none(),
+                            Err(err) => {
+                                Task::done(ErrorPopupMessage::AddError(format!("{err:#}")).into())
+                            }
+                        },
+                    );
+                }
+            }
+            ChatPageMessage::SendPastedImage => {
+                if let Some(PastedImage { contents, .. }) = self.pasted_image.take() {
+                    return self.add_upload(UploadSource::Memory {
+                        filename: clipboard::PASTED_IMAGE_FILENAME.to_string(),
+                        contents: contents.into(),
                     });
-
-                if let Some(ref mut sender) = self.chat_sender {
-                    
-                    match process_file_result.and_then(|(filename, file_contents)| {
-                        sender.try_send(client::ClientMessage::file(filename, file_contents)).map_err(anyhow::Error::from)
-                    }) {
-                        Ok(_) => return Task::none(),
-                        Err(err) => return Task::done(ErrorPopupMessage::AddError(err.to_string()).into()),
+                }
+            }
+            ChatPageMessage::DownloadFile(sha256) => {
+                let Some(ref mut sender) = self.chat_sender else {
+                    return Task::done(
+                        ErrorPopupMessage::AddError(
+                            "Files can only be downloaded while connected".to_string(),
+                        )
+                        .into(),
+                    );
+                };
+                match sender.try_send(client::ClientMessage::download_file(&sha256).into()) {
+                    Ok(_) => _ = self.pending_downloads.insert(sha256, Vec::new()),
+                    Err(err) => {
+                        return Task::done(ErrorPopupMessage::AddError(err.to_string()).into());
                     }
                 }
             }
+            ChatPageMessage::SaveFile(message_idx) => {
+                if let Some(ServerMessage {
+                    contents: MessageContents::File(file_metadata),
+                    ..
+                }) = self.chat_messages.get(message_idx)
+                    && let Some(file_contents) = self.downloaded_files.get(&file_metadata.sha256)
+                {
+                    // Clone so the write can happen off the UI thread
+                    let save_future = save_file(file_metadata.name.clone(), file_contents.clone());
+                    let session_id = self.session_id;
+                    return Task::future(save_future).then(move |save_result| match save_result {
+                        Ok(Some(saved_path)) => Task::done(
+                            ChatPageMessage::ShowNotice(format!(
+                                "Saved file to {}",
+                                saved_path.display()
+                            ))
+                            .for_session(session_id),
+                        ),
+                        // User closed the dialog without picking a location
+                        Ok(None) => Task::none(),
+                        Err(err) => Task::done(ErrorPopupMessage::AddError(err.to_string()).into()),
+                    });
+                }
+            }
+            ChatPageMessage::KickUser(name) => {
+                if let Some(ref mut sender) = self.chat_sender
+                    && let Err(err) = sender.try_send(
+                        client::ClientMessage::text(format!(
+                            "{KICK_COMMAND} {}",
+                            command_target(&name)
+                        ))
+                        .into(),
+                    )
+                {
+                    return Task::done(ErrorPopupMessage::AddError(err.to_string()).into());
+                }
+            }
             ChatPageMessage::Disconnect => {
+                self.is_disconnect_requested = true;
                 if let Some(ref mut sender) = self.chat_sender {
-                    match sender.try_send(client::ClientMessage::disconnect_message()) {
+                    match sender.try_send(client::ClientMessage::disconnect_message().into()) {
                         Ok(_) => return Task::none(),
                         Err(err) => {
                             return Task::done(ErrorPopupMessage::AddError(err.to_string()).into());
@@ -161,31 +528,348 @@ impl ChatPage {
                 }
             }
 
+            ChatPageMessage::AddMessageToHistory(ServerMessage {
+                contents: MessageContents::UserList(online_users),
+                ..
+            }) => {
+                // Stop a direct message to someone who has left
+                if self
+                    .direct_message_recipient
+                    .as_ref()
+                    .is_some_and(|recipient| {
+                        !online_users.iter().any(|user| user.name == *recipient)
+                    })
+                {
+                    self.direct_message_recipient = None;
+                }
+                self.online_users = online_users;
+            }
+            ChatPageMessage::AddMessageToHistory(ServerMessage {
+                contents:
+                    MessageContents::TransferProgress {
+                        transfer_id,
+                        received_bytes,
+                    },
+                ..
+            }) => return self.update_received_bytes(transfer_id, received_bytes),
+            ChatPageMessage::AddMessageToHistory(ServerMessage {
+                contents:
+                    MessageContents::TransferRefused {
+                        transfer_id,
+                        reason,
+                    },
+                ..
+            }) => return self.refuse_upload(transfer_id, &reason),
+            ChatPageMessage::AddMessageToHistory(ServerMessage {
+                contents:
+                    MessageContents::FileContents {
+                        sha256,
+                        offset,
+                        total_size,
+                        contents: received_contents,
+                    },
+                ..
+            }) => {
+                let downloaded_contents = self.pending_downloads.entry(sha256.clone()).or_default();
+                // A download asked for again starts over
+                if offset == 0 {
+                    downloaded_contents.clear();
+                }
+                downloaded_contents.extend_from_slice(&received_contents);
+                if (downloaded_contents.len() as u64) < total_size {
+                    return Task::none();
+                }
+                let contents = self.pending_downloads.remove(&sha256).unwrap_or_default();
+                let maybe_image_contents =
+                    image_preview::is_previewable_image(&contents).then(|| contents.clone());
+                self.downloaded_files.insert(sha256.clone(), contents);
+
+                if let Some(image_contents) = maybe_image_contents {
+                    let session_id = self.session_id;
+                    return Task::perform(
+                        image_preview::decode_image_preview(image_contents),
+                        move |decode_result| match decode_result {
+                            Ok(preview) => {
+                                ChatPageMessage::ImagePreviewReady(sha256.clone(), preview)
+                                    .for_session(session_id)
+                            }
+                            Err(err) => ErrorPopupMessage::AddError(format!("{err:#}")).into(),
+                        },
+                    );
+                }
+            }
+            ChatPageMessage::AddMessageToHistory(ServerMessage {
+                contents: MessageContents::DownloadFailed { sha256, reason },
+                ..
+            }) => {
+                // Lets the user try again
+                self.pending_downloads.remove(&sha256);
+                return Task::done(
+                    ErrorPopupMessage::AddError(format!("Could not download file: {reason}"))
+                        .into(),
+                );
+            }
+            ChatPageMessage::AddMessageToHistory(msg) => {
+                if let Some(sequence) = msg.sequence
+                    && let Some(ref mut resume) = self.maybe_resume
+                {
+                    resume.last_sequence = resume.last_sequence.max(sequence);
+                }
+                self.chat_messages.push(msg);
+            }
+
             // Simple Updaters
-            ChatPageMessage::AddMessageToHistory(msg) => self.chat_messages.push(msg),
+            ChatPageMessage::ImagePreviewReady(sha256, preview) => {
+                _ = self.image_previews.insert(sha256, preview)
+            }
+            ChatPageMessage::ExpandImage(sha256) => self.expanded_image = Some(sha256),
+            ChatPageMessage::CloseExpandedImage => self.expanded_image = None,
+            ChatPageMessage::ClipboardImageReady(pasted_image) => {
+                self.pasted_image = Some(pasted_image)
+            }
+            ChatPageMessage::CancelPastedImage => self.pasted_image = None,
+            ChatPageMessage::ShowNotice(notice) => self.notice = Some(notice),
+            ChatPageMessage::DismissNotice => self.notice = None,
+            ChatPageMessage::MentionUser(name) => self.chat_input.push_str(&format!("@{name} ")),
+            ChatPageMessage::StartDirectMessage(name) => self.direct_message_recipient = Some(name),
+            ChatPageMessage::CancelDirectMessage => self.direct_message_recipient = None,
             ChatPageMessage::UpdateChatInput(new_chat_input) => self.chat_input = new_chat_input,
-            ChatPageMessage::ResetChatWorker => {
-                self.chat_sender = None;
-                self.chat_messages.clear();
-                self.chat_input.clear();
+            ChatPageMessage::Reconnect => {
+                if !self.can_reconnect() {
+                    return Task::none();
+                }
+                self.state = SessionState::Reconnecting;
+                self.reconnect_countdown = None;
+                self.reconnect_attempts += 1;
+                let ConnectionDetails {
+                    username,
+                    password,
+                    server_addr,
+                    ..
+                } = self.connection_details.clone();
+                let connect_options = ConnectOptions {
+                    resume: self.maybe_resume.clone(),
+                    ..self.connection_details.connect_options(false)
+                };
+                let session_id = self.session_id;
+                return Task::perform(
+                    client::connect_with_options(username, password, server_addr, connect_options),
+                    move |connection_result| match connection_result {
+                        Ok(chat_session) => {
+                            AppUpdateMessage::ResumeChat(session_id, Box::new(chat_session))
+                        }
+                        Err(err) => ChatPageMessage::ReconnectFailed(err.to_string())
+                            .for_session(session_id),
+                    },
+                );
             }
+            ChatPageMessage::ReconnectFailed(err) => {
+                self.state = SessionState::Disconnected;
+                self.schedule_reconnect();
+                return Task::done(ErrorPopupMessage::AddError(err).into());
+            }
+            ChatPageMessage::ReconnectCountdownTick => {
+                if let Some(seconds_left) = self.reconnect_countdown {
+                    let seconds_left = seconds_left.saturating_sub(1);
+                    self.reconnect_countdown = Some(seconds_left);
+                    // Keeps waiting at zero if the old connection is still shutting down
+                    if seconds_left == 0 && self.can_reconnect() {
+                        return self.update(ChatPageMessage::Reconnect).into();
+                    }
+                }
+            }
+
+            // Lifecycle updaters
             ChatPageMessage::WorkerReady(chat_worker_sender) => {
-                self.chat_sender = Some(chat_worker_sender)
+                self.chat_sender = Some(chat_worker_sender);
+                self.state = SessionState::Connected;
+                self.reconnect_attempts = 0;
+                return self.resume_uploads();
+            }
+            ChatPageMessage::WorkerStopped => {
+                self.is_worker_running = false;
+                self.chat_sender = None;
+                self.connection_lost();
+            }
+            ChatPageMessage::ConnectionClosed => {
+                self.is_reader_running = false;
+                // Dropping the sender stops the worker if it is still running
+                self.chat_sender = None;
+                self.connection_lost();
+                // Downloads cut off by the connection can be asked for again
+                self.pending_downloads.clear();
+                self.online_users.clear();
+                self.direct_message_recipient = None;
             }
         };
 
         Task::none()
     }
 
-    /// Convenience method to determine if user is in a chat
-    pub(crate) fn is_in_chat(&self) -> bool {
-        self.chat_sender.is_some()
+    pub(crate) fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub(crate) fn username(&self) -> &str {
+        &self.connection_details.username
+    }
+
+    /// Mark the reader and worker of a new connection as running, must be
+    /// called whenever they are started for this session
+    pub(crate) fn begin_connection(
+        &mut self,
+        capabilities: Capabilities,
+        maybe_resume: Option<Resume>,
+    ) {
+        self.capabilities = capabilities;
+        self.maybe_resume = maybe_resume;
+        self.is_reader_running = true;
+        self.is_worker_running = true;
+        self.is_disconnect_requested = false;
+    }
+
+    /// Name, size and type of a file in the history. Its contents are only
+    /// downloaded when asked for, after which it can be saved or previewed
+    fn file_view<'a>(
+        &'a self,
+        message_idx: usize,
+        file_metadata: &'a FileMetadata,
+    ) -> Element<'a, ChatPageMessage> {
+        let file_size = PrettyFileSize::from_bytes(file_metadata.size);
+        let file_action = if self.downloaded_files.contains_key(&file_metadata.sha256) {
+            button(text("Save")).on_press(ChatPageMessage::SaveFile(message_idx))
+        } else if self.pending_downloads.contains_key(&file_metadata.sha256) {
+            button(text("Downloading..."))
+        } else {
+            button(text("Download"))
+                .on_press(ChatPageMessage::DownloadFile(file_metadata.sha256.clone()))
+        }
+        .padding(2);
+        let file_info = row!(
+            text(&file_metadata.name),
+            text(format!("({file_size}, {})", file_metadata.mime_type)),
+            file_action
+        )
+        .spacing(5)
+        .align_y(iced::Alignment::Center);
+
+        match self.image_previews.get(&file_metadata.sha256) {
+            Some(preview) => column!(
+                file_info,
+                mouse_area(image(preview.thumbnail.clone()))
+                    .on_press(ChatPageMessage::ExpandImage(file_metadata.sha256.clone()))
+            )
+            .spacing(2)
+            .into(),
+            None => file_info.into(),
+        }
+    }
+
+    /// The latest notice with a button to dismiss it, empty without one
+    fn notice_view(&self) -> Element<'_, ChatPageMessage> {
+        let Some(notice) = &self.notice else {
+            return column![].into();
+        };
+        row!(
+            text(notice).style(text::success).width(Length::Fill),
+            button(text("Dismiss"))
+                .on_press(ChatPageMessage::DismissNotice)
+                .padding(2)
+        )
+        .spacing(5)
+        .align_y(iced::Alignment::Center)
+        .into()
+    }
+
+    fn check_files_allowed(&self) -> Result<()> {
+        if !self.capabilities.contains(Capability::Files) {
+            bail!("This server does not accept files");
+        }
+        Ok(())
+    }
+
+    /// Both tasks of the previous connection have stopped so a new one can be made
+    fn can_reconnect(&self) -> bool {
+        self.state == SessionState::Disconnected
+            && !self.is_reader_running
+            && !self.is_worker_running
+    }
+
+    /// Schedule an automatic reconnect unless the user is the one who left
+    fn connection_lost(&mut self) {
+        if self.state != SessionState::Disconnected {
+            self.state = SessionState::Disconnected;
+            if !self.is_disconnect_requested {
+                self.schedule_reconnect();
+            }
+        }
+    }
+
+    /// Wait twice as long after every failed attempt, giving up and staying
+    /// offline after [MAXIMUM_RECONNECT_ATTEMPTS]
+    fn schedule_reconnect(&mut self) {
+        self.reconnect_countdown =
+            (self.reconnect_attempts < MAXIMUM_RECONNECT_ATTEMPTS).then(|| {
+                let reconnect_delay = Duration::from_secs(2u64.pow(self.reconnect_attempts));
+                reconnect_delay.min(MAXIMUM_RECONNECT_DELAY).as_secs()
+            });
+    }
+
+    /// A reconnect is scheduled and needs [ChatPageMessage::ReconnectCountdownTick]
+    pub(crate) fn is_reconnect_scheduled(&self) -> bool {
+        self.reconnect_countdown.is_some()
+    }
+
+    /// The user left the chat and the worker finished sending the disconnect,
+    /// so the session can be dropped
+    pub(crate) fn is_closed(&self) -> bool {
+        self.is_disconnect_requested && !self.is_worker_running
+    }
+
+    pub(crate) fn unread_messages(&self) -> usize {
+        self.unread_messages
+    }
+
+    pub(crate) fn mark_unread(&mut self) {
+        self.unread_messages += 1;
+    }
+
+    pub(crate) fn mark_read(&mut self) {
+        self.unread_messages = 0;
     }
 
     pub(crate) fn init_worker(
+        session_id: SessionId,
         username: String,
         chat_session_writer: impl ChatWrite,
     ) -> impl Stream<Item = AppUpdateMessage> {
-        chat_worker::start_chat_worker(chat_session_writer, username)
+        chat_worker::start_chat_worker(session_id, chat_session_writer, username)
     }
 }
+
+/// Need some sort of protection against overflowing memory
+/// by accident by selecting a very large file
+fn check_file_size(file_size_bytes: u64) -> Result<()> {
+    let file_size = PrettyFileSize::from_bytes(file_size_bytes);
+    if file_size > MAXIMUM_FILE_SIZE_BYTES {
+        bail!("Selected file was too large: {file_size} (Maximum: {MAXIMUM_FILE_SIZE_BYTES})");
+    }
+    Ok(())
+}
+
+/// Ask the user where to save a received file and write it there.
+/// Returns `None` if the user cancelled the save dialog.
+async fn save_file(filename: String, file_contents: Vec<u8>) -> Result<Option<PathBuf>> {
+    let Some(file_handle) = AsyncFileDialog::new()
+        .set_file_name(filename)
+        .save_file()
+        .await
+    else {
+        return Ok(None);
+    };
+    let save_path = file_handle.path().to_path_buf();
+    tokio::fs::write(&save_path, file_contents)
+        .await
+        .with_context(|| format!("Could not save file to {}", save_path.display()))?;
+    Ok(Some(save_path))
+}
@@ -1,5 +1,9 @@
-use client::ChatWrite;
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use client::{ChatWrite, FileTransfer};
 use futures::TryFutureExt;
+use futures::future::{AbortRegistration, Abortable};
 use iced::futures::Stream;
 use iced::futures::channel::mpsc;
 use iced::futures::sink::SinkExt;
@@ -7,42 +11,113 @@ use iced::futures::stream::StreamExt;
 use iced::stream::channel;
 
 use client::ClientMessage;
-use shared_types::messages::ServerMessage;
+use shared_types::{
+    messages::{FileMetadata, ServerMessage, chat_text},
+    transfer::{FileChunk, TransferId},
+};
+use tokio::io::AsyncRead;
 
 use crate::{AppUpdateMessage, ErrorPopupMessage};
 
-use super::ChatPageMessage;
+use super::{ChatPageMessage, SessionId, UploadId, UploadSource};
 
 /// Shorthand type for the mpsc Sender responsible for communicating with the worker
-pub(super) type ChatSender = mpsc::Sender<ClientMessage>;
+pub(super) type ChatSender = mpsc::Sender<WorkerCommand>;
+
+/// Chunks uploads may read ahead of what the worker has sent, shared by
+/// every upload so text messages never queue up behind a whole file
+const UPLOAD_QUEUE_LENGTH: usize = 4;
+
+/// Work the chat page hands over to the worker
+#[derive(Debug)]
+pub(crate) enum WorkerCommand {
+    /// Send the message as is
+    Send(ClientMessage),
+    /// Stream the file to the server chunk by chunk, reporting progress
+    UploadFile(FileUpload),
+}
+
+impl From<ClientMessage> for WorkerCommand {
+    fn from(client_message: ClientMessage) -> Self {
+        Self::Send(client_message)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FileUpload {
+    pub(super) upload_id: UploadId,
+    pub(super) source: UploadSource,
+    pub(super) transfer_id: TransferId,
+    /// Bytes the server confirmed receiving before the connection dropped
+    pub(super) received_bytes: u64,
+    /// Lets the chat page stop the upload between chunks
+    pub(super) cancel_registration: AbortRegistration,
+}
+
+/// How an upload task ended
+enum UploadOutcome {
+    /// The whole file was handed to the worker, the chat page shows it
+    /// once the server confirmed storing it
+    Sent(FileMetadata),
+    Cancelled,
+    /// The connection dropped, the chat page resumes the upload once reconnected
+    Interrupted,
+}
 
 /// Start the background chat worker to handle sending messages to the
 /// WebSocket server. Need to pass username that the user chose during
 /// the connection step.
 pub(super) fn start_chat_worker(
+    session_id: SessionId,
     mut chat_session_writer: impl ChatWrite,
     username: String,
 ) -> impl Stream<Item = AppUpdateMessage> {
     channel(100, async move |mut output| {
         // Create channel
-        let (sender, mut receiver) = mpsc::channel::<ClientMessage>(100);
+        let (sender, mut receiver) = mpsc::channel::<WorkerCommand>(100);
 
-        // Send the sender back to the application
-        output
-            .send(ChatPageMessage::WorkerReady(sender).into())
+        // Send the sender back to the application, if the app stopped
+        // listening there is nobody left to send messages for
+        if output
+            .send(ChatPageMessage::WorkerReady(sender).for_session(session_id))
             .await
-            .expect(
-                "chat worker should be able to return the sender back to the chat_page component",
-            ); // If gives error its probably not recoverable
+            .is_err()
+        {
+            return;
+        }
+
+        // Uploads run in tasks of their own and hand their chunks back to
+        // be sent between whatever else the chat page sends. Once the worker
+        // stops the uploads can't hand over chunks anymore and stop too
+        let (chunk_sender, mut chunk_receiver) = mpsc::channel::<FileChunk>(UPLOAD_QUEUE_LENGTH);
 
         let mut disconnected = false;
 
         // Read next available command in the form of the desired
-        // ClientMessage to be sent:
-        while let Some(chat_message_to_send) = receiver.next().await {
-            if disconnected {
-                break;
-            }
+        // ClientMessage to be sent. Ends once the chat page drops the
+        // sender or after the disconnect message was sent
+        while !disconnected {
+            let worker_command = tokio::select! {
+                maybe_worker_command = receiver.next() => match maybe_worker_command {
+                    Some(worker_command) => worker_command,
+                    None => break,
+                },
+                Some(file_chunk) = chunk_receiver.next() => {
+                    WorkerCommand::Send(ClientMessage::FileChunk(file_chunk))
+                }
+            };
+            let chat_message_to_send = match worker_command {
+                WorkerCommand::Send(client_message) => client_message,
+                WorkerCommand::UploadFile(file_upload) => {
+                    tokio::spawn(upload_file(
+                        session_id,
+                        output.clone(),
+                        chunk_sender.clone(),
+                        file_upload,
+                    ));
+                    continue;
+                }
+            };
             // Perform appropriate client action:
             let chat_message_send_result = match chat_message_to_send {
                 // If all went well we want to add the message to the history.
@@ -50,17 +125,66 @@ pub(super) fn start_chat_worker(
                 // all messages the same and then pass it on as if it was
                 // received from the server
                 ClientMessage::Text(text_msg) => {
-                    let server_message = ServerMessage::text(&username, &text_msg);
+                    // Commands are answered by the server, only chat is shown right away
+                    let maybe_server_message = chat_text(&text_msg)
+                        .map(|chat_text| ServerMessage::text(&username, chat_text));
+                    match (
+                        chat_session_writer.send_message(text_msg).await,
+                        maybe_server_message,
+                    ) {
+                        (Ok(_), Some(server_message)) => Ok(server_message),
+                        (Ok(_), None) => continue,
+                        (Err(err), _) => Err(err),
+                    }
+                }
+                ClientMessage::File(filename, file_contents) => {
                     chat_session_writer
-                        .send_message(text_msg)
-                        .and_then(async |_| Ok(server_message))
+                        .send_file(
+                            filename,
+                            file_contents.len() as u64,
+                            file_contents.as_slice(),
+                        )
+                        .and_then(async |file_metadata| {
+                            Ok(ServerMessage::file(&username, file_metadata))
+                        })
                         .await
                 }
-                ClientMessage::File(filename, file_contents) => {
+                ClientMessage::FileChunk(file_chunk) => {
+                    // Only part of a file, nothing to show until all of it was sent
+                    let transfer_id = file_chunk.header.transfer_id;
+                    match chat_session_writer.send_file_chunk(file_chunk).await {
+                        Ok(_) => continue,
+                        // The upload resumes from what the server confirmed
+                        // once reconnected
+                        Err(err) if err.is_connection_lost() => continue,
+                        // The server won't confirm a chunk it never got, so
+                        // the upload ends here instead of waiting on it
+                        Err(err) => {
+                            let upload_failed =
+                                ChatPageMessage::UploadFailed(transfer_id, err.to_string());
+                            if output
+                                .send(upload_failed.for_session(session_id))
+                                .await
+                                .is_err()
+                            {
+                                return;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                ClientMessage::DownloadFile(sha256) => {
+                    match chat_session_writer.request_download(sha256).await {
+                        // The contents arrive through the reader later on
+                        Ok(_) => continue,
+                        Err(err) => Err(err),
+                    }
+                }
+                ClientMessage::DirectMessage(recipient, text_msg) => {
                     let server_message =
-                        ServerMessage::file(&username, &filename, file_contents.as_slice());
+                        ServerMessage::direct_message(&username, &recipient, &text_msg);
                     chat_session_writer
-                        .send_file(filename, file_contents)
+                        .send_direct_message(recipient, text_msg)
                         .and_then(async |_| Ok(server_message))
                         .await
                 }
@@ -76,10 +200,13 @@ pub(super) fn start_chat_worker(
                 }
             };
             // Map result of client action to a message to update the GUI:
-            match chat_message_send_result {
+            let gui_update_result = match chat_message_send_result {
                 Ok(sent_chat_message) => {
                     output
-                        .send(ChatPageMessage::AddMessageToHistory(sent_chat_message).into())
+                        .send(
+                            ChatPageMessage::AddMessageToHistory(sent_chat_message)
+                                .for_session(session_id),
+                        )
                         .await
                 }
                 Err(err) => {
@@ -87,16 +214,134 @@ pub(super) fn start_chat_worker(
                         .send(ErrorPopupMessage::AddError(err.to_string()).into())
                         .await
                 }
+            };
+            if gui_update_result.is_err() {
+                return;
             }
-            // If we get an Err variant its probably unrecoverable
-            .expect("receiver should not drop while we are in this loop");
         }
-        // TODO: Figure out why it doesn't reach this part of the code on disconnect
-        // Ensure final message notifies app to discard other side of channel
-        // If it fails then the page will be stuck on the chat softlocking the app
-        output
-            .send(ChatPageMessage::ResetChatWorker.into())
-            .await
-            .expect("receiver should not drop untill after this call");
+        // Let the chat page know it can no longer send messages. If the app
+        // is gone there is nothing left to update so the error is ignored
+        let _ = output
+            .send(ChatPageMessage::WorkerStopped.for_session(session_id))
+            .await;
     })
 }
+
+/// Read the file one chunk at a time and hand the chunks to the worker,
+/// letting the chat page know how the upload ended
+async fn upload_file(
+    session_id: SessionId,
+    mut output: mpsc::Sender<AppUpdateMessage>,
+    chunk_sender: mpsc::Sender<FileChunk>,
+    file_upload: FileUpload,
+) {
+    let upload_id = file_upload.upload_id;
+    let upload_result = read_file_chunks(session_id, &mut output, chunk_sender, file_upload).await;
+    let upload_finished = ChatPageMessage::UploadFinished(upload_id).for_session(session_id);
+    // Nobody left to tell if these fail
+    match upload_result {
+        Ok(UploadOutcome::Sent(file_metadata)) => {
+            let upload_sent =
+                ChatPageMessage::UploadSent(upload_id, file_metadata).for_session(session_id);
+            let _ = output.send(upload_sent).await;
+        }
+        // The chat page already forgot about cancelled uploads and keeps
+        // interrupted ones around
+        Ok(UploadOutcome::Cancelled | UploadOutcome::Interrupted) => {}
+        Err(err) => {
+            let read_error = ErrorPopupMessage::AddError(format!("{err:#}"));
+            if output.send(read_error.into()).await.is_ok() {
+                let _ = output.send(upload_finished).await;
+            }
+        }
+    }
+}
+
+async fn read_file_chunks(
+    session_id: SessionId,
+    output: &mut mpsc::Sender<AppUpdateMessage>,
+    chunk_sender: mpsc::Sender<FileChunk>,
+    file_upload: FileUpload,
+) -> Result<UploadOutcome> {
+    let FileUpload {
+        upload_id,
+        source,
+        transfer_id,
+        received_bytes,
+        cancel_registration,
+    } = file_upload;
+
+    let send_file = async move {
+        match source {
+            UploadSource::Path(path) => {
+                let filename: String = path
+                    .file_name()
+                    .context("Path does not point to a file")?
+                    .to_string_lossy() // Not big deal if name is a bit mangled
+                    .into();
+                let file = tokio::fs::File::open(&path)
+                    .await
+                    .with_context(|| format!("Could not open {}", path.display()))?;
+
+                let file_metadata = file
+                    .metadata()
+                    .await
+                    .context("Could not get file metadata to check for file size")?;
+                // Dropping a folder onto the window gives its path too
+                if !file_metadata.is_file() {
+                    anyhow::bail!("Only files can be sent, {} is not one", path.display());
+                }
+                let total_bytes = file_metadata.len();
+                let file_transfer =
+                    FileTransfer::resume(transfer_id, &filename, total_bytes, file, received_bytes);
+                send_file_chunks(session_id, output, chunk_sender, upload_id, file_transfer).await
+            }
+            UploadSource::Memory { filename, contents } => {
+                let total_bytes = contents.len() as u64;
+                let file_transfer = FileTransfer::resume(
+                    transfer_id,
+                    &filename,
+                    total_bytes,
+                    Cursor::new(contents),
+                    received_bytes,
+                );
+                send_file_chunks(session_id, output, chunk_sender, upload_id, file_transfer).await
+            }
+        }
+    };
+
+    match Abortable::new(send_file, cancel_registration).await {
+        Ok(upload_result) => upload_result,
+        Err(_aborted) => Ok(UploadOutcome::Cancelled),
+    }
+}
+
+async fn send_file_chunks<R: AsyncRead + Unpin>(
+    session_id: SessionId,
+    output: &mut mpsc::Sender<AppUpdateMessage>,
+    mut chunk_sender: mpsc::Sender<FileChunk>,
+    upload_id: UploadId,
+    mut file_transfer: FileTransfer<R>,
+) -> Result<UploadOutcome> {
+    let total_bytes = file_transfer.total_size();
+    super::check_file_size(total_bytes)?;
+    // Progress itself comes from the server confirming chunks
+    let _ = output
+        .send(ChatPageMessage::UploadStarted(upload_id, total_bytes).for_session(session_id))
+        .await;
+
+    while let Some(file_chunk) = file_transfer
+        .next_chunk()
+        .await
+        .context("Error while reading file")?
+    {
+        if chunk_sender.send(file_chunk).await.is_err() {
+            return Ok(UploadOutcome::Interrupted);
+        }
+    }
+
+    let file_metadata = file_transfer
+        .file_metadata()
+        .context("File changed size while it was being sent")?;
+    Ok(UploadOutcome::Sent(file_metadata))
+}
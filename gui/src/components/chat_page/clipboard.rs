@@ -0,0 +1,50 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageOutputFormat, RgbaImage};
+
+use super::image_preview::{self, ImagePreview};
+
+/// Name the pasted image is sent with since the clipboard doesn't keep one
+pub(super) const PASTED_IMAGE_FILENAME: &str = "pasted_image.png";
+
+/// An image from the clipboard waiting for the user to confirm sending it
+#[derive(Debug, Clone)]
+pub(crate) struct PastedImage {
+    /// PNG encoded image, ready to be sent as a file
+    pub(super) contents: Vec<u8>,
+    pub(super) preview: ImagePreview,
+}
+
+/// Read an image from the system clipboard and encode it as PNG on the
+/// blocking thread pool. Returns `None` if the clipboard holds no image
+pub(super) async fn read_clipboard_image() -> Result<Option<PastedImage>> {
+    tokio::task::spawn_blocking(|| {
+        let mut clipboard = arboard::Clipboard::new().context("Could not open the clipboard")?;
+        let image_data = match clipboard.get_image() {
+            Ok(image_data) => image_data,
+            Err(arboard::Error::ContentNotAvailable) => return Ok(None),
+            Err(err) => return Err(err).context("Could not read image from the clipboard"),
+        };
+
+        let rgba_image = RgbaImage::from_raw(
+            image_data.width as u32,
+            image_data.height as u32,
+            image_data.bytes.into_owned(),
+        )
+        .context("Clipboard image did not match its own dimensions")?;
+        let pasted_image = DynamicImage::ImageRgba8(rgba_image);
+
+        let mut png_contents = Cursor::new(vec![]);
+        pasted_image
+            .write_to(&mut png_contents, ImageOutputFormat::Png)
+            .context("Could not encode clipboard image as PNG")?;
+
+        Ok(Some(PastedImage {
+            contents: png_contents.into_inner(),
+            preview: image_preview::build_image_preview(pasted_image),
+        }))
+    })
+    .await
+    .context("Clipboard reading task did not finish")?
+}
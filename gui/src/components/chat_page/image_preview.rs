@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+use iced::widget::image::Handle;
+use image::{DynamicImage, ImageFormat};
+
+/// Largest width or height a thumbnail is scaled down to
+const THUMBNAIL_MAX_DIMENSION: u32 = 256;
+
+/// Decoded image handles for a received image file, kept around so the
+/// chat can be scrolled without decoding the image again
+#[derive(Debug, Clone)]
+pub(crate) struct ImagePreview {
+    pub(super) thumbnail: Handle,
+    pub(super) full_size: Handle,
+}
+
+/// Check the magic bytes of a file to see if it is an image format we preview
+pub(super) fn is_previewable_image(file_contents: &[u8]) -> bool {
+    matches!(
+        image::guess_format(file_contents),
+        Ok(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP)
+    )
+}
+
+/// Decode the image and build its thumbnail on the blocking thread pool
+/// so large images do not stall the UI
+pub(super) async fn decode_image_preview(file_contents: Vec<u8>) -> Result<ImagePreview> {
+    tokio::task::spawn_blocking(move || {
+        let decoded_image =
+            image::load_from_memory(&file_contents).context("Could not decode image")?;
+        Ok(build_image_preview(decoded_image))
+    })
+    .await
+    .context("Image decoding task did not finish")?
+}
+
+/// Scale down the thumbnail, should be called from the blocking thread pool
+pub(super) fn build_image_preview(decoded_image: DynamicImage) -> ImagePreview {
+    let thumbnail = decoded_image.thumbnail(THUMBNAIL_MAX_DIMENSION, THUMBNAIL_MAX_DIMENSION);
+    ImagePreview {
+        thumbnail: into_handle(thumbnail),
+        full_size: into_handle(decoded_image),
+    }
+}
+
+fn into_handle(decoded_image: DynamicImage) -> Handle {
+    let rgba_image = decoded_image.into_rgba8();
+    Handle::from_rgba(
+        rgba_image.width(),
+        rgba_image.height(),
+        rgba_image.into_raw(),
+    )
+}
@@ -0,0 +1,40 @@
+use iced::{
+    Element,
+    widget::{button, container, row, text},
+};
+
+use super::{ChatPage, ChatPageMessage, SessionState};
+
+impl ChatPage {
+    /// Shows whether the session is connected, when the next automatic
+    /// reconnect happens or that it gave up, with a button to reconnect now
+    pub(super) fn status_badge_view(&self) -> Element<'_, ChatPageMessage> {
+        let status = match (self.state, self.reconnect_countdown) {
+            (SessionState::Connected, _) => text("● Connected").style(text::success),
+            (SessionState::Connecting, _) => text("● Connecting...").style(text::secondary),
+            (SessionState::Reconnecting, _) => text("● Reconnecting...").style(text::secondary),
+            (SessionState::Disconnected, Some(seconds_left)) => {
+                text(format!("● Reconnecting in {seconds_left}s")).style(text::secondary)
+            }
+            (SessionState::Disconnected, None) => text("● Offline").style(text::danger),
+        };
+        let badge = container(status).padding(5).style(container::bordered_box);
+
+        if self.state != SessionState::Disconnected {
+            return badge.into();
+        }
+        let reconnect_label = if self.reconnect_countdown.is_some() {
+            "Reconnect now"
+        } else {
+            "Reconnect"
+        };
+        let reconnect_button = button(text(reconnect_label))
+            .on_press_maybe(self.can_reconnect().then_some(ChatPageMessage::Reconnect))
+            .padding(5);
+
+        row!(badge, reconnect_button)
+            .spacing(5)
+            .align_y(iced::Alignment::Center)
+            .into()
+    }
+}
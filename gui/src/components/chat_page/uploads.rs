@@ -0,0 +1,245 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::{Context, Result};
+use futures::future::AbortHandle;
+use iced::{
+    Element, Length, Task,
+    widget::{button, column, progress_bar, row, text},
+};
+use shared_types::{
+    messages::{FileMetadata, ServerMessage},
+    transfer::TransferId,
+};
+use size::Size as PrettyFileSize;
+
+use crate::{AppUpdateMessage, components::ErrorPopupMessage};
+
+use super::{
+    ChatPage, ChatPageMessage,
+    chat_worker::{FileUpload, WorkerCommand},
+};
+
+/// Identifies an upload within its chat page
+pub(crate) type UploadId = usize;
+
+/// Where an upload reads its file from
+#[derive(Debug, Clone)]
+pub(crate) enum UploadSource {
+    /// Picked in the file dialog or dropped onto the window
+    Path(PathBuf),
+    /// Already in memory, such as an image pasted from the clipboard
+    Memory {
+        filename: String,
+        contents: Arc<[u8]>,
+    },
+}
+
+/// A file being streamed to the server by an upload task. Kept until the
+/// server confirmed storing the whole file so it can be resumed after a
+/// reconnect
+#[derive(Debug)]
+pub(super) struct Upload {
+    filename: String,
+    source: UploadSource,
+    transfer_id: TransferId,
+    /// Bytes the server confirmed storing, shown as the progress and where
+    /// a resumed upload starts from
+    received_bytes: u64,
+    /// Zero until the upload task has opened the file
+    pub(super) total_bytes: u64,
+    /// Known once the upload task has read the whole file
+    maybe_file_metadata: Option<FileMetadata>,
+    /// Set once the server confirmed storing the whole file
+    is_stored: bool,
+    /// Stops the upload task currently sending the file
+    cancel_handle: Option<AbortHandle>,
+}
+
+impl Upload {
+    pub(super) fn new(source: UploadSource) -> Self {
+        let filename = match &source {
+            UploadSource::Path(path) => path
+                .file_name()
+                .map(|filename| filename.to_string_lossy().into())
+                .unwrap_or_default(),
+            UploadSource::Memory { filename, .. } => filename.clone(),
+        };
+        Self {
+            filename,
+            source,
+            transfer_id: TransferId::new_v4(),
+            received_bytes: 0,
+            total_bytes: 0,
+            maybe_file_metadata: None,
+            is_stored: false,
+            cancel_handle: None,
+        }
+    }
+
+    pub(super) fn cancel(self) {
+        if let Some(cancel_handle) = self.cancel_handle {
+            cancel_handle.abort();
+        }
+    }
+}
+
+impl ChatPage {
+    /// Keep track of a new upload and hand it to the chat worker
+    pub(super) fn add_upload(&mut self, source: UploadSource) -> Task<AppUpdateMessage> {
+        let upload_id = self.next_upload_id;
+        self.next_upload_id += 1;
+        self.uploads.insert(upload_id, Upload::new(source));
+        match self.start_upload(upload_id) {
+            Ok(_) => Task::none(),
+            Err(err) => {
+                self.uploads.remove(&upload_id);
+                Task::done(ErrorPopupMessage::AddError(err.to_string()).into())
+            }
+        }
+    }
+
+    /// Hand the upload to the chat worker, continuing from whatever the
+    /// server already received
+    pub(super) fn start_upload(&mut self, upload_id: UploadId) -> Result<()> {
+        self.check_files_allowed()?;
+        let sender = self
+            .chat_sender
+            .as_mut()
+            .context("Files can only be sent while connected")?;
+        let upload = self
+            .uploads
+            .get_mut(&upload_id)
+            .context("Upload was already cancelled")?;
+
+        let (cancel_handle, cancel_registration) = AbortHandle::new_pair();
+        sender.try_send(WorkerCommand::UploadFile(FileUpload {
+            upload_id,
+            source: upload.source.clone(),
+            transfer_id: upload.transfer_id,
+            received_bytes: upload.received_bytes,
+            cancel_registration,
+        }))?;
+        upload.cancel_handle = Some(cancel_handle);
+        Ok(())
+    }
+
+    /// Restart every upload the previous connection didn't finish
+    pub(super) fn resume_uploads(&mut self) -> Task<AppUpdateMessage> {
+        let upload_ids: Vec<UploadId> = self.uploads.keys().copied().collect();
+        Task::batch(upload_ids.into_iter().filter_map(|upload_id| {
+            let resume_error = self.start_upload(upload_id).err()?;
+            Some(Task::done(
+                ErrorPopupMessage::AddError(resume_error.to_string()).into(),
+            ))
+        }))
+    }
+
+    fn find_upload(&self, transfer_id: TransferId) -> Option<UploadId> {
+        self.uploads
+            .iter()
+            .find(|(_, upload)| upload.transfer_id == transfer_id)
+            .map(|(upload_id, _)| *upload_id)
+    }
+
+    pub(super) fn update_received_bytes(
+        &mut self,
+        transfer_id: TransferId,
+        received_bytes: u64,
+    ) -> Task<AppUpdateMessage> {
+        let Some(upload_id) = self.find_upload(transfer_id) else {
+            return Task::none();
+        };
+        if let Some(upload) = self.uploads.get_mut(&upload_id) {
+            upload.received_bytes = received_bytes;
+            // Progress only ever arrives once the upload task knows the size
+            upload.is_stored = received_bytes == upload.total_bytes;
+        }
+        self.finish_upload(upload_id)
+    }
+
+    /// The upload task handed over every chunk, the file is only shown
+    /// once the server confirmed storing it too
+    pub(super) fn upload_sent(
+        &mut self,
+        upload_id: UploadId,
+        file_metadata: FileMetadata,
+    ) -> Task<AppUpdateMessage> {
+        if let Some(upload) = self.uploads.get_mut(&upload_id) {
+            upload.maybe_file_metadata = Some(file_metadata);
+        }
+        self.finish_upload(upload_id)
+    }
+
+    /// Add the file to the history once it was both sent and stored
+    fn finish_upload(&mut self, upload_id: UploadId) -> Task<AppUpdateMessage> {
+        let Some(upload) = self.uploads.get(&upload_id) else {
+            return Task::none();
+        };
+        if !upload.is_stored || upload.maybe_file_metadata.is_none() {
+            return Task::none();
+        }
+        let Some(file_metadata) = self
+            .uploads
+            .remove(&upload_id)
+            .and_then(|upload| upload.maybe_file_metadata)
+        else {
+            return Task::none();
+        };
+        let file_message = ServerMessage::file(self.username(), file_metadata);
+        Task::done(ChatPageMessage::AddMessageToHistory(file_message).for_session(self.session_id))
+    }
+
+    /// The server refused the upload or a chunk of it could not be sent,
+    /// stop sending the rest of it
+    pub(super) fn refuse_upload(
+        &mut self,
+        transfer_id: TransferId,
+        reason: &str,
+    ) -> Task<AppUpdateMessage> {
+        let Some(upload) = self
+            .find_upload(transfer_id)
+            .and_then(|upload_id| self.uploads.remove(&upload_id))
+        else {
+            return Task::none();
+        };
+        let refusal = format!("Could not send {}: {reason}", upload.filename);
+        upload.cancel();
+        Task::done(ErrorPopupMessage::AddError(refusal).into())
+    }
+
+    /// A progress bar with a cancel button for every unfinished upload
+    pub(super) fn uploads_view(&self) -> Element<'_, ChatPageMessage> {
+        self.uploads
+            .iter()
+            .fold(column![].spacing(2), |col, (upload_id, upload)| {
+                let progress = if self.chat_sender.is_none() {
+                    String::from("Waiting to reconnect...")
+                } else {
+                    format!(
+                        "{} / {}",
+                        PrettyFileSize::from_bytes(upload.received_bytes),
+                        PrettyFileSize::from_bytes(upload.total_bytes)
+                    )
+                };
+                let cancel_button = button(text("Cancel"))
+                    .on_press(ChatPageMessage::CancelUpload(*upload_id))
+                    .padding(2);
+                col.push(
+                    row!(
+                        text(&upload.filename),
+                        progress_bar(
+                            0.0..=upload.total_bytes as f32,
+                            upload.received_bytes as f32
+                        )
+                        .height(10)
+                        .width(Length::Fill),
+                        text(progress),
+                        cancel_button
+                    )
+                    .spacing(5)
+                    .align_y(iced::Alignment::Center),
+                )
+            })
+            .into()
+    }
+}
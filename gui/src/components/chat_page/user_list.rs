@@ -0,0 +1,69 @@
+use iced::{
+    Element, Length,
+    widget::{button, column, container, row, scrollable, text},
+};
+use shared_types::messages::{OnlineUser, Role};
+
+use super::{ChatPage, ChatPageMessage};
+
+/// Name as shown in the list, with the role of anyone who isn't a plain user
+fn display_name(online_user: &OnlineUser) -> String {
+    match online_user.role {
+        Role::User => online_user.name.clone(),
+        Role::Moderator => format!("{} (moderator)", online_user.name),
+        Role::Admin => format!("{} (admin)", online_user.name),
+    }
+}
+
+impl ChatPage {
+    /// Sidebar listing everyone connected to the server with quick actions
+    pub(super) fn user_list_view(&self) -> Element<'_, ChatPageMessage> {
+        let own_role = self
+            .online_users
+            .iter()
+            .find(|online_user| online_user.name == self.username())
+            .map_or(Role::User, |online_user| online_user.role);
+        let users = self.online_users.iter().fold(
+            column![text(format!("Online ({})", self.online_users.len()))].spacing(5),
+            |col, online_user| {
+                // The local user gets highlighted instead of actions on themselves
+                if online_user.name == self.username() {
+                    return col.push(
+                        text(format!("{} (you)", display_name(online_user))).style(text::primary),
+                    );
+                }
+                let mention_button = button(text("@"))
+                    .on_press(ChatPageMessage::MentionUser(online_user.name.clone()))
+                    .padding(2);
+                let direct_message_button = button(text("DM"))
+                    .on_press(ChatPageMessage::StartDirectMessage(
+                        online_user.name.clone(),
+                    ))
+                    .padding(2);
+                let user_row = row!(
+                    text(display_name(online_user)).width(Length::Fill),
+                    mention_button,
+                    direct_message_button
+                );
+                // The server only lets moderators kick users ranked below them
+                let user_row = if own_role >= Role::Moderator && online_user.role < own_role {
+                    user_row.push(
+                        button(text("Kick"))
+                            .on_press(ChatPageMessage::KickUser(online_user.name.clone()))
+                            .style(button::danger)
+                            .padding(2),
+                    )
+                } else {
+                    user_row
+                };
+                col.push(user_row.spacing(2).align_y(iced::Alignment::Center))
+            },
+        );
+
+        container(scrollable(users))
+            .padding(5)
+            .height(Length::Fill)
+            .style(container::bordered_box)
+            .into()
+    }
+}
@@ -28,7 +28,7 @@ impl From<ErrorPopupMessage> for AppUpdateMessage {
 // TODO: Convert to anyhow maybe
 impl ErrorPopup {
     //TODO: Make this prettier
-    pub(crate) fn view(&self) -> iced::Element<ErrorPopupMessage> {
+    pub(crate) fn view(&self) -> iced::Element<'_, ErrorPopupMessage> {
         let toggle_errorlist_button = container(
             button(text("Toggle Errors"))
                 .on_press(ErrorPopupMessage::ToggleExpand)
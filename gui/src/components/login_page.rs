@@ -1,12 +1,15 @@
-use client::connect;
+use client::connect_with_options;
 use derivative::Derivative;
-use futures::TryFutureExt;
 use iced::{
     Length, Task, Theme,
-    widget::{button, column, combo_box, container, text, text_input},
+    widget::{button, checkbox, column, combo_box, container, pick_list, row, text, text_input},
 };
 
-use crate::AppUpdateMessage;
+use crate::{
+    AppUpdateMessage,
+    components::ConnectionDetails,
+    settings::{self, ServerProfile, Settings},
+};
 
 use super::ErrorPopupMessage;
 
@@ -19,6 +22,11 @@ pub(crate) struct LoginPage {
     username: String,
     password: String,
     server_addr: String,
+    settings: Settings,
+    profile_name: String,
+    remember_password: bool,
+    account_password: String,
+    register_account: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -28,6 +36,16 @@ pub(crate) enum LoginPageMessage {
     UpdatePassword(String),
     UpdateServerAddress(String),
     UpdateTheme(Theme),
+    SettingsLoaded(Settings),
+    SelectProfile(ServerProfile),
+    SelectRecentServer(String),
+    UpdateProfileName(String),
+    ToggleRememberPassword(bool),
+    UpdateAccountPassword(String),
+    ToggleRegisterAccount(bool),
+    SaveProfile,
+    DeleteProfile,
+    ConnectionSucceeded(String),
 }
 
 impl From<LoginPageMessage> for AppUpdateMessage {
@@ -37,7 +55,7 @@ impl From<LoginPageMessage> for AppUpdateMessage {
 }
 
 impl LoginPage {
-    pub(crate) fn view(&self) -> iced::Element<LoginPageMessage> {
+    pub(crate) fn view(&self) -> iced::Element<'_, LoginPageMessage> {
         let theme_picker = combo_box(
             &self.theme_combobox_state,
             "Theme Selection",
@@ -47,6 +65,29 @@ impl LoginPage {
         .width(300)
         .padding(5);
 
+        let selected_profile = self
+            .settings
+            .profiles()
+            .iter()
+            .find(|profile| profile.name == self.profile_name);
+        let profile_picker = pick_list(
+            self.settings.profiles(),
+            selected_profile,
+            LoginPageMessage::SelectProfile,
+        )
+        .placeholder("Saved profiles")
+        .width(300)
+        .padding(5);
+
+        let recent_server_picker = pick_list(
+            self.settings.recent_servers(),
+            None::<String>,
+            LoginPageMessage::SelectRecentServer,
+        )
+        .placeholder("Recent servers")
+        .width(300)
+        .padding(5);
+
         let name_input = text_input("username", &self.username)
             .on_input(LoginPageMessage::UpdateUsername)
             .width(300)
@@ -58,6 +99,19 @@ impl LoginPage {
             .width(300)
             .padding(5);
 
+        let account_password_input =
+            text_input("Account password... (Optional)", &self.account_password)
+                .on_input(LoginPageMessage::UpdateAccountPassword)
+                .secure(true)
+                .width(300)
+                .padding(5);
+
+        let register_account_checkbox = checkbox("Register account", self.register_account)
+            .on_toggle_maybe(
+                (!self.account_password.is_empty())
+                    .then_some(LoginPageMessage::ToggleRegisterAccount),
+            );
+
         let server_address_input = text_input("wss://...", &self.server_addr)
             .on_input(LoginPageMessage::UpdateServerAddress)
             .width(300)
@@ -72,13 +126,41 @@ impl LoginPage {
             .on_press_maybe(submit_message)
             .padding(5);
 
+        let profile_name_input = text_input("Profile name", &self.profile_name)
+            .on_input(LoginPageMessage::UpdateProfileName)
+            .width(300)
+            .padding(5);
+
+        let remember_password_checkbox = checkbox("Remember password", self.remember_password)
+            .on_toggle(LoginPageMessage::ToggleRememberPassword);
+
+        let save_profile_message = if !self.profile_name.is_empty() && !self.server_addr.is_empty()
+        {
+            Some(LoginPageMessage::SaveProfile)
+        } else {
+            None
+        };
+        let save_profile_button = button(text("Save Profile"))
+            .on_press_maybe(save_profile_message)
+            .padding(5);
+        let delete_profile_button = button(text("Delete Profile"))
+            .on_press_maybe(selected_profile.map(|_| LoginPageMessage::DeleteProfile))
+            .padding(5);
+
         container(
             column!(
                 theme_picker,
+                profile_picker,
+                recent_server_picker,
                 name_input,
                 password_input,
+                account_password_input,
+                register_account_checkbox,
                 server_address_input,
-                submit_button
+                submit_button,
+                profile_name_input,
+                remember_password_checkbox,
+                row!(save_profile_button, delete_profile_button).spacing(10)
             )
             .align_x(iced::Alignment::Center)
             .spacing(10),
@@ -96,47 +178,114 @@ impl LoginPage {
             // Tell main app to initiate connection
             LoginPageMessage::AttemptToConnect => {
                 // Needed to be able to pass as a task up to the main app
-                // Not happening often
-                let function_owned_user = self.username.clone();
-                let function_owned_addr = self.server_addr.clone();
-                let function_owned_pass = if self.password.is_empty() {
-                    None
-                } else {
-                    Some(self.password.clone())
+                // Not happening often. The chat page keeps the details
+                // around so it can reconnect later on
+                let connection_details = ConnectionDetails {
+                    username: self.username.clone(),
+                    password: if self.password.is_empty() {
+                        None
+                    } else {
+                        Some(self.password.clone())
+                    },
+                    server_addr: self.server_addr.clone(),
+                    account_password: (!self.account_password.is_empty())
+                        .then(|| self.account_password.clone()),
                 };
 
-                let connection_future = connect(
-                    // Double clone since I use it twice, here and
-                    // in the Ok variant that I send back to the main app
-                    // I can't keep track of the username otherwise
-                    function_owned_user.clone(),
-                    function_owned_pass,
-                    function_owned_addr,
-                )
-                .map_ok(|chat_session| (chat_session, function_owned_user));
-
-                return Task::perform(
-                    connection_future,
-                    |connection_result| match connection_result {
-                        Ok((chat_session, username)) => {
-                            AppUpdateMessage::BeginChat(chat_session, username)
-                        }
-                        Err(err) => ErrorPopupMessage::AddError(err.to_string()).into(),
-                    },
+                let connection_future = connect_with_options(
+                    connection_details.username.clone(),
+                    connection_details.password.clone(),
+                    connection_details.server_addr.clone(),
+                    connection_details.connect_options(self.register_account),
                 );
+
+                return Task::future(connection_future).then(move |connection_result| {
+                    match connection_result {
+                        // Only remember servers we could actually connect to
+                        Ok(chat_session) => Task::batch(vec![
+                            Task::done(AppUpdateMessage::BeginChat(
+                                Box::new(chat_session),
+                                connection_details.clone(),
+                            )),
+                            Task::done(
+                                LoginPageMessage::ConnectionSucceeded(
+                                    connection_details.server_addr.clone(),
+                                )
+                                .into(),
+                            ),
+                        ]),
+                        Err(err) => Task::done(ErrorPopupMessage::AddError(err.to_string()).into()),
+                    }
+                });
+            }
+            LoginPageMessage::UpdateTheme(new_theme) => {
+                self.settings.set_theme(&new_theme);
+                self.selected_theme = new_theme;
+                return self.save_settings();
+            }
+            LoginPageMessage::SettingsLoaded(loaded_settings) => {
+                self.selected_theme = loaded_settings.theme();
+                self.settings = loaded_settings;
+            }
+            LoginPageMessage::SelectProfile(profile) => {
+                self.remember_password = profile.password.is_some();
+                self.password = profile.password.unwrap_or_default();
+                self.profile_name = profile.name;
+                self.username = profile.username;
+                self.server_addr = profile.server_addr;
+            }
+            LoginPageMessage::SaveProfile => {
+                let profile = ServerProfile {
+                    name: self.profile_name.clone(),
+                    server_addr: self.server_addr.clone(),
+                    username: self.username.clone(),
+                    password: (self.remember_password && !self.password.is_empty())
+                        .then(|| self.password.clone()),
+                };
+                self.settings.save_profile(profile);
+                return self.save_settings();
+            }
+            LoginPageMessage::DeleteProfile => {
+                self.settings.remove_profile(&self.profile_name);
+                self.profile_name.clear();
+                return self.save_settings();
+            }
+            LoginPageMessage::ConnectionSucceeded(server_addr) => {
+                self.settings.add_recent_server(&server_addr);
+                return self.save_settings();
             }
 
             // Basic updaters
             LoginPageMessage::UpdateUsername(new_username) => self.username = new_username,
             LoginPageMessage::UpdatePassword(new_password) => self.password = new_password,
             LoginPageMessage::UpdateServerAddress(new_addr) => self.server_addr = new_addr,
-            LoginPageMessage::UpdateTheme(new_theme) => self.selected_theme = new_theme,
+            LoginPageMessage::SelectRecentServer(server_addr) => self.server_addr = server_addr,
+            LoginPageMessage::UpdateProfileName(new_name) => self.profile_name = new_name,
+            LoginPageMessage::ToggleRememberPassword(remember) => self.remember_password = remember,
+            LoginPageMessage::UpdateAccountPassword(new_password) => {
+                self.register_account &= !new_password.is_empty();
+                self.account_password = new_password;
+            }
+            LoginPageMessage::ToggleRegisterAccount(register) => self.register_account = register,
         }
         Task::none()
     }
 
-    // TODO: Might get rid of this in the future for a more robust Theme
-    // controller with persistence features using https://github.com/rust-cli/confy
+    /// Load the persisted settings, used when the app first starts
+    pub(crate) fn load_settings() -> Task<AppUpdateMessage> {
+        Task::perform(settings::load(), |load_result| match load_result {
+            Ok(loaded_settings) => LoginPageMessage::SettingsLoaded(loaded_settings).into(),
+            Err(err) => ErrorPopupMessage::AddError(format!("{err:#}")).into(),
+        })
+    }
+
+    fn save_settings(&self) -> Task<AppUpdateMessage> {
+        Task::future(settings::save(self.settings.clone())).then(|save_result| match save_result {
+            Ok(()) => Task::none(),
+            Err(err) => Task::done(ErrorPopupMessage::AddError(format!("{err:#}")).into()),
+        })
+    }
+
     pub(crate) fn get_selected_theme(&self) -> Theme {
         self.selected_theme.clone()
     }
@@ -3,6 +3,8 @@ mod error_popup;
 mod login_page;
 pub(crate) use chat_page::ChatPage;
 pub(crate) use chat_page::ChatPageMessage;
+pub(crate) use chat_page::ConnectionDetails;
+pub(crate) use chat_page::SessionId;
 pub(crate) use error_popup::ErrorPopup;
 pub(crate) use error_popup::ErrorPopupMessage;
 pub(crate) use login_page::LoginPage;
@@ -1,28 +1,98 @@
 #![windows_subsystem = "windows"]
 mod components;
+mod settings;
+
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 
 use client::ChatSession;
 use components::{
-    ChatPage, ChatPageMessage, ErrorPopup, ErrorPopupMessage, LoginPage, LoginPageMessage,
+    ChatPage, ChatPageMessage, ConnectionDetails, ErrorPopup, ErrorPopupMessage, LoginPage,
+    LoginPageMessage, SessionId,
 };
 
 use futures::StreamExt;
-use iced::widget::{container, stack};
-use iced::{Element, Length, Subscription, Task, Theme, application};
+use iced::widget::{button, column, container, row, stack, text};
+use iced::{
+    Element, Event, Length, Subscription, Task, Theme, application, event, keyboard, window,
+};
 
 #[derive(Debug)]
 enum AppUpdateMessage {
     LoginPageMessage(LoginPageMessage),
     ErrorPopupMessage(ErrorPopupMessage),
-    ChatPageMessage(ChatPageMessage),
-    BeginChat(ChatSession, String),
+    ChatPageMessage(SessionId, ChatPageMessage),
+    BeginChat(Box<ChatSession>, ConnectionDetails),
+    /// New connection for a session that was disconnected
+    ResumeChat(SessionId, Box<ChatSession>),
+    SessionTabsMessage(SessionTabsMessage),
+    /// Counts down the scheduled reconnects of every session
+    ReconnectCountdownTick,
+    /// A file was dropped onto the window, sent in the active session
+    FileDropped(PathBuf),
+    /// Ctrl+V was pressed, the active session offers to send a pasted image
+    PasteFromClipboard,
+}
+
+#[derive(Debug, Clone)]
+enum SessionTabsMessage {
+    SelectSession(SessionId),
+    NewConnection,
 }
 
 #[derive(Debug, Default)]
 struct Messenger {
     login_page: LoginPage,
     error_popup: ErrorPopup,
-    chat_page: ChatPage,
+    /// Every open connection, ordered by when it was opened
+    chat_sessions: BTreeMap<SessionId, ChatPage>,
+    /// The session being shown, `None` shows the login page
+    active_session: Option<SessionId>,
+    next_session_id: SessionId,
+}
+
+impl Messenger {
+    /// Start the reader and worker tasks for a connection of the session
+    fn start_chat_tasks(
+        &mut self,
+        session_id: SessionId,
+        chat_session: ChatSession,
+    ) -> Task<AppUpdateMessage> {
+        let Some(chat_page) = self.chat_sessions.get_mut(&session_id) else {
+            return Task::none();
+        };
+        chat_page.begin_connection(chat_session.capabilities().clone(), chat_session.resume());
+        let session_title = chat_page.title().to_string();
+
+        // Start read Stream as a subscription
+        // Send the Writer to the chat component to be used there
+        let (chat_session_writer, chat_session_reader) = chat_session.split();
+
+        // Bit verbose but works
+        let chat_session_read_task = Task::stream(chat_session_reader)
+            .map(move |chat_message_result| match chat_message_result {
+                Ok(chat_message) => {
+                    ChatPageMessage::AddMessageToHistory(chat_message).for_session(session_id)
+                }
+                Err(err) => ErrorPopupMessage::AddError(format!("{session_title}: {err}")).into(),
+            })
+            // The stream only ends once the connection is closed
+            .chain(Task::done(
+                ChatPageMessage::ConnectionClosed.for_session(session_id),
+            ));
+        let chat_worker_update_stream = Task::stream(ChatPage::init_worker(
+            session_id,
+            chat_page.username().to_string(),
+            chat_session_writer,
+        ));
+        Task::batch(vec![chat_session_read_task, chat_worker_update_stream])
+    }
+
+    fn close_session(&mut self, session_id: SessionId) {
+        self.chat_sessions.remove(&session_id);
+        if self.active_session == Some(session_id) {
+            self.active_session = self.chat_sessions.keys().next().copied();
+        }
+    }
 }
 
 fn title(_app: &Messenger) -> String {
@@ -31,25 +101,59 @@ fn title(_app: &Messenger) -> String {
 
 fn update(app: &mut Messenger, message: AppUpdateMessage) -> Task<AppUpdateMessage> {
     match message {
-        AppUpdateMessage::BeginChat(chat_session, username) => {
-            // Start read Stream as a subscription
-            // Send the Writer to the chat component to be used there
-            let (chat_session_writer, chat_session_reader) = chat_session.split();
-
-            // Bit verbose but works
-            let chat_session_read_task =
-                Task::stream(chat_session_reader).map(|chat_message_result| {
-                    match chat_message_result {
-                        Ok(chat_message) => {
-                            ChatPageMessage::AddMessageToHistory(chat_message).into()
-                        }
-                        Err(err) => ErrorPopupMessage::AddError(err.to_string()).into(),
-                    }
-                });
-            let chat_worker_update_stream =
-                Task::stream(ChatPage::init_worker(username, chat_session_writer));
-            Task::batch(vec![chat_session_read_task, chat_worker_update_stream])
+        AppUpdateMessage::BeginChat(chat_session, connection_details) => {
+            let session_id = app.next_session_id;
+            app.next_session_id += 1;
+
+            // Accounts keep the spelling they were registered with
+            let connection_details = ConnectionDetails {
+                username: chat_session.username().to_string(),
+                ..connection_details
+            };
+            app.chat_sessions
+                .insert(session_id, ChatPage::new(session_id, connection_details));
+            app.active_session = Some(session_id);
+            app.start_chat_tasks(session_id, *chat_session)
+        }
+        AppUpdateMessage::ResumeChat(session_id, chat_session) => {
+            app.start_chat_tasks(session_id, *chat_session)
+        }
+        AppUpdateMessage::SessionTabsMessage(SessionTabsMessage::SelectSession(session_id)) => {
+            if let Some(chat_page) = app.chat_sessions.get_mut(&session_id) {
+                chat_page.mark_read();
+                app.active_session = Some(session_id);
+            }
+            Task::none()
+        }
+        AppUpdateMessage::SessionTabsMessage(SessionTabsMessage::NewConnection) => {
+            app.active_session = None;
+            Task::none()
         }
+        AppUpdateMessage::FileDropped(path_buf) => match app.active_session {
+            Some(session_id) => update(
+                app,
+                ChatPageMessage::UploadFile(path_buf).for_session(session_id),
+            ),
+            None => Task::none(),
+        },
+        AppUpdateMessage::PasteFromClipboard => match app.active_session {
+            Some(session_id) => update(
+                app,
+                ChatPageMessage::PasteFromClipboard.for_session(session_id),
+            ),
+            None => Task::none(),
+        },
+        AppUpdateMessage::ReconnectCountdownTick => Task::batch(
+            app.chat_sessions
+                .values_mut()
+                .filter(|chat_page| chat_page.is_reconnect_scheduled())
+                .map(|chat_page| {
+                    chat_page
+                        .update(ChatPageMessage::ReconnectCountdownTick)
+                        .into()
+                })
+                .collect::<Vec<_>>(),
+        ),
 
         // Component Updaters
         AppUpdateMessage::LoginPageMessage(login_page_message) => {
@@ -58,19 +162,78 @@ fn update(app: &mut Messenger, message: AppUpdateMessage) -> Task<AppUpdateMessa
         AppUpdateMessage::ErrorPopupMessage(error_popup_message) => {
             app.error_popup.update(error_popup_message).into()
         }
-        AppUpdateMessage::ChatPageMessage(chat_page_message) => {
-            app.chat_page.update(chat_page_message).into()
+        AppUpdateMessage::ChatPageMessage(session_id, chat_page_message) => {
+            let is_active_session = app.active_session == Some(session_id);
+            // Messages for a session that was already closed are dropped
+            let Some(chat_page) = app.chat_sessions.get_mut(&session_id) else {
+                return Task::none();
+            };
+
+            if !is_active_session && chat_page_message.is_new_chat_message() {
+                chat_page.mark_unread();
+            }
+
+            let chat_page_task = chat_page.update(chat_page_message).into();
+            if chat_page.is_closed() {
+                app.close_session(session_id);
+            }
+            chat_page_task
         }
     }
 }
 
-fn view(app: &Messenger) -> iced::Element<AppUpdateMessage> {
-    let main_ui: Element<AppUpdateMessage> = if app.chat_page.is_in_chat() {
-        app.chat_page.view().map(AppUpdateMessage::ChatPageMessage)
-    } else {
-        app.login_page
+/// Tab bar to switch between the open sessions, with a tab to open a new one
+fn session_tabs(app: &Messenger) -> Element<'_, SessionTabsMessage> {
+    let tabs = app
+        .chat_sessions
+        .iter()
+        .fold(row![].spacing(2), |tabs, (session_id, chat_page)| {
+            let tab_label = match chat_page.unread_messages() {
+                0 => chat_page.title().to_string(),
+                unread_messages => format!("{} ({unread_messages})", chat_page.title()),
+            };
+            let tab_style = if app.active_session == Some(*session_id) {
+                button::primary
+            } else {
+                button::secondary
+            };
+            tabs.push(
+                button(text(tab_label))
+                    .style(tab_style)
+                    .on_press(SessionTabsMessage::SelectSession(*session_id))
+                    .padding(5),
+            )
+        });
+    let new_connection_tab = button(text("+"))
+        .style(if app.active_session.is_none() {
+            button::primary
+        } else {
+            button::secondary
+        })
+        .on_press(SessionTabsMessage::NewConnection)
+        .padding(5);
+
+    tabs.push(new_connection_tab).into()
+}
+
+fn view(app: &Messenger) -> iced::Element<'_, AppUpdateMessage> {
+    let current_page: Element<AppUpdateMessage> = match app
+        .active_session
+        .and_then(|session_id| Some((session_id, app.chat_sessions.get(&session_id)?)))
+    {
+        Some((session_id, chat_page)) => chat_page
             .view()
-            .map(AppUpdateMessage::LoginPageMessage)
+            .map(move |chat_page_message| chat_page_message.for_session(session_id)),
+        None => app
+            .login_page
+            .view()
+            .map(AppUpdateMessage::LoginPageMessage),
+    };
+    let main_ui: Element<AppUpdateMessage> = if app.chat_sessions.is_empty() {
+        current_page
+    } else {
+        let session_tabs = session_tabs(app).map(AppUpdateMessage::SessionTabsMessage);
+        column![session_tabs, current_page].spacing(5).into()
     };
     let error_popup = app
         .error_popup
@@ -84,12 +247,42 @@ fn view(app: &Messenger) -> iced::Element<AppUpdateMessage> {
         .into()
 }
 
-fn subscription(_app: &Messenger) -> iced::Subscription<AppUpdateMessage> {
-    Subscription::none()
+/// Window events the chat cares about, listened to even if a widget
+/// already handled them so Ctrl+V in the chat input still checks for images
+fn window_event(
+    event: Event,
+    _status: event::Status,
+    _window: window::Id,
+) -> Option<AppUpdateMessage> {
+    match event {
+        Event::Window(window::Event::FileDropped(path_buf)) => {
+            Some(AppUpdateMessage::FileDropped(path_buf))
+        }
+        Event::Keyboard(keyboard::Event::KeyPressed {
+            key: keyboard::Key::Character(character),
+            modifiers,
+            ..
+        }) if modifiers.command() && character.as_str() == "v" => {
+            Some(AppUpdateMessage::PasteFromClipboard)
+        }
+        _ => None,
+    }
+}
+
+fn subscription(app: &Messenger) -> iced::Subscription<AppUpdateMessage> {
+    let reconnect_countdown = if app
+        .chat_sessions
+        .values()
+        .any(ChatPage::is_reconnect_scheduled)
+    {
+        iced::time::every(Duration::from_secs(1)).map(|_| AppUpdateMessage::ReconnectCountdownTick)
+    } else {
+        Subscription::none()
+    };
+    Subscription::batch([reconnect_countdown, event::listen_with(window_event)])
 }
 
 fn theme(app: &Messenger) -> Theme {
-    // Temporary, see comment for this function in the LoginPage source
     app.login_page.get_selected_theme()
 }
 
@@ -97,5 +290,5 @@ fn main() -> Result<(), iced::Error> {
     let app = application(title, update, view)
         .subscription(subscription)
         .theme(theme);
-    app.run()
+    app.run_with(|| (Messenger::default(), LoginPage::load_settings()))
 }
@@ -0,0 +1,158 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result};
+use iced::Theme;
+use serde::{Deserialize, Serialize};
+
+const APP_NAME: &str = "msger";
+const SETTINGS_NAME: &str = "settings";
+const MAXIMUM_RECENT_SERVERS: usize = 10;
+
+/// Incremented for every save so a snapshot that reaches the blocking pool
+/// after a newer one can be dropped instead of overwriting it
+static SAVE_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// Generation of the last stored snapshot, held while storing so only one
+/// save uses the temporary file at a time
+static STORED_GENERATION: Mutex<u64> = Mutex::new(0);
+
+/// # GUI Settings
+/// Everything the GUI remembers between launches, stored as TOML in the
+/// platform config directory (`$XDG_CONFIG_HOME/msger` on Linux)
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Settings {
+    /// Name of the selected [Theme], stored as its display name since
+    /// [Theme] does not implement serde traits
+    #[serde(default)]
+    theme: Option<String>,
+
+    /// Servers that were successfully connected to, most recent first
+    #[serde(default)]
+    recent_servers: Vec<String>,
+
+    #[serde(default)]
+    profiles: Vec<ServerProfile>,
+}
+
+/// A named set of login details so the user does not need to retype them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ServerProfile {
+    pub(crate) name: String,
+    pub(crate) server_addr: String,
+    pub(crate) username: String,
+    /// Only stored if the user opted to remember it
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+}
+
+impl std::fmt::Display for ServerProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl Settings {
+    pub(crate) fn theme(&self) -> Theme {
+        self.theme
+            .as_ref()
+            .and_then(|theme_name| {
+                Theme::ALL
+                    .iter()
+                    .find(|theme| theme.to_string() == *theme_name)
+            })
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn set_theme(&mut self, theme: &Theme) {
+        self.theme = Some(theme.to_string());
+    }
+
+    pub(crate) fn recent_servers(&self) -> &[String] {
+        &self.recent_servers
+    }
+
+    /// Move the server to the front of the recent list, dropping the oldest
+    /// entry if the list gets too long
+    pub(crate) fn add_recent_server(&mut self, server_addr: &str) {
+        self.recent_servers.retain(|addr| addr != server_addr);
+        self.recent_servers.insert(0, server_addr.to_string());
+        self.recent_servers.truncate(MAXIMUM_RECENT_SERVERS);
+    }
+
+    pub(crate) fn profiles(&self) -> &[ServerProfile] {
+        &self.profiles
+    }
+
+    /// Add the profile, replacing any existing profile with the same name
+    pub(crate) fn save_profile(&mut self, profile: ServerProfile) {
+        match self
+            .profiles
+            .iter_mut()
+            .find(|existing| existing.name == profile.name)
+        {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    pub(crate) fn remove_profile(&mut self, profile_name: &str) {
+        self.profiles.retain(|profile| profile.name != profile_name);
+    }
+
+    /// Write the settings to a temporary file first and then rename it over
+    /// the real one so a crash mid-write can't leave a half written file
+    fn store(&self, generation: u64) -> Result<()> {
+        let mut stored_generation = STORED_GENERATION
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if generation <= *stored_generation {
+            return Ok(());
+        }
+
+        let settings_path = settings_path()?;
+        let temp_settings_path = settings_path.with_extension("toml.tmp");
+
+        // Remembered passwords are stored in plain text so keep the file private
+        #[cfg(unix)]
+        let store_result = {
+            use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+            confy::store_path_perms(&temp_settings_path, self, Permissions::from_mode(0o600))
+        };
+        #[cfg(not(unix))]
+        let store_result = confy::store_path(&temp_settings_path, self);
+        store_result.context("Could not write settings file")?;
+
+        std::fs::rename(&temp_settings_path, &settings_path)
+            .context("Could not replace settings file")?;
+        *stored_generation = generation;
+        Ok(())
+    }
+}
+
+fn settings_path() -> Result<PathBuf> {
+    confy::get_configuration_file_path(APP_NAME, SETTINGS_NAME)
+        .context("Could not find a config directory for the settings file")
+}
+
+/// Load the settings on the blocking thread pool, creating the file
+/// with default values if it doesn't exist yet
+pub(crate) async fn load() -> Result<Settings> {
+    tokio::task::spawn_blocking(|| {
+        confy::load_path(settings_path()?).context("Could not load settings file")
+    })
+    .await
+    .context("Settings loading task did not finish")?
+}
+
+pub(crate) async fn save(settings: Settings) -> Result<()> {
+    let generation = SAVE_GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    tokio::task::spawn_blocking(move || settings.store(generation))
+        .await
+        .context("Settings saving task did not finish")?
+}
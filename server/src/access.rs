@@ -0,0 +1,437 @@
+use std::{collections::HashSet, fmt::Display, net::IpAddr, str::FromStr};
+
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio_tungstenite::tungstenite::http::{
+    HeaderMap,
+    header::{AsHeaderName, FORWARDED},
+};
+
+use crate::{
+    config::ServerConfig,
+    error::{AccessError, CapacityError, InvalidIpRange},
+    moderation::Ban,
+    username::confusable_skeleton,
+};
+
+/// Header most reverse proxies append the address they were connected from to
+const X_FORWARDED_FOR_KEY: &str = "x-forwarded-for";
+
+/// Which header the trusted reverse proxies append the client's address to.
+/// Proxies pass along whatever else the client sent, so only this one is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ForwardedHeader {
+    /// `X-Forwarded-For`, set by nginx and most other proxies
+    #[default]
+    XForwardedFor,
+    /// `Forwarded` from RFC 7239
+    Forwarded,
+}
+
+/// An IP address or a CIDR range of them, such as `10.1.0.0/16` or
+/// `2001:db8::/32`. A single address is a range of just that address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct IpRange(IpNet);
+
+impl IpRange {
+    /// IPv4 clients of a dual stack listener show up as IPv4-mapped IPv6
+    /// addresses, they are matched as the IPv4 address they stand for
+    pub(crate) fn contains(&self, ip_addr: IpAddr) -> bool {
+        self.0.contains(&ip_addr.to_canonical())
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(ip_addr: IpAddr) -> Self {
+        Self(IpNet::from(ip_addr.to_canonical()))
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    /// Bits past the prefix are dropped, `10.1.2.3/16` is `10.1.0.0/16`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.contains('/') {
+            IpNet::from_str(text)
+                .map(|ip_net| Self(ip_net.trunc()))
+                .map_err(|_| InvalidIpRange(text.to_string()))
+        } else {
+            IpAddr::from_str(text)
+                .map(Self::from)
+                .map_err(|_| InvalidIpRange(text.to_string()))
+        }
+    }
+}
+
+/// Single addresses are written without a prefix length
+impl Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.prefix_len() == self.0.max_prefix_len() {
+            write!(f, "{}", self.0.addr())
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// # Access Rules
+/// Who may connect: the allowlist and bans from the config, along with the
+/// bans in the ban store. Handshakes can't wait on the moderation lock, so
+/// each one checks a copy taken before it starts
+#[derive(Debug, Clone)]
+pub(crate) struct AccessRules {
+    /// Anyone may connect when empty
+    allowed_ranges: Vec<IpRange>,
+    banned_ranges: Vec<IpRange>,
+    banned_skeletons: HashSet<String>,
+    /// Ban store bans that hadn't expired when the copy was taken
+    bans: Vec<Ban>,
+}
+
+impl AccessRules {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        Self {
+            allowed_ranges: config.allowed_ranges.clone(),
+            banned_ranges: config.banned_users.clone(),
+            banned_skeletons: config
+                .banned_usernames
+                .iter()
+                .map(|username| confusable_skeleton(username))
+                .collect(),
+            bans: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_bans(&self, bans: Vec<Ban>) -> Self {
+        Self {
+            bans,
+            ..self.clone()
+        }
+    }
+
+    /// Checked before anything else in the handshake
+    pub(crate) fn check_ip_addr(&self, ip_addr: IpAddr) -> Result<(), AccessError> {
+        if !self.allowed_ranges.is_empty()
+            && !self
+                .allowed_ranges
+                .iter()
+                .any(|allowed_range| allowed_range.contains(ip_addr))
+        {
+            return Err(AccessError::NotAllowed);
+        }
+        if self
+            .banned_ranges
+            .iter()
+            .any(|banned_range| banned_range.contains(ip_addr))
+        {
+            return Err(AccessError::Banned(String::new()));
+        }
+        match self.bans.iter().find(|ban| ban.applies_to_ip_addr(ip_addr)) {
+            Some(ban) => Err(AccessError::Banned(ban.sanction.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Also catches names that only look like a banned one
+    pub(crate) fn check_username(&self, username: &str) -> Result<(), AccessError> {
+        let username_skeleton = confusable_skeleton(username);
+        if self.banned_skeletons.contains(&username_skeleton) {
+            return Err(AccessError::Banned(String::new()));
+        }
+        match self
+            .bans
+            .iter()
+            .find(|ban| ban.applies_to_username(&username_skeleton))
+        {
+            Some(ban) => Err(AccessError::Banned(ban.sanction.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether there is room for one more user from `ip_addr`, given the
+/// addresses of everyone online
+pub(crate) fn check_capacity(
+    config: &ServerConfig,
+    online_ip_addrs: &[IpAddr],
+    ip_addr: IpAddr,
+) -> Result<(), CapacityError> {
+    if let Some(max_users) = config.max_users
+        && online_ip_addrs.len() >= max_users
+    {
+        return Err(CapacityError::ServerFull(max_users));
+    }
+    if let Some(max_connections_per_ip) = config.max_connections_per_ip
+        && online_ip_addrs
+            .iter()
+            .filter(|online_ip_addr| **online_ip_addr == ip_addr)
+            .count()
+            >= max_connections_per_ip
+    {
+        return Err(CapacityError::TooManyConnections(max_connections_per_ip));
+    }
+    Ok(())
+}
+
+/// Address of the client behind any trusted reverse proxies. Each trusted
+/// proxy vouches for the hop before it in `forwarded_header`, so the list is
+/// walked from the right until an address that isn't a trusted proxy. Anyone
+/// else's headers are ignored, since clients can send whatever they like in them
+pub(crate) fn resolve_client_ip_addr(
+    peer_ip_addr: IpAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpRange],
+    forwarded_header: ForwardedHeader,
+) -> IpAddr {
+    let is_trusted = |ip_addr: IpAddr| {
+        trusted_proxies
+            .iter()
+            .any(|trusted_proxy| trusted_proxy.contains(ip_addr))
+    };
+    let forwarded_for: Vec<&str> = match forwarded_header {
+        ForwardedHeader::XForwardedFor => header_list(headers, X_FORWARDED_FOR_KEY).collect(),
+        ForwardedHeader::Forwarded => header_list(headers, FORWARDED)
+            .filter_map(|forwarded_element| {
+                forwarded_element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for").then_some(value)
+                })
+            })
+            .collect(),
+    };
+
+    let mut client_ip_addr = peer_ip_addr.to_canonical();
+    for forwarded_node in forwarded_for.into_iter().rev() {
+        if !is_trusted(client_ip_addr) {
+            break;
+        }
+        // Hidden or unknown nodes can't be followed any further
+        match parse_forwarded_node(forwarded_node) {
+            Some(ip_addr) => client_ip_addr = ip_addr.to_canonical(),
+            None => break,
+        }
+    }
+    client_ip_addr
+}
+
+/// Comma separated items across every instance of the header, in order
+fn header_list(headers: &HeaderMap, name: impl AsHeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+}
+
+/// `192.0.2.60`, `192.0.2.60:4711`, `"[2001:db8::17]:4711"` or a bare IPv6
+/// address as some proxies write it in `X-Forwarded-For`
+fn parse_forwarded_node(forwarded_node: &str) -> Option<IpAddr> {
+    let forwarded_node = forwarded_node.trim_matches('"');
+    if let Some(bracketed) = forwarded_node.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    forwarded_node.parse().ok().or_else(|| {
+        let (ip_addr, _port) = forwarded_node.split_once(':')?;
+        ip_addr.parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn range(text: &str) -> IpRange {
+        text.parse().unwrap()
+    }
+
+    fn headers(name: &'static str, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn matches_cidr_ranges() {
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(!range("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+        assert!(range("10.1.2.3/32").contains(ip("10.1.2.3")));
+        assert!(!range("10.1.2.3/32").contains(ip("10.1.2.4")));
+        assert!(range("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!range("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert!(range("10.1.2.3/16").contains(ip("10.1.255.255")));
+        assert!(!range("10.1.2.3/16").contains(ip("10.2.0.0")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_as_ipv4() {
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped());
+        assert!(range("10.1.0.0/16").contains(mapped));
+        assert!(range("10.1.2.3").contains(mapped));
+        assert!(range("::ffff:10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!range("::/0").contains(mapped));
+    }
+
+    #[test]
+    fn parses_and_displays_ranges() {
+        assert_eq!(range("10.1.2.3/16").to_string(), "10.1.0.0/16");
+        assert_eq!(range("10.1.2.3").to_string(), "10.1.2.3");
+        assert_eq!(range("10.1.2.3/32").to_string(), "10.1.2.3");
+        assert_eq!(range("2001:db8::1/32").to_string(), "2001:db8::/32");
+        for invalid in [
+            "",
+            "10.1.2",
+            "10.1.2.3/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "host",
+        ] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{invalid:?} parsed");
+        }
+    }
+
+    #[test]
+    fn ignores_forwarded_headers_from_untrusted_peers() {
+        let spoofed = headers(X_FORWARDED_FOR_KEY, &["198.51.100.1"]);
+        let client_ip_addr = resolve_client_ip_addr(
+            ip("203.0.113.7"),
+            &spoofed,
+            &[range("10.0.0.0/8")],
+            ForwardedHeader::XForwardedFor,
+        );
+        assert_eq!(client_ip_addr, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn takes_the_right_most_untrusted_hop() {
+        // The client claims to be 198.51.100.1, its real address was
+        // appended by the first proxy, which the second proxy vouches for
+        let forwarded_for = headers(
+            X_FORWARDED_FOR_KEY,
+            &["198.51.100.1, 203.0.113.7", "10.0.0.2"],
+        );
+        let client_ip_addr = resolve_client_ip_addr(
+            ip("10.0.0.1"),
+            &forwarded_for,
+            &[range("10.0.0.0/8")],
+            ForwardedHeader::XForwardedFor,
+        );
+        assert_eq!(client_ip_addr, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn only_reads_the_configured_header() {
+        let mut both = headers(X_FORWARDED_FOR_KEY, &["198.51.100.1"]);
+        both.append(FORWARDED, HeaderValue::from_static("for=203.0.113.7"));
+        let trusted_proxies = [range("10.0.0.1")];
+        assert_eq!(
+            resolve_client_ip_addr(
+                ip("10.0.0.1"),
+                &both,
+                &trusted_proxies,
+                ForwardedHeader::Forwarded
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            resolve_client_ip_addr(
+                ip("10.0.0.1"),
+                &both,
+                &trusted_proxies,
+                ForwardedHeader::XForwardedFor
+            ),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn reads_forwarded_nodes() {
+        let forwarded = headers(
+            "forwarded",
+            &[r#"for="[2001:db8::17]:4711";proto=https, For=192.0.2.60:4711;by=10.0.0.2"#],
+        );
+        let client_ip_addr = resolve_client_ip_addr(
+            ip("10.0.0.1"),
+            &forwarded,
+            &[range("10.0.0.0/8"), range("192.0.2.60")],
+            ForwardedHeader::Forwarded,
+        );
+        assert_eq!(
+            client_ip_addr,
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x17))
+        );
+    }
+
+    #[test]
+    fn stops_at_malformed_forwarded_nodes() {
+        let trusted_proxies = [range("10.0.0.0/8")];
+        for malformed in [
+            "for=unknown",
+            "for=_hidden",
+            r#"for="[2001:db8::17""#,
+            "for=",
+            "for=300.1.2.3",
+            "proto=https",
+            "garbage",
+        ] {
+            let forwarded = headers("forwarded", &[malformed]);
+            let client_ip_addr = resolve_client_ip_addr(
+                ip("10.0.0.1"),
+                &forwarded,
+                &trusted_proxies,
+                ForwardedHeader::Forwarded,
+            );
+            assert_eq!(client_ip_addr, ip("10.0.0.1"), "followed {malformed:?}");
+        }
+        // Nothing before a hidden node is believed
+        let forwarded = headers("forwarded", &["for=198.51.100.1, for=_hidden"]);
+        let client_ip_addr = resolve_client_ip_addr(
+            ip("10.0.0.1"),
+            &forwarded,
+            &trusted_proxies,
+            ForwardedHeader::Forwarded,
+        );
+        assert_eq!(client_ip_addr, ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn resolves_ipv4_mapped_peers_as_ipv4() {
+        let mapped_proxy = IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+        let forwarded_for = headers(X_FORWARDED_FOR_KEY, &["::ffff:203.0.113.7"]);
+        let client_ip_addr = resolve_client_ip_addr(
+            mapped_proxy,
+            &forwarded_for,
+            &[range("10.0.0.1")],
+            ForwardedHeader::XForwardedFor,
+        );
+        assert_eq!(client_ip_addr, ip("203.0.113.7"));
+    }
+}
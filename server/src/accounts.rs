@@ -0,0 +1,534 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{
+        SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use clap::ValueEnum;
+use log::*;
+use serde::{Deserialize, Serialize};
+use shared_types::protocol::AccountAction;
+
+use crate::{config::ServerConfig, error::AccountError, username::confusable_skeleton};
+
+/// Wait after the first failed sign in, doubled with every failure after it
+const SIGN_IN_BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// Longest wait after failed sign ins, failures older than this are forgotten
+const SIGN_IN_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Password hashes checked or made at once, each one takes about 19 MiB
+/// and a whole core for a while
+const MAX_CONCURRENT_PASSWORD_HASHES: usize = 4;
+/// Longest a sign in waits for the others to be hashed before it is refused
+const PASSWORD_HASH_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether users sign in to accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AccountMode {
+    /// Anyone can use any name that isn't taken, nobody can register
+    #[default]
+    Off,
+    /// Users may register names, guests can use any name that isn't registered
+    AllowGuests,
+    /// Only users with an account can join
+    Required,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Account {
+    /// Spelled the way it was registered
+    username: String,
+    /// Argon2 hash in the PHC string format, which includes the salt and parameters
+    password_hash: String,
+    registered_at: SystemTime,
+    /// Moved on whenever the password changes, so session tokens issued
+    /// before then can't be used to resume anymore
+    #[serde(default)]
+    token_generation: u64,
+}
+
+/// What a client sent to sign in to an account during the handshake
+#[derive(Debug)]
+pub(crate) struct Credentials {
+    pub(crate) action: AccountAction,
+    pub(crate) password: String,
+    /// Only used when changing the password
+    pub(crate) maybe_new_password: Option<String>,
+}
+
+/// A user whose credentials were checked during the handshake
+#[derive(Debug)]
+pub(crate) struct SignIn {
+    /// The account's username or the name the guest asked for
+    pub(crate) username: String,
+    pub(crate) is_account: bool,
+    /// Goes into the session token issued for this sign in
+    pub(crate) token_generation: u64,
+    /// Random for a new sign in, the token's own when resuming
+    pub(crate) session_id: u64,
+    /// Registered or changed account, stored once the handshake completes
+    updated_account: Option<Account>,
+    /// Whether the updated account is a new one, which may not replace an
+    /// account registered by someone else in the meantime
+    is_registration: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FailedSignIns {
+    failures: u32,
+    last_failure: Instant,
+}
+
+impl FailedSignIns {
+    fn retry_at(&self) -> Instant {
+        let backoff = SIGN_IN_BACKOFF_BASE
+            .saturating_mul(2u32.saturating_pow(self.failures.saturating_sub(1)))
+            .min(SIGN_IN_BACKOFF_MAX);
+        self.last_failure + backoff
+    }
+}
+
+/// Failed sign ins by IP address and by account, so guessing passwords gets
+/// slower whether one address tries many accounts or many addresses try one
+#[derive(Debug, Default)]
+struct SignInBackoff {
+    by_ip_addr: HashMap<IpAddr, FailedSignIns>,
+    /// By the skeleton of the username
+    by_account: HashMap<String, FailedSignIns>,
+    /// Sign ins still being checked, which have to finish before another
+    /// one from the same address or for the same account may start
+    ip_addrs_signing_in: HashSet<IpAddr>,
+    accounts_signing_in: HashSet<String>,
+}
+
+impl SignInBackoff {
+    /// Check the backoff and hold the spot of the address and the account
+    /// until the sign in is done, so attempts running at the same time can't
+    /// all get past the check before any of them failed
+    fn reserve(&mut self, ip_addr: IpAddr, username_skeleton: &str) -> Result<(), AccountError> {
+        if self.ip_addrs_signing_in.contains(&ip_addr)
+            || self.accounts_signing_in.contains(username_skeleton)
+        {
+            return Err(AccountError::SignInInProgress);
+        }
+        self.check(ip_addr, username_skeleton)?;
+        self.ip_addrs_signing_in.insert(ip_addr);
+        self.accounts_signing_in
+            .insert(username_skeleton.to_string());
+        Ok(())
+    }
+
+    fn release(&mut self, ip_addr: IpAddr, username_skeleton: &str) {
+        self.ip_addrs_signing_in.remove(&ip_addr);
+        self.accounts_signing_in.remove(username_skeleton);
+    }
+
+    fn check(&self, ip_addr: IpAddr, username_skeleton: &str) -> Result<(), AccountError> {
+        let maybe_retry_at = [
+            self.by_ip_addr.get(&ip_addr),
+            self.by_account.get(username_skeleton),
+        ]
+        .into_iter()
+        .flatten()
+        .map(FailedSignIns::retry_at)
+        .max();
+        match maybe_retry_at {
+            Some(retry_at) if retry_at > Instant::now() => Err(AccountError::TooManyAttempts(
+                (retry_at - Instant::now()).as_secs().max(1),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn record_failure(&mut self, ip_addr: IpAddr, username_skeleton: &str) {
+        let now = Instant::now();
+        let is_recent = |failed_sign_ins: &FailedSignIns| {
+            now.duration_since(failed_sign_ins.last_failure) < SIGN_IN_BACKOFF_MAX
+        };
+        self.by_ip_addr
+            .retain(|_, failed_sign_ins| is_recent(failed_sign_ins));
+        self.by_account
+            .retain(|_, failed_sign_ins| is_recent(failed_sign_ins));
+        let no_failures = FailedSignIns {
+            failures: 0,
+            last_failure: now,
+        };
+        for failed_sign_ins in [
+            self.by_ip_addr.entry(ip_addr).or_insert(no_failures),
+            self.by_account
+                .entry(username_skeleton.to_string())
+                .or_insert(no_failures),
+        ] {
+            failed_sign_ins.failures = failed_sign_ins.failures.saturating_add(1);
+            failed_sign_ins.last_failure = now;
+        }
+    }
+}
+
+/// A sign in that holds its spot in the backoff, given up when dropped
+struct SignInReservation<'a> {
+    sign_in_backoff: &'a Mutex<SignInBackoff>,
+    ip_addr: IpAddr,
+    username_skeleton: &'a str,
+}
+
+impl<'a> SignInReservation<'a> {
+    fn new(
+        sign_in_backoff: &'a Mutex<SignInBackoff>,
+        ip_addr: IpAddr,
+        username_skeleton: &'a str,
+    ) -> Result<Self, AccountError> {
+        sign_in_backoff
+            .lock()
+            .expect("sign in backoff lock to not be poisoned")
+            .reserve(ip_addr, username_skeleton)?;
+        Ok(Self {
+            sign_in_backoff,
+            ip_addr,
+            username_skeleton,
+        })
+    }
+
+    fn record_failure(&self) {
+        self.sign_in_backoff
+            .lock()
+            .expect("sign in backoff lock to not be poisoned")
+            .record_failure(self.ip_addr, self.username_skeleton);
+    }
+}
+
+impl Drop for SignInReservation<'_> {
+    fn drop(&mut self) {
+        self.sign_in_backoff
+            .lock()
+            .expect("sign in backoff lock to not be poisoned")
+            .release(self.ip_addr, self.username_skeleton);
+    }
+}
+
+/// Skeletons of every registered username, shared with whatever checks
+/// names outside of handshakes while the account store is busy with them
+#[derive(Debug, Clone)]
+pub(crate) struct RegisteredNames(Arc<RwLock<HashSet<String>>>);
+
+impl RegisteredNames {
+    pub(crate) fn contains(&self, username: &str) -> bool {
+        self.0
+            .read()
+            .expect("registered names lock to not be poisoned")
+            .contains(&confusable_skeleton(username))
+    }
+
+    fn insert(&self, username_skeleton: String) {
+        self.0
+            .write()
+            .expect("registered names lock to not be poisoned")
+            .insert(username_skeleton);
+    }
+}
+
+/// # Account Store
+/// Registered users and their hashed passwords, kept in a JSON file. Accounts
+/// are looked up by the confusable skeleton of their username so nobody can
+/// pass as a registered user with a name that only looks like theirs. Shared
+/// by every handshake, which may run at the same time
+pub(crate) struct AccountStore {
+    accounts_file: PathBuf,
+    account_mode: AccountMode,
+    password_min_length: usize,
+    /// Every account by the skeleton of its username
+    accounts: RwLock<HashMap<String, Account>>,
+    registered_names: RegisteredNames,
+    sign_in_backoff: Mutex<SignInBackoff>,
+    /// Limits the password hashes running at once
+    password_hashes: tokio::sync::Semaphore,
+    /// Checked against when the username is unknown, so refusing it takes as
+    /// long as refusing a wrong password and doesn't give away who is registered
+    dummy_password_hash: String,
+    /// Held while writing the accounts file so an older state can't overwrite a newer one
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl AccountStore {
+    /// Load the accounts file, nothing is read when accounts are off
+    pub(crate) async fn open(config: &ServerConfig) -> Result<Self, AccountError> {
+        let accounts: HashMap<String, Account> = if config.account_mode == AccountMode::Off {
+            HashMap::new()
+        } else {
+            match tokio::fs::read(&config.accounts_file).await {
+                Ok(accounts) => serde_json::from_slice(&accounts)?,
+                Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+                Err(error) => return Err(error.into()),
+            }
+        };
+        let dummy_password_hash = tokio::task::spawn_blocking(|| {
+            let salt = SaltString::generate(&mut OsRng);
+            Ok::<_, AccountError>(
+                Argon2::default()
+                    .hash_password(b"not a password anyone uses", &salt)?
+                    .to_string(),
+            )
+        })
+        .await??;
+        if config.account_mode != AccountMode::Off {
+            info!(
+                "Opened accounts file {} with {} accounts",
+                config.accounts_file.display(),
+                accounts.len()
+            );
+        }
+        Ok(Self {
+            accounts_file: config.accounts_file.clone(),
+            account_mode: config.account_mode,
+            password_min_length: config.password_min_length,
+            registered_names: RegisteredNames(Arc::new(RwLock::new(
+                accounts.keys().cloned().collect(),
+            ))),
+            accounts: RwLock::new(accounts),
+            sign_in_backoff: Mutex::new(SignInBackoff::default()),
+            password_hashes: tokio::sync::Semaphore::new(MAX_CONCURRENT_PASSWORD_HASHES),
+            dummy_password_hash,
+            save_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Check the credentials sent with a handshake for an already validated
+    /// username. Hashing is slow on purpose, so it runs on the blocking
+    /// thread pool, a few hashes at a time. Only one sign in from the same IP
+    /// address or for the same account is checked at once, and every failure
+    /// makes the next attempt wait longer
+    pub(crate) async fn sign_in(
+        &self,
+        username: &str,
+        ip_addr: IpAddr,
+        maybe_credentials: Option<Credentials>,
+    ) -> Result<SignIn, AccountError> {
+        let Some(credentials) = maybe_credentials else {
+            return self.guest_sign_in(username, OsRng.next_u64());
+        };
+        if self.account_mode == AccountMode::Off {
+            return Err(AccountError::Disabled);
+        }
+        let username_skeleton = confusable_skeleton(username);
+        let reservation =
+            SignInReservation::new(&self.sign_in_backoff, ip_addr, &username_skeleton)?;
+        let _password_hash_permit =
+            tokio::time::timeout(PASSWORD_HASH_QUEUE_TIMEOUT, self.password_hashes.acquire())
+                .await
+                .map_err(|_| AccountError::Busy)?
+                .expect("password hash semaphore to never be closed");
+        let maybe_account = self.account(&username_skeleton);
+
+        let sign_in_result = match credentials.action {
+            AccountAction::Login => self
+                .verify_password(maybe_account, credentials.password)
+                .await
+                .map(|account| SignIn {
+                    username: account.username,
+                    is_account: true,
+                    token_generation: account.token_generation,
+                    session_id: OsRng.next_u64(),
+                    updated_account: None,
+                    is_registration: false,
+                }),
+            AccountAction::Register => match maybe_account {
+                Some(account) => Err(AccountError::AlreadyRegistered(account.username)),
+                None => self
+                    .hash_password(credentials.password)
+                    .await
+                    .map(|password_hash| Account {
+                        username: username.to_string(),
+                        password_hash,
+                        registered_at: SystemTime::now(),
+                        token_generation: 0,
+                    })
+                    .map(|account| SignIn {
+                        username: account.username.clone(),
+                        is_account: true,
+                        token_generation: account.token_generation,
+                        session_id: OsRng.next_u64(),
+                        updated_account: Some(account),
+                        is_registration: true,
+                    }),
+            },
+            AccountAction::ChangePassword => {
+                async {
+                    let account = self
+                        .verify_password(maybe_account, credentials.password)
+                        .await?;
+                    let new_password = credentials
+                        .maybe_new_password
+                        .ok_or(AccountError::MissingPassword)?;
+                    let changed_account = Account {
+                        password_hash: self.hash_password(new_password).await?,
+                        token_generation: account.token_generation.wrapping_add(1),
+                        ..account
+                    };
+                    Ok(SignIn {
+                        username: changed_account.username.clone(),
+                        is_account: true,
+                        token_generation: changed_account.token_generation,
+                        session_id: OsRng.next_u64(),
+                        updated_account: Some(changed_account),
+                        is_registration: false,
+                    })
+                }
+                .await
+            }
+        };
+        if let Err(AccountError::BadCredentials) = sign_in_result {
+            reservation.record_failure();
+        }
+        sign_in_result
+    }
+
+    /// Guests can use any name that isn't registered, unless accounts are required
+    fn guest_sign_in(&self, username: &str, session_id: u64) -> Result<SignIn, AccountError> {
+        match (
+            self.account_mode,
+            self.account(&confusable_skeleton(username)),
+        ) {
+            (AccountMode::Required, _) => Err(AccountError::Required),
+            (_, Some(account)) => Err(AccountError::Registered(account.username)),
+            (_, None) => Ok(SignIn {
+                username: username.to_string(),
+                is_account: false,
+                token_generation: 0,
+                session_id,
+                updated_account: None,
+                is_registration: false,
+            }),
+        }
+    }
+
+    /// Sign in with a session token issued to `username`. Accounts can resume
+    /// for as long as they exist and their password hasn't changed since,
+    /// guests as long as they could still join with the name
+    pub(crate) fn resume(
+        &self,
+        username: &str,
+        is_account: bool,
+        token_generation: u64,
+        session_id: u64,
+    ) -> Result<SignIn, AccountError> {
+        if !is_account {
+            return self.guest_sign_in(username, session_id);
+        }
+        let account = self
+            .account(&confusable_skeleton(username))
+            .filter(|account| account.token_generation == token_generation)
+            .ok_or(AccountError::BadCredentials)?;
+        Ok(SignIn {
+            username: account.username,
+            is_account: true,
+            token_generation,
+            session_id,
+            updated_account: None,
+            is_registration: false,
+        })
+    }
+
+    /// Store the account a sign in registered or changed the password of
+    pub(crate) async fn complete_sign_in(&self, sign_in: SignIn) -> Result<(), AccountError> {
+        let Some(account) = sign_in.updated_account else {
+            return Ok(());
+        };
+        let username_skeleton = confusable_skeleton(&account.username);
+        {
+            let mut accounts_lock = self
+                .accounts
+                .write()
+                .expect("accounts lock to not be poisoned");
+            // Someone else registered the name while this handshake was running
+            if sign_in.is_registration
+                && let Some(registered_account) = accounts_lock.get(&username_skeleton)
+            {
+                return Err(AccountError::AlreadyRegistered(
+                    registered_account.username.clone(),
+                ));
+            }
+            info!("Saving the account of {}", account.username);
+            self.registered_names.insert(username_skeleton.clone());
+            accounts_lock.insert(username_skeleton, account);
+        }
+        self.save().await
+    }
+
+    pub(crate) fn registered_names(&self) -> RegisteredNames {
+        self.registered_names.clone()
+    }
+
+    fn account(&self, username_skeleton: &str) -> Option<Account> {
+        self.accounts
+            .read()
+            .expect("accounts lock to not be poisoned")
+            .get(username_skeleton)
+            .cloned()
+    }
+
+    /// Runs on the blocking thread pool since hashing is slow on purpose
+    async fn hash_password(&self, password: String) -> Result<String, AccountError> {
+        if password.chars().count() < self.password_min_length {
+            return Err(AccountError::PasswordTooShort(self.password_min_length));
+        }
+        tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Ok(Argon2::default()
+                .hash_password(password.as_bytes(), &salt)?
+                .to_string())
+        })
+        .await?
+    }
+
+    /// Unknown usernames and wrong passwords are refused the same way, and take
+    /// as long to refuse. Runs on the blocking thread pool since hashing is slow on purpose
+    async fn verify_password(
+        &self,
+        maybe_account: Option<Account>,
+        password: String,
+    ) -> Result<Account, AccountError> {
+        let password_hash = maybe_account.as_ref().map_or_else(
+            || self.dummy_password_hash.clone(),
+            |account| account.password_hash.clone(),
+        );
+        let is_verified = tokio::task::spawn_blocking(move || {
+            let password_hash = PasswordHash::new(&password_hash)?;
+            Ok::<_, AccountError>(
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &password_hash)
+                    .is_ok(),
+            )
+        })
+        .await??;
+        match maybe_account {
+            Some(account) if is_verified => Ok(account),
+            _ => Err(AccountError::BadCredentials),
+        }
+    }
+
+    /// Written to a temporary file first so a crash can't leave the accounts half written
+    async fn save(&self) -> Result<(), AccountError> {
+        let _save_lock = self.save_lock.lock().await;
+        let accounts = serde_json::to_vec_pretty(
+            &*self
+                .accounts
+                .read()
+                .expect("accounts lock to not be poisoned"),
+        )?;
+        let temporary_file = self.accounts_file.with_extension("tmp");
+        tokio::fs::write(&temporary_file, accounts).await?;
+        tokio::fs::rename(&temporary_file, &self.accounts_file).await?;
+        Ok(())
+    }
+}
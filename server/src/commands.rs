@@ -0,0 +1,540 @@
+use std::collections::BTreeMap;
+
+use log::*;
+use shared_types::messages::{DIRECT_MESSAGE_COMMAND, ServerMessage};
+
+use crate::{
+    error::CommandError,
+    message_log::Audience,
+    moderation::{
+        BAN_COMMAND, KICK_COMMAND, MUTE_COMMAND, ModerationCommand, Role, UNBAN_COMMAND,
+        UNMUTE_COMMAND,
+    },
+    scripts::ScriptCall,
+    username::confusable_skeleton,
+};
+
+pub(crate) const HELP_COMMAND: &str = "/help";
+pub(crate) const WHO_COMMAND: &str = "/who";
+pub(crate) const ME_COMMAND: &str = "/me";
+pub(crate) const NICK_COMMAND: &str = "/nick";
+pub(crate) const TOPIC_COMMAND: &str = "/topic";
+
+/// What a command gets to know about the server and whoever sent it
+pub struct CommandContext<'a> {
+    pub caller: &'a str,
+    pub caller_role: Role,
+    /// Name and role of everyone online, sorted by name
+    pub online_users: Vec<(&'a str, Role)>,
+    pub maybe_topic: Option<&'a str>,
+    pub(crate) commands: &'a CommandRegistry,
+}
+
+impl CommandContext<'_> {
+    /// Split the user a command is aimed at off the rest of its arguments.
+    /// Names can have spaces in them, so the longest run of words naming
+    /// someone online wins, then a name in double quotes, then the first word
+    pub fn split_target<'b>(&self, command_args: &'b str) -> (&'b str, &'b str) {
+        let command_args = command_args.trim_start();
+        let online_skeletons: Vec<String> = self
+            .online_users
+            .iter()
+            .map(|(name, _)| confusable_skeleton(name))
+            .collect();
+        let maybe_online_target = command_args
+            .match_indices(' ')
+            .map(|(idx, _)| idx)
+            .chain([command_args.len()])
+            .rev()
+            .find(|idx| online_skeletons.contains(&confusable_skeleton(&command_args[..*idx])));
+        if let Some(idx) = maybe_online_target {
+            return (&command_args[..idx], &command_args[idx..]);
+        }
+        if let Some((quoted_target, rest)) = command_args
+            .strip_prefix('"')
+            .and_then(|quoted_args| quoted_args.split_once('"'))
+            .filter(|(_, rest)| rest.is_empty() || rest.starts_with(' '))
+        {
+            return (quoted_target, rest);
+        }
+        command_args
+            .split_once(' ')
+            .unwrap_or((command_args.trim_end(), ""))
+    }
+}
+
+/// Something a command wants done, carried out by the server in order.
+/// Whatever only concerns the caller goes back to them alone
+#[derive(Debug)]
+pub enum CommandAction {
+    /// Text from the server only the caller sees
+    Reply(String),
+    /// Chat message from the caller to everyone, the caller included
+    Say(String),
+    DirectMessage {
+        recipient: String,
+        text: String,
+    },
+    /// Change the caller's name
+    Rename(String),
+    SetTopic(String),
+    Moderate(ModerationCommand),
+    /// A message of the command's own making, sent to whoever it is for
+    Deliver {
+        message: ServerMessage,
+        audience: Audience,
+    },
+    /// Call into a script once the server has let go of the users, only
+    /// made by commands that scripts registered
+    RunScript(ScriptCall),
+}
+
+/// # Command
+/// A slash command users can send as a text message. Commands only decide
+/// what should happen, the server checks and carries out the actions they
+/// return
+pub trait Command: Send + Sync {
+    /// Including the slash, such as `/who`
+    fn name(&self) -> &str;
+
+    /// Arguments as shown by `/help`, such as `<user> <text>`
+    fn usage(&self) -> &str {
+        ""
+    }
+
+    fn description(&self) -> &str;
+
+    /// Least role that may run the command, checked before it runs
+    fn required_role(&self) -> Role {
+        Role::User
+    }
+
+    fn run(
+        &self,
+        context: &CommandContext,
+        command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError>;
+}
+
+/// # Command Registry
+/// Every slash command the server answers to by name. Deployments add their
+/// own with [`crate::ServerBuilder::command`], replacing built-in ones of the
+/// same name, and scripts add and remove theirs while the server runs
+pub(crate) struct CommandRegistry {
+    commands: BTreeMap<String, Box<dyn Command>>,
+}
+
+impl CommandRegistry {
+    pub(crate) fn with_builtins() -> Self {
+        let mut registry = Self {
+            commands: BTreeMap::new(),
+        };
+        registry.register(HelpCommand);
+        registry.register(WhoCommand);
+        registry.register(MeCommand);
+        registry.register(NickCommand);
+        registry.register(TopicCommand);
+        registry.register(DirectMessageCommand);
+        registry.register(ModerationCommandEntry {
+            name: KICK_COMMAND,
+            usage: "<user> [reason]",
+            description: "Disconnect a user",
+            required_role: Role::Moderator,
+        });
+        registry.register(ModerationCommandEntry {
+            name: MUTE_COMMAND,
+            usage: "<user> [duration] [reason]",
+            description: "Stop a user from chatting, forever without a duration",
+            required_role: Role::Moderator,
+        });
+        registry.register(ModerationCommandEntry {
+            name: UNMUTE_COMMAND,
+            usage: "<user>",
+            description: "Let a muted user chat again",
+            required_role: Role::Moderator,
+        });
+        registry.register(ModerationCommandEntry {
+            name: BAN_COMMAND,
+            usage: "<user|range> [duration] [reason]",
+            description: "Disconnect a user or IP range and keep them out, forever without a duration",
+            required_role: Role::Admin,
+        });
+        registry.register(ModerationCommandEntry {
+            name: UNBAN_COMMAND,
+            usage: "<user|range>",
+            description: "Let a banned user or IP range back in",
+            required_role: Role::Admin,
+        });
+        registry
+    }
+
+    pub(crate) fn register(&mut self, command: impl Command + 'static) {
+        let name = command.name().to_string();
+        if self
+            .commands
+            .insert(name.clone(), Box::new(command))
+            .is_some()
+        {
+            debug!("Replaced the {name} command");
+        }
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.commands.contains_key(name)
+    }
+
+    pub(crate) fn unregister(&mut self, name: &str) {
+        if self.commands.remove(name).is_some() {
+            debug!("Removed the {name} command");
+        }
+    }
+
+    /// Commands a user with `role` may run, sorted by name
+    pub(crate) fn available_to(&self, role: Role) -> impl Iterator<Item = &dyn Command> {
+        self.commands
+            .values()
+            .map(|command| command.as_ref())
+            .filter(move |command| command.required_role() <= role)
+    }
+
+    /// Look up and run the command `text` starts with
+    pub(crate) fn run(
+        &self,
+        context: &CommandContext,
+        text: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        let (name, command_args) = text.split_once(' ').unwrap_or((text, ""));
+        let command = self
+            .commands
+            .get(name)
+            .ok_or_else(|| CommandError::Unknown(name.to_string()))?;
+        if context.caller_role < command.required_role() {
+            return Err(CommandError::NotPermitted(name.to_string()));
+        }
+        command.run(context, command_args.trim())
+    }
+}
+
+fn usage_error(command: &dyn Command) -> CommandError {
+    CommandError::Usage(format!("{} {}", command.name(), command.usage()))
+}
+
+struct HelpCommand;
+
+impl Command for HelpCommand {
+    fn name(&self) -> &str {
+        HELP_COMMAND
+    }
+
+    fn description(&self) -> &str {
+        "List the commands you can use"
+    }
+
+    fn run(
+        &self,
+        context: &CommandContext,
+        _command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        let command_lines: Vec<String> = context
+            .commands
+            .available_to(context.caller_role)
+            .map(|command| match command.usage() {
+                "" => format!("{} - {}", command.name(), command.description()),
+                usage => format!("{} {usage} - {}", command.name(), command.description()),
+            })
+            .collect();
+        Ok(vec![CommandAction::Reply(format!(
+            "Commands you can use:\n{}\nPut names with spaces in double quotes\nStart a message with // to send it as chat starting with /",
+            command_lines.join("\n")
+        ))])
+    }
+}
+
+struct WhoCommand;
+
+impl Command for WhoCommand {
+    fn name(&self) -> &str {
+        WHO_COMMAND
+    }
+
+    fn description(&self) -> &str {
+        "List everyone online"
+    }
+
+    fn run(
+        &self,
+        context: &CommandContext,
+        _command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        let online_users: Vec<String> = context
+            .online_users
+            .iter()
+            .map(|(name, role)| match role {
+                Role::User => name.to_string(),
+                Role::Moderator => format!("{name} (moderator)"),
+                Role::Admin => format!("{name} (admin)"),
+            })
+            .collect();
+        Ok(vec![CommandAction::Reply(format!(
+            "{} online: {}",
+            online_users.len(),
+            online_users.join(", ")
+        ))])
+    }
+}
+
+struct MeCommand;
+
+impl Command for MeCommand {
+    fn name(&self) -> &str {
+        ME_COMMAND
+    }
+
+    fn usage(&self) -> &str {
+        "<action>"
+    }
+
+    fn description(&self) -> &str {
+        "Describe what you are doing, /me waves shows as * you waves"
+    }
+
+    fn run(
+        &self,
+        context: &CommandContext,
+        command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        if command_args.is_empty() {
+            return Err(usage_error(self));
+        }
+        Ok(vec![CommandAction::Say(format!(
+            "* {} {command_args}",
+            context.caller
+        ))])
+    }
+}
+
+struct NickCommand;
+
+impl Command for NickCommand {
+    fn name(&self) -> &str {
+        NICK_COMMAND
+    }
+
+    fn usage(&self) -> &str {
+        "<name>"
+    }
+
+    fn description(&self) -> &str {
+        "Change your name, unless it belongs to your account"
+    }
+
+    fn run(
+        &self,
+        _context: &CommandContext,
+        command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        if command_args.is_empty() {
+            return Err(usage_error(self));
+        }
+        Ok(vec![CommandAction::Rename(command_args.to_string())])
+    }
+}
+
+struct TopicCommand;
+
+impl Command for TopicCommand {
+    fn name(&self) -> &str {
+        TOPIC_COMMAND
+    }
+
+    fn usage(&self) -> &str {
+        "[topic]"
+    }
+
+    fn description(&self) -> &str {
+        "Show the topic, moderators may also change it"
+    }
+
+    fn run(
+        &self,
+        context: &CommandContext,
+        command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        if command_args.is_empty() {
+            let reply = match context.maybe_topic {
+                Some(topic) => format!("Topic: {topic}"),
+                None => String::from("No topic is set"),
+            };
+            return Ok(vec![CommandAction::Reply(reply)]);
+        }
+        if context.caller_role < Role::Moderator {
+            return Err(CommandError::NotPermitted(format!(
+                "{TOPIC_COMMAND} to change the topic"
+            )));
+        }
+        Ok(vec![CommandAction::SetTopic(command_args.to_string())])
+    }
+}
+
+struct DirectMessageCommand;
+
+impl Command for DirectMessageCommand {
+    fn name(&self) -> &str {
+        DIRECT_MESSAGE_COMMAND
+    }
+
+    fn usage(&self) -> &str {
+        "<user> <text>"
+    }
+
+    fn description(&self) -> &str {
+        "Send a message only the user sees"
+    }
+
+    fn run(
+        &self,
+        context: &CommandContext,
+        command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        match context.split_target(command_args) {
+            (recipient, text) if !recipient.is_empty() && !text.trim().is_empty() => {
+                Ok(vec![CommandAction::DirectMessage {
+                    recipient: recipient.to_string(),
+                    text: text.trim().to_string(),
+                }])
+            }
+            _ => Err(usage_error(self)),
+        }
+    }
+}
+
+/// Kick, ban and mute commands, carried out by the server's moderation
+struct ModerationCommandEntry {
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    required_role: Role,
+}
+
+impl Command for ModerationCommandEntry {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn usage(&self) -> &str {
+        self.usage
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn required_role(&self) -> Role {
+        self.required_role
+    }
+
+    fn run(
+        &self,
+        context: &CommandContext,
+        command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        let (target, rest) = context.split_target(command_args);
+        let moderation_command = ModerationCommand::parse(self.name, target, rest)?;
+        Ok(vec![CommandAction::Moderate(moderation_command)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn run_command(text: &str) -> Vec<CommandAction> {
+        let commands = CommandRegistry::with_builtins();
+        let context = CommandContext {
+            caller: "Mod",
+            caller_role: Role::Admin,
+            online_users: vec![
+                ("Alice", Role::User),
+                ("Alice Smith", Role::User),
+                ("Mod", Role::Admin),
+            ],
+            maybe_topic: None,
+            commands: &commands,
+        };
+        commands.run(&context, text).expect("command to run")
+    }
+
+    #[test]
+    fn direct_messages_names_with_spaces() {
+        assert!(matches!(
+            run_command("/msg Alice Smith hi there").as_slice(),
+            [CommandAction::DirectMessage { recipient, text }]
+                if recipient == "Alice Smith" && text == "hi there"
+        ));
+        assert!(matches!(
+            run_command("/msg alice smith hi").as_slice(),
+            [CommandAction::DirectMessage { recipient, text }]
+                if recipient == "alice smith" && text == "hi"
+        ));
+        assert!(matches!(
+            run_command("/msg \"Alice\" Smith said hi").as_slice(),
+            [CommandAction::DirectMessage { recipient, text }]
+                if recipient == "Alice" && text == "Smith said hi"
+        ));
+        assert!(matches!(
+            run_command("/msg Alice hi").as_slice(),
+            [CommandAction::DirectMessage { recipient, text }]
+                if recipient == "Alice" && text == "hi"
+        ));
+    }
+
+    #[test]
+    fn moderates_names_with_spaces() {
+        assert!(matches!(
+            run_command("/kick Alice Smith").as_slice(),
+            [CommandAction::Moderate(ModerationCommand::Kick { target, maybe_reason: None })]
+                if target == "Alice Smith"
+        ));
+        assert!(matches!(
+            run_command("/kick \"Alice Smith\" spamming").as_slice(),
+            [CommandAction::Moderate(ModerationCommand::Kick { target, maybe_reason: Some(reason) })]
+                if target == "Alice Smith" && reason == "spamming"
+        ));
+        // Offline users can only be told apart from the reason by quoting them
+        assert!(matches!(
+            run_command("/ban \"Carol Jones\" 1h spamming").as_slice(),
+            [CommandAction::Moderate(ModerationCommand::Ban {
+                target,
+                maybe_duration: Some(duration),
+                maybe_reason: Some(reason),
+            })] if target == "Carol Jones"
+                && *duration == Duration::from_secs(3600)
+                && reason == "spamming"
+        ));
+        assert!(matches!(
+            run_command("/mute Carol Jones").as_slice(),
+            [CommandAction::Moderate(ModerationCommand::Mute { target, maybe_reason: Some(reason), .. })]
+                if target == "Carol" && reason == "Jones"
+        ));
+    }
+
+    #[test]
+    fn refuses_direct_messages_without_text() {
+        let commands = CommandRegistry::with_builtins();
+        let context = CommandContext {
+            caller: "Mod",
+            caller_role: Role::User,
+            online_users: vec![("Alice Smith", Role::User)],
+            maybe_topic: None,
+            commands: &commands,
+        };
+        for text in ["/msg", "/msg Alice Smith", "/msg \"Alice Smith\"  "] {
+            assert!(
+                matches!(commands.run(&context, text), Err(CommandError::Usage(_))),
+                "{text:?} was sent"
+            );
+        }
+    }
+}
@@ -6,6 +6,13 @@ use std::{
 
 use clap::{Args, Parser};
 use serde::{Deserialize, Serialize};
+use shared_types::protocol::{Capabilities, Capability};
+
+use crate::{
+    access::{ForwardedHeader, IpRange},
+    accounts::AccountMode,
+    username::CharacterClass,
+};
 
 // Helper defualt functions for serde and clap
 
@@ -25,6 +32,74 @@ const fn default_port() -> u16 {
     2004
 }
 
+const fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+const fn default_script_time_limit() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_file_storage_dir() -> PathBuf {
+    PathBuf::from("files")
+}
+
+const fn default_user_storage_quota() -> u64 {
+    104_857_600 // 100 MB
+}
+
+const fn default_total_storage_quota() -> u64 {
+    1_073_741_824 // 1 GB
+}
+
+const fn default_max_concurrent_uploads() -> usize {
+    3
+}
+
+const fn default_file_retention() -> Duration {
+    Duration::from_secs(7 * 24 * 60 * 60)
+}
+
+const fn default_username_min_length() -> usize {
+    1
+}
+
+const fn default_username_max_length() -> usize {
+    32
+}
+
+fn default_username_characters() -> Vec<CharacterClass> {
+    vec![CharacterClass::Letters, CharacterClass::Digits]
+}
+
+fn default_username_extra_characters() -> String {
+    String::from("_-.")
+}
+
+fn default_reserved_usernames() -> Vec<String> {
+    vec![String::from("server")]
+}
+
+fn default_accounts_file() -> PathBuf {
+    PathBuf::from("accounts.json")
+}
+
+const fn default_password_min_length() -> usize {
+    8
+}
+
+const fn default_session_lifetime() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+const fn default_resume_window() -> Duration {
+    Duration::from_secs(5 * 60)
+}
+
+fn default_ban_store_file() -> PathBuf {
+    PathBuf::from("bans.json")
+}
+
 //TODO: Add maximum file size server side option
 #[derive(Deserialize, Serialize, Args, Debug)]
 /// # Server Configuration
@@ -32,11 +107,71 @@ const fn default_port() -> u16 {
 ///
 /// You may set these options from the command-line or from a TOML file
 /// You may also use the config editor feature of the server to edit this file before starting the server
-pub(crate) struct ServerConfig {
-    #[arg(short = 'b', long = "banned")]
-    /// Optional list of users that will not be able to initiate a connection with the server
+pub struct ServerConfig {
+    #[arg(short = 'b', long = "banned", value_delimiter = ',')]
+    /// Optional list of IP addresses or CIDR ranges, such as 10.1.0.0/16 or 2001:db8::/32, that will not be able to initiate a connection with the server
+    #[serde(default)]
+    pub(crate) banned_users: Vec<IpRange>,
+
+    #[arg(long = "banned-usernames", value_delimiter = ',')]
+    /// Usernames, and names that look like them, that may not join whether signed in to an account or not
     #[serde(default)]
-    pub(crate) banned_users: Vec<IpAddr>,
+    pub(crate) banned_usernames: Vec<String>,
+
+    #[arg(long = "allowed-ranges", value_delimiter = ',')]
+    /// Only admit clients from these IP addresses or CIDR ranges, anyone may connect when left empty
+    #[serde(default)]
+    pub(crate) allowed_ranges: Vec<IpRange>,
+
+    #[arg(long = "trusted-proxies", value_delimiter = ',')]
+    /// Reverse proxies, as IP addresses or CIDR ranges, whose forwarded header says who the client really is
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<IpRange>,
+
+    #[arg(
+        long = "forwarded-header",
+        value_enum,
+        default_value = "x-forwarded-for"
+    )]
+    /// Header the trusted proxies set to the client's address: x-forwarded-for or forwarded. The other one is ignored since proxies pass it along from the client
+    #[serde(default)]
+    pub(crate) forwarded_header: ForwardedHeader,
+
+    #[arg(long = "max-users")]
+    /// Most users that may be connected at once, unlimited when not set
+    #[serde(default)]
+    pub(crate) max_users: Option<usize>,
+
+    #[arg(long = "max-connections-per-ip")]
+    /// Most users that may be connected at once from a single IP address, unlimited when not set
+    #[serde(default)]
+    pub(crate) max_connections_per_ip: Option<usize>,
+
+    #[arg(long = "message-filters", value_delimiter = ',')]
+    /// Names of the message filters to run, in the order to run them in. Every registered filter runs, in the order registered, when left empty
+    #[serde(default)]
+    pub(crate) message_filters: Vec<String>,
+
+    #[arg(long = "content-filter-file")]
+    /// TOML file of words and patterns to mask, reject or warn about in messages and file names, nothing is filtered when not set
+    #[serde(default)]
+    pub(crate) content_filter_file: Option<PathBuf>,
+
+    #[arg(long = "scripts-dir")]
+    /// Directory of Rhai scripts (*.rhai) that react to chat events and add slash commands, reloaded whenever they change. No scripts run when not set
+    #[serde(default)]
+    pub(crate) scripts_dir: Option<PathBuf>,
+
+    #[arg(long = "script-max-operations", default_value = "100000")]
+    /// Most operations a script may run for a single event or command before it is stopped
+    #[serde(default = "default_script_max_operations")]
+    pub(crate) script_max_operations: u64,
+
+    #[clap(value_parser = humantime::parse_duration, default_value = "1s")]
+    #[arg(long = "script-time-limit")]
+    /// Longest a script may run for a single event or command before it is stopped
+    #[serde(default = "default_script_time_limit", with = "humantime_serde")]
+    pub(crate) script_time_limit: Duration,
 
     #[arg(short = 'a', long = "auth")]
     /// Optional authentication which will be used to encrypt outgoing data
@@ -63,17 +198,129 @@ pub(crate) struct ServerConfig {
     // Set the port that the server will bind to
     #[serde(default = "default_port")]
     pub(crate) port: u16,
+
+    #[arg(long = "file-storage-dir", default_value = "files")]
+    /// Directory uploaded files are stored in, named by the SHA-256 hash of their contents
+    #[serde(default = "default_file_storage_dir")]
+    pub(crate) file_storage_dir: PathBuf,
+
+    #[arg(long = "user-storage-quota", default_value = "104857600")]
+    /// The most bytes of files a single user may have stored at once
+    #[serde(default = "default_user_storage_quota")]
+    pub(crate) user_storage_quota: u64,
+
+    #[arg(long = "total-storage-quota", default_value = "1073741824")]
+    /// The most bytes of files stored for everyone together
+    #[serde(default = "default_total_storage_quota")]
+    pub(crate) total_storage_quota: u64,
+
+    #[arg(long = "max-concurrent-uploads", default_value = "3")]
+    /// The most files a single user may be uploading at once
+    #[serde(default = "default_max_concurrent_uploads")]
+    pub(crate) max_concurrent_uploads: usize,
+
+    #[clap(value_parser = humantime::parse_duration, default_value = "7days")]
+    #[arg(long = "file-retention")]
+    /// How long uploaded files are kept before being deleted
+    #[serde(default = "default_file_retention", with = "humantime_serde")]
+    pub(crate) file_retention: Duration,
+
+    #[arg(long = "username-min-length", default_value = "1")]
+    /// Fewest characters a username may have
+    #[serde(default = "default_username_min_length")]
+    pub(crate) username_min_length: usize,
+
+    #[arg(long = "username-max-length", default_value = "32")]
+    /// Most characters a username may have
+    #[serde(default = "default_username_max_length")]
+    pub(crate) username_max_length: usize,
+
+    #[arg(
+        long = "username-characters",
+        value_delimiter = ',',
+        default_value = "letters,digits"
+    )]
+    /// Kinds of characters usernames may be made of: letters, digits, spaces and punctuation
+    #[serde(default = "default_username_characters")]
+    pub(crate) username_characters: Vec<CharacterClass>,
+
+    #[arg(long = "username-extra-characters", default_value = "_-.")]
+    /// Characters usernames may contain on top of the allowed kinds
+    #[serde(default = "default_username_extra_characters")]
+    pub(crate) username_extra_characters: String,
+
+    #[arg(
+        long = "reserved-usernames",
+        value_delimiter = ',',
+        default_value = "server"
+    )]
+    /// Names nobody may use, including names that only look like them
+    #[serde(default = "default_reserved_usernames")]
+    pub(crate) reserved_usernames: Vec<String>,
+
+    #[arg(long = "account-mode", value_enum, default_value = "off")]
+    /// Whether users sign in to accounts: off, allow-guests or required
+    #[serde(default)]
+    pub(crate) account_mode: AccountMode,
+
+    #[arg(long = "accounts-file", default_value = "accounts.json")]
+    /// File registered users and their hashed passwords are kept in
+    #[serde(default = "default_accounts_file")]
+    pub(crate) accounts_file: PathBuf,
+
+    #[arg(long = "password-min-length", default_value = "8")]
+    /// Fewest characters an account password may have
+    #[serde(default = "default_password_min_length")]
+    pub(crate) password_min_length: usize,
+
+    #[clap(value_parser = humantime::parse_duration, default_value = "1day")]
+    #[arg(long = "session-lifetime")]
+    /// How long the session token handed out with every handshake can be used to resume
+    #[serde(default = "default_session_lifetime", with = "humantime_serde")]
+    pub(crate) session_lifetime: Duration,
+
+    #[clap(value_parser = humantime::parse_duration, default_value = "5min")]
+    #[arg(long = "resume-window")]
+    /// How long messages are kept to be replayed to users resuming their session
+    #[serde(default = "default_resume_window", with = "humantime_serde")]
+    pub(crate) resume_window: Duration,
+
+    #[arg(long = "admins", value_delimiter = ',')]
+    /// Accounts that may kick, mute and ban users, only given to users signed in to the account
+    #[serde(default)]
+    pub(crate) admins: Vec<String>,
+
+    #[arg(long = "moderators", value_delimiter = ',')]
+    /// Accounts that may kick and mute users, only given to users signed in to the account
+    #[serde(default)]
+    pub(crate) moderators: Vec<String>,
+
+    #[arg(long = "ban-store-file", default_value = "bans.json")]
+    /// File the bans and mutes made with moderation commands are kept in
+    #[serde(default = "default_ban_store_file")]
+    pub(crate) ban_store_file: PathBuf,
+}
+
+impl ServerConfig {
+    /// What this server offers to clients. Rooms, history and encryption
+    /// aren't supported yet, the password only guards the handshake
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        self.allow_files
+            .then_some(Capability::Files)
+            .into_iter()
+            .collect()
+    }
 }
 
 #[derive(Parser, Debug)]
 #[command(about = "Official/Refrence implementation for <Project Name>", long_about = None)]
-pub(crate) struct ClapArgConfig {
+pub struct ClapArgConfig {
     #[command(flatten)]
-    pub(crate) server_config: ServerConfig,
+    pub server_config: ServerConfig,
 
     #[arg(short = 'c', long = "config", group = "config_file")]
     /// Optional path to a toml file used to configure the server, overrides any other command line args
-    pub(crate) server_config_file: Option<PathBuf>,
+    pub server_config_file: Option<PathBuf>,
 
     #[arg(
         short = 'e',
@@ -82,5 +329,5 @@ pub(crate) struct ClapArgConfig {
         default_value = "false"
     )]
     /// Option to enter special config editor mode, must have provided a server config file path
-    pub(crate) server_config_file_editor_flag: bool,
+    pub server_config_file_editor_flag: bool,
 }
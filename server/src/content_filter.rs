@@ -0,0 +1,269 @@
+use std::{collections::HashMap, path::Path, sync::Mutex, time::Duration};
+
+use log::*;
+use regex::Regex;
+use serde::Deserialize;
+use shared_types::messages::{MessageContents, ServerMessage};
+
+use crate::{
+    error::ContentFilterError,
+    filters::{FilterVerdict, InboundMessage, MessageFilter},
+    message_log::Audience,
+    moderation::Sanction,
+    username::confusable_skeleton,
+};
+
+const CONTENT_FILTER_NAME: &str = "content";
+/// Shown as who muted a user that ran out of strikes
+const CONTENT_FILTER_ISSUER: &str = "the content filter";
+const DEFAULT_REASON: &str = "that language isn't allowed here";
+
+/// What happens to a message a rule matches
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RuleAction {
+    /// Replace the matched text with asterisks
+    Mask,
+    /// Drop the message, telling the author why
+    Reject,
+    /// Let the message through but warn its author, counting a strike
+    /// towards an automatic mute
+    Warn,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleConfig {
+    /// Matched as whole words, ignoring case
+    #[serde(default)]
+    words: Vec<String>,
+    /// Regular expressions, start one with `(?i)` to ignore case
+    #[serde(default)]
+    patterns: Vec<String>,
+    action: RuleAction,
+    /// Told to the author of a rejected or warned message
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentFilterConfig {
+    /// Strikes from warnings that get a user muted, never when not set
+    strikes_to_mute: Option<u32>,
+    /// How long the automatic mute lasts, forever when not set
+    #[serde(default, with = "humantime_serde")]
+    mute_duration: Option<Duration>,
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+struct Rule {
+    regexes: Vec<Regex>,
+    action: RuleAction,
+    reason: String,
+}
+
+impl Rule {
+    fn new(rule_config: RuleConfig) -> Result<Self, ContentFilterError> {
+        let mut regexes = Vec::with_capacity(rule_config.patterns.len() + 1);
+        if !rule_config.words.is_empty() {
+            let words: Vec<String> = rule_config
+                .words
+                .iter()
+                .map(|word| word_pattern(word))
+                .collect();
+            let words_pattern = format!("(?i){}", words.join("|"));
+            regexes.push(
+                Regex::new(&words_pattern)
+                    .map_err(|error| ContentFilterError::Pattern(words_pattern, error))?,
+            );
+        }
+        for pattern in rule_config.patterns {
+            let regex = Regex::new(&pattern)
+                .map_err(|error| ContentFilterError::Pattern(pattern, error))?;
+            regexes.push(regex);
+        }
+        Ok(Self {
+            regexes,
+            action: rule_config.action,
+            reason: rule_config
+                .reason
+                .unwrap_or_else(|| String::from(DEFAULT_REASON)),
+        })
+    }
+
+    /// Every piece of `text` the rule matches, for the log
+    fn matches<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.regexes
+            .iter()
+            .flat_map(|regex| regex.find_iter(text))
+            .map(|found| found.as_str())
+            .collect()
+    }
+
+    fn mask(&self, text: &str) -> String {
+        self.regexes.iter().fold(text.to_string(), |text, regex| {
+            regex
+                .replace_all(&text, |captures: &regex::Captures| {
+                    "*".repeat(captures[0].chars().count())
+                })
+                .into_owned()
+        })
+    }
+}
+
+/// Escaped word, only held to word boundaries at ends that are part of a word
+/// so words like `c++` still match
+fn word_pattern(word: &str) -> String {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let start_boundary = match word.starts_with(is_word_char) {
+        true => r"\b",
+        false => "",
+    };
+    let end_boundary = match word.ends_with(is_word_char) {
+        true => r"\b",
+        false => "",
+    };
+    format!("{start_boundary}{}{end_boundary}", regex::escape(word))
+}
+
+/// # Content Filter
+/// Rules from a TOML file that mask, reject or warn about words and patterns
+/// in text messages, commands included, and in the names of uploaded files.
+/// Rules apply in the order they are written in, until one rejects
+///
+/// ```toml
+/// strikes_to_mute = 3
+/// mute_duration = "1h"
+///
+/// [[rules]]
+/// words = ["darn", "heck"]
+/// action = "mask"
+///
+/// [[rules]]
+/// patterns = ['(?i)buy\s+now']
+/// action = "reject"
+/// reason = "no advertising"
+///
+/// [[rules]]
+/// words = ["idiot"]
+/// action = "warn"
+/// reason = "be kind to each other"
+/// ```
+pub struct ContentFilter {
+    rules: Vec<Rule>,
+    maybe_strikes_to_mute: Option<u32>,
+    maybe_mute_duration: Option<Duration>,
+    /// Warnings by confusable skeleton of the username, cleared on mute
+    strikes: Mutex<HashMap<String, u32>>,
+}
+
+impl ContentFilter {
+    pub fn open(path: &Path) -> Result<Self, ContentFilterError> {
+        let content_filter_config: ContentFilterConfig =
+            toml::from_str(&std::fs::read_to_string(path)?)?;
+        let rules = content_filter_config
+            .rules
+            .into_iter()
+            .map(Rule::new)
+            .collect::<Result<Vec<Rule>, ContentFilterError>>()?;
+        info!(
+            "Loaded {} content filter rules from {}",
+            rules.len(),
+            path.display()
+        );
+        Ok(Self {
+            rules,
+            maybe_strikes_to_mute: content_filter_config.strikes_to_mute,
+            maybe_mute_duration: content_filter_config.mute_duration,
+            strikes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Count a strike against the author and warn them, muting them once
+    /// they run out of strikes
+    fn strike(&self, message: &mut InboundMessage, reason: &str) {
+        let author_skeleton = confusable_skeleton(&message.author);
+        let mut strikes = self
+            .strikes
+            .lock()
+            .expect("content filter strikes lock to not be poisoned");
+        let author_strikes = strikes.entry(author_skeleton.clone()).or_default();
+        *author_strikes += 1;
+        let warning = match self.maybe_strikes_to_mute {
+            Some(strikes_to_mute) => {
+                format!("Warning: {reason}, strike {author_strikes} of {strikes_to_mute}")
+            }
+            None => format!("Warning: {reason}"),
+        };
+        info!(
+            "Content filter warned {}, strike {author_strikes}: {reason}",
+            message.author
+        );
+        if self
+            .maybe_strikes_to_mute
+            .is_some_and(|strikes_to_mute| *author_strikes >= strikes_to_mute)
+        {
+            info!(
+                "Content filter muted {} after {author_strikes} strikes",
+                message.author
+            );
+            strikes.remove(&author_skeleton);
+            match Sanction::new(
+                CONTENT_FILTER_ISSUER,
+                self.maybe_mute_duration,
+                Some(String::from("too many warnings")),
+            ) {
+                Ok(mute) => message.mute_author(mute),
+                Err(error) => error!("Content filter could not mute {}: {error}", message.author),
+            }
+        }
+        let author = Audience::User(message.author.clone());
+        message.inject(
+            ServerMessage::server_announcement(MessageContents::Text(warning)),
+            author,
+        );
+    }
+}
+
+impl MessageFilter for ContentFilter {
+    fn name(&self) -> &str {
+        CONTENT_FILTER_NAME
+    }
+
+    fn filter(&self, message: &mut InboundMessage) -> FilterVerdict {
+        let author = message.author.clone();
+        let text = match &mut message.contents {
+            MessageContents::Text(text) | MessageContents::DirectMessage { text, .. } => text,
+            MessageContents::File(file_metadata) => &mut file_metadata.name,
+            _ => return FilterVerdict::Pass,
+        };
+        let mut maybe_warning_reason = None;
+        for rule in &self.rules {
+            let matches = rule.matches(text);
+            if matches.is_empty() {
+                continue;
+            }
+            match rule.action {
+                RuleAction::Mask => {
+                    info!("Content filter masked {matches:?} from {author}");
+                    *text = rule.mask(text);
+                }
+                RuleAction::Reject => {
+                    info!(
+                        "Content filter rejected a message from {author} for {matches:?}: {}",
+                        rule.reason
+                    );
+                    return FilterVerdict::Reject(rule.reason.clone());
+                }
+                RuleAction::Warn => {
+                    info!("Content filter matched {matches:?} from {author}");
+                    // A message only counts as one strike
+                    maybe_warning_reason.get_or_insert(rule.reason.clone());
+                }
+            }
+        }
+        if let Some(warning_reason) = maybe_warning_reason {
+            self.strike(message, &warning_reason);
+        }
+        FilterVerdict::Pass
+    }
+}
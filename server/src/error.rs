@@ -12,5 +12,313 @@ pub enum ServerError {
     TCPBind(#[source] std::io::Error),
 
     #[error("Could not build a websocket connection")]
-    CreateWebsocket(#[source] tokio_tungstenite::tungstenite::Error),
+    CreateWebsocket(#[source] Box<tokio_tungstenite::tungstenite::Error>),
+
+    #[error("Could not open the file storage directory")]
+    OpenFileStore(#[source] StorageError),
+
+    #[error("Could not open the accounts file")]
+    OpenAccountStore(#[source] AccountError),
+
+    #[error("Could not open the ban store")]
+    OpenBanStore(#[source] ModerationError),
+
+    #[error("Could not open the content filter file")]
+    OpenContentFilter(#[source] ContentFilterError),
+}
+
+/// Reasons a username was refused during the handshake, sent back to the client
+#[derive(Error, Debug)]
+pub enum UsernameError {
+    #[error("Did not provide a username")]
+    Missing,
+
+    #[error("Username is not valid UTF-8")]
+    NotUtf8,
+
+    #[error("Username must be at least {0} characters long")]
+    TooShort(usize),
+
+    #[error("Username must be at most {0} characters long")]
+    TooLong(usize),
+
+    #[error("Username may not contain '{}'", .0.escape_debug())]
+    DisallowedCharacter(char),
+
+    #[error("Username may not start or end with a space")]
+    SurroundingSpace,
+
+    #[error("{0} is reserved")]
+    Reserved(String),
+
+    #[error("{0} is already taken")]
+    Taken(String),
+}
+
+/// Reasons signing in to an account was refused during the handshake, sent back to the client
+#[derive(Error, Debug)]
+pub enum AccountError {
+    #[error("This server only lets in registered users, register or log in")]
+    Required,
+
+    #[error("Accounts are not enabled on this server")]
+    Disabled,
+
+    #[error("Unknown account action '{0}'")]
+    UnknownAction(String),
+
+    #[error("{0} is registered, log in to use it")]
+    Registered(String),
+
+    #[error("{0} is already registered")]
+    AlreadyRegistered(String),
+
+    #[error("Wrong username or password")]
+    BadCredentials,
+
+    #[error("Did not provide a password")]
+    MissingPassword,
+
+    #[error("Password must be at least {0} characters long")]
+    PasswordTooShort(usize),
+
+    #[error("Too many failed sign ins, try again in {0} seconds")]
+    TooManyAttempts(u64),
+
+    #[error(
+        "Another sign in from here or to this account is still being checked, try again shortly"
+    )]
+    SignInInProgress,
+
+    #[error("The server is busy checking other sign ins, try again shortly")]
+    Busy,
+
+    #[error("Could not hash the password")]
+    Hash(#[from] argon2::password_hash::Error),
+
+    #[error("Hashing the password stopped before it finished")]
+    HashTask(#[from] tokio::task::JoinError),
+
+    #[error("Could not access the accounts file")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not read or write the accounts file")]
+    Serialize(#[from] serde_json::Error),
+}
+
+/// Reasons a moderation command could not be carried out, reported back to whoever sent it
+#[derive(Error, Debug)]
+pub enum ModerationError {
+    #[error("Usage: {0} <user> ...")]
+    MissingTarget(String),
+
+    #[error("You are not allowed to do that")]
+    NotPermitted,
+
+    #[error("{0} can't be moderated by you")]
+    Outranked(String),
+
+    #[error("{0} is not online")]
+    NotOnline(String),
+
+    #[error("Usage: durations can't reach past the year 9999, leave it out to never expire")]
+    DurationTooLong,
+
+    #[error("{0} is an IP range, only bans can target IP ranges")]
+    IpRangeTarget(String),
+
+    #[error("{0} is not banned")]
+    NotBanned(String),
+
+    #[error("{0} is not muted")]
+    NotMuted(String),
+
+    #[error("Could not access the ban store")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not read or write the ban store")]
+    Serialize(#[from] serde_json::Error),
+}
+
+/// Reasons a client is not let in, sent back with a 403 during the handshake
+#[derive(Error, Debug)]
+pub enum AccessError {
+    #[error("Your address is not allowed to connect to this server")]
+    NotAllowed,
+
+    /// Holds how long the ban lasts and why, when known
+    #[error("You are banned{0}")]
+    Banned(String),
+}
+
+/// Reasons there is no room for another connection, sent back during the handshake
+#[derive(Error, Debug)]
+pub enum CapacityError {
+    #[error("Server is full, it allows at most {0} users at once")]
+    ServerFull(usize),
+
+    #[error("Too many connections from your address, the limit is {0}")]
+    TooManyConnections(usize),
+}
+
+#[derive(Error, Debug)]
+#[error("'{0}' is not an IP address or CIDR range")]
+pub struct InvalidIpRange(pub String);
+
+/// Reasons a slash command did not work, sent back to whoever sent it
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Unknown command {0}, send /help for the commands you can use")]
+    Unknown(String),
+
+    #[error("You are not allowed to use {0}")]
+    NotPermitted(String),
+
+    #[error("Usage: {0}")]
+    Usage(String),
+
+    #[error("{0} is not online")]
+    NotOnline(String),
+
+    #[error("You are muted{0}")]
+    Muted(String),
+
+    #[error("Your name belongs to your account and can't be changed")]
+    AccountName,
+
+    #[error("{0} is registered to an account")]
+    RegisteredName(String),
+
+    #[error("{0} is banned")]
+    BannedName(String),
+
+    #[error("{0} failed, try again later")]
+    Script(String),
+
+    #[error(transparent)]
+    Username(#[from] UsernameError),
+
+    #[error(transparent)]
+    Moderation(#[from] ModerationError),
+}
+
+/// Reasons the content filter file could not be loaded
+#[derive(Error, Debug)]
+pub enum ContentFilterError {
+    #[error("Could not read the content filter file")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not parse the content filter file")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("'{0}' is not a valid regular expression")]
+    Pattern(String, #[source] regex::Error),
+}
+
+/// Reasons a script could not be loaded or run, only logged
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("Could not read the script")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not parse the script: {0}")]
+    Parse(#[from] rhai::ParseError),
+
+    #[error("Script stopped with an error: {0}")]
+    Run(#[from] Box<rhai::EvalAltResult>),
+
+    #[error("Script is not loaded")]
+    NotLoaded,
+}
+
+/// Reasons a session token was not accepted, the client signs in as usual instead
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("Session token is malformed")]
+    Malformed,
+
+    #[error("Session token was not signed by this server")]
+    BadSignature,
+
+    #[error("Session token has expired")]
+    Expired,
+}
+
+/// Reasons a file chunk from a user was refused, reported back to the uploader
+#[derive(Error, Debug)]
+pub enum TransferError {
+    #[error("Could not read the file chunk")]
+    DecodeChunk(#[from] shared_types::transfer::FrameDecodeError),
+
+    #[error("File chunk did not hold the expected amount of data")]
+    ChunkSize,
+
+    #[error("Unknown file transfer, it may have expired and needs to be sent again")]
+    UnknownTransfer,
+
+    #[error("File chunk did not match the transfer it belongs to")]
+    MetadataMismatch,
+
+    #[error("Expected file chunk {expected} but received chunk {received}")]
+    MissingChunk { expected: u64, received: u64 },
+
+    #[error("Received file did not match its SHA-256 hash")]
+    HashMismatch,
+
+    #[error("File name not allowed: {0}")]
+    FilenameRejected(String),
+
+    #[error("Only {max} files may be uploaded at once")]
+    TooManyTransfers { max: usize },
+
+    #[error("Could not write the file chunk")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Reasons a file could not be stored or handed out
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Files are not allowed on this server")]
+    FilesNotAllowed,
+
+    #[error("File would go over your storage quota of {quota} bytes")]
+    UserQuota { quota: u64 },
+
+    #[error("The server has no room left for files")]
+    TotalQuota,
+
+    #[error("File not found, it may have expired")]
+    NotFound,
+
+    #[error("Could not access file storage")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not read or write the file storage index")]
+    Index(#[from] serde_json::Error),
+}
+
+/// Reasons a message from a client could not be read
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error(transparent)]
+    Decode(#[from] shared_types::wire_format::DecodeError),
+
+    #[error(transparent)]
+    DecodeFrame(#[from] shared_types::transfer::FrameDecodeError),
+}
+
+/// Reasons a download stopped partway through
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error("Could not read the file")]
+    Read(#[from] std::io::Error),
+
+    #[error("Could not encode the file contents")]
+    Encode(#[from] shared_types::wire_format::EncodeError),
+
+    #[error("Could not send the file contents")]
+    Send(#[from] tokio_tungstenite::tungstenite::Error),
 }
@@ -0,0 +1,236 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use shared_types::{messages::FileMetadata, transfer::mime_type};
+use tokio::fs::File;
+
+use crate::{config::ServerConfig, error::StorageError, transfer::PendingBytes};
+
+const INDEX_FILENAME: &str = "index.json";
+
+/// When and by whom a file was uploaded, the file is kept for as long as
+/// any of its uploads is within the retention period
+#[derive(Debug, Serialize, Deserialize)]
+struct Upload {
+    uploader: String,
+    uploaded_at: SystemTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredFile {
+    size: u64,
+    uploads: Vec<Upload>,
+}
+
+/// # File Store
+/// Uploaded files stored on disk under the SHA-256 hash of their contents,
+/// so the same file uploaded twice is only stored once. Which user uploaded
+/// what is kept in an index next to the files to enforce the quotas
+pub(crate) struct FileStore {
+    storage_dir: PathBuf,
+    allow_files: bool,
+    user_storage_quota: u64,
+    total_storage_quota: u64,
+    file_retention: Duration,
+    /// Every stored file by hash
+    files: HashMap<String, StoredFile>,
+}
+
+impl FileStore {
+    /// Create the storage directory if needed, load its index and delete
+    /// anything that expired while the server was not running
+    pub(crate) async fn open(config: &ServerConfig) -> Result<Self, StorageError> {
+        let storage_dir = config.file_storage_dir.clone();
+        tokio::fs::create_dir_all(&storage_dir).await?;
+
+        let files = match tokio::fs::read(storage_dir.join(INDEX_FILENAME)).await {
+            Ok(index) => serde_json::from_slice(&index)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => return Err(error.into()),
+        };
+        let mut file_store = Self {
+            storage_dir,
+            allow_files: config.allow_files,
+            user_storage_quota: config.user_storage_quota,
+            total_storage_quota: config.total_storage_quota,
+            file_retention: config.file_retention,
+            files,
+        };
+        file_store.remove_expired().await?;
+        info!(
+            "Opened file storage at {} with {} files",
+            file_store.storage_dir.display(),
+            file_store.files.len()
+        );
+        Ok(file_store)
+    }
+
+    /// Check a new upload of `size` bytes would fit before receiving it,
+    /// along with the `pending_bytes` of uploads that are still going on.
+    /// Uploads of files that are already stored don't count towards the
+    /// total quota, which can only be known once the hash arrives
+    pub(crate) fn check_quota(
+        &self,
+        uploader: &str,
+        size: u64,
+        maybe_sha256: Option<&str>,
+        pending_bytes: PendingBytes,
+    ) -> Result<(), StorageError> {
+        if !self.allow_files {
+            return Err(StorageError::FilesNotAllowed);
+        }
+
+        // The size comes from the client, refuse anything that couldn't fit
+        // even on its own before adding it to anything
+        if size > self.user_storage_quota {
+            return Err(StorageError::UserQuota {
+                quota: self.user_storage_quota,
+            });
+        }
+        let is_stored = maybe_sha256.is_some_and(|sha256| self.files.contains_key(sha256));
+        if !is_stored && size > self.total_storage_quota {
+            return Err(StorageError::TotalQuota);
+        }
+
+        let uploader_usage = self
+            .files
+            .values()
+            .filter(|file| {
+                file.uploads
+                    .iter()
+                    .any(|upload| upload.uploader == uploader)
+            })
+            .try_fold(0u64, |usage, file| usage.checked_add(file.size));
+        let uploader_total = uploader_usage
+            .and_then(|usage| usage.checked_add(pending_bytes.user))
+            .and_then(|usage| usage.checked_add(size));
+        if uploader_total.is_none_or(|usage| usage > self.user_storage_quota) {
+            return Err(StorageError::UserQuota {
+                quota: self.user_storage_quota,
+            });
+        }
+
+        let total_usage = self
+            .files
+            .values()
+            .try_fold(0u64, |usage, file| usage.checked_add(file.size));
+        let new_total = total_usage
+            .and_then(|usage| usage.checked_add(pending_bytes.total))
+            .and_then(|usage| usage.checked_add(size));
+        if !is_stored && new_total.is_none_or(|usage| usage > self.total_storage_quota) {
+            return Err(StorageError::TotalQuota);
+        }
+        Ok(())
+    }
+
+    /// Store a fully received file whose contents were already checked
+    /// against `sha256`, returning the metadata to announce it with. The
+    /// file is moved over from where the upload was written to, or removed
+    /// there if the same file is already stored
+    pub(crate) async fn store(
+        &mut self,
+        uploader: &str,
+        filename: &str,
+        sha256: &str,
+        partial_path: &Path,
+        size: u64,
+        pending_bytes: PendingBytes,
+    ) -> Result<FileMetadata, StorageError> {
+        self.remove_expired().await?;
+        self.check_quota(uploader, size, Some(sha256), pending_bytes)?;
+
+        let upload = Upload {
+            uploader: uploader.to_string(),
+            uploaded_at: SystemTime::now(),
+        };
+        match self.files.entry(sha256.to_string()) {
+            Entry::Occupied(mut occupied_entry) => {
+                debug!("{filename} is already stored as {sha256}");
+                // Uploading the same file again only restarts its retention
+                let uploads = &mut occupied_entry.get_mut().uploads;
+                uploads.retain(|existing_upload| existing_upload.uploader != uploader);
+                uploads.push(upload);
+                remove_file(partial_path).await?;
+            }
+            Entry::Vacant(vacant_entry) => {
+                let file_path = self.storage_dir.join(sha256);
+                tokio::fs::rename(partial_path, &file_path).await?;
+                vacant_entry.insert(StoredFile {
+                    size,
+                    uploads: vec![upload],
+                });
+            }
+        }
+        self.save_index().await?;
+
+        Ok(FileMetadata {
+            name: filename.to_string(),
+            size,
+            mime_type: mime_type(filename),
+            sha256: sha256.to_string(),
+        })
+    }
+
+    /// Open a stored file to read it, along with its size
+    pub(crate) async fn open_file(&self, sha256: &str) -> Result<(File, u64), StorageError> {
+        // Only hashes from the index are turned into paths so a request
+        // can't point anywhere outside of the storage directory
+        let Some(stored_file) = self.files.get(sha256) else {
+            return Err(StorageError::NotFound);
+        };
+        let file = File::open(self.storage_dir.join(sha256)).await?;
+        Ok((file, stored_file.size))
+    }
+
+    /// Forget uploads older than the retention period, deleting files
+    /// once nobody's upload of them is left
+    async fn remove_expired(&mut self) -> Result<(), StorageError> {
+        let file_retention = self.file_retention;
+        let mut expired_files = vec![];
+        for (sha256, file) in self.files.iter_mut() {
+            file.uploads.retain(|upload| {
+                upload
+                    .uploaded_at
+                    .elapsed()
+                    .is_ok_and(|upload_age| upload_age < file_retention)
+            });
+            if file.uploads.is_empty() {
+                expired_files.push(sha256.clone());
+            }
+        }
+        if expired_files.is_empty() {
+            return Ok(());
+        }
+
+        for sha256 in expired_files {
+            self.files.remove(&sha256);
+            remove_file(&self.storage_dir.join(&sha256)).await?;
+            info!("Deleted expired file {sha256}");
+        }
+        self.save_index().await
+    }
+
+    /// Write the index to a temporary file first and then rename it over
+    /// the real one so a crash mid-write can't lose track of every file
+    async fn save_index(&self) -> Result<(), StorageError> {
+        let index_path = self.storage_dir.join(INDEX_FILENAME);
+        let temp_index_path = index_path.with_extension("json.tmp");
+        tokio::fs::write(&temp_index_path, serde_json::to_vec(&self.files)?).await?;
+        tokio::fs::rename(&temp_index_path, &index_path).await?;
+        Ok(())
+    }
+}
+
+/// Remove a stored file, it being gone already is fine
+async fn remove_file(file_path: &Path) -> Result<(), StorageError> {
+    match tokio::fs::remove_file(file_path).await {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+
+use log::*;
+use shared_types::messages::{MessageContents, ServerMessage};
+
+use crate::{message_log::Audience, moderation::Sanction};
+
+/// A message on its way in, before the server acts on it. Text messages
+/// are seen before commands are told apart from chat, so filters see both
+pub struct InboundMessage {
+    pub author: String,
+    /// Of the client itself rather than any trusted proxy in front of it
+    pub ip_addr: IpAddr,
+    /// Text of a text message, recipient and text of a direct message sent
+    /// as its own request, or the file of an upload as its first chunk
+    /// arrives, with an empty hash since none of it is stored yet. Filters
+    /// may change it in place for the filters after them and the server
+    pub contents: MessageContents,
+    /// Sent after the message itself, or straight away if it is rejected
+    injected_messages: Vec<(ServerMessage, Audience)>,
+    maybe_author_mute: Option<Sanction>,
+}
+
+impl InboundMessage {
+    pub(crate) fn new(author: &str, ip_addr: IpAddr, contents: MessageContents) -> Self {
+        Self {
+            author: author.to_string(),
+            ip_addr,
+            contents,
+            injected_messages: Vec::new(),
+            maybe_author_mute: None,
+        }
+    }
+
+    /// Have the server send another message along with this one
+    pub fn inject(&mut self, message: ServerMessage, audience: Audience) {
+        self.injected_messages.push((message, audience));
+    }
+
+    /// Have the server mute the author, whether the message goes out or not
+    pub fn mute_author(&mut self, mute: Sanction) {
+        self.maybe_author_mute = Some(mute);
+    }
+
+    pub(crate) fn take_author_mute(&mut self) -> Option<Sanction> {
+        self.maybe_author_mute.take()
+    }
+
+    pub(crate) fn take_injected_messages(&mut self) -> Vec<(ServerMessage, Audience)> {
+        std::mem::take(&mut self.injected_messages)
+    }
+}
+
+/// Whether a message goes on after a filter had its look at it
+#[derive(Debug)]
+pub enum FilterVerdict {
+    /// On to the next filter, changed or not
+    Pass,
+    /// Dropped, the author is told the reason
+    Reject(String),
+}
+
+/// # Message Filter
+/// Hook into every inbound message to log, filter, rewrite or answer it.
+/// Filters run one after another in the order the `message_filters` config
+/// option gives, or the order they were registered in without it
+pub trait MessageFilter: Send + Sync {
+    /// Used to order and pick filters in the config
+    fn name(&self) -> &str;
+
+    fn filter(&self, message: &mut InboundMessage) -> FilterVerdict;
+}
+
+/// Every message filter in the order they run in
+#[derive(Default)]
+pub(crate) struct MessageFilters {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl MessageFilters {
+    /// Keep the filters named in `order`, in that order, or all of them
+    /// when `order` is empty
+    pub(crate) fn new(mut filters: Vec<Box<dyn MessageFilter>>, order: &[String]) -> Self {
+        if order.is_empty() {
+            return Self { filters };
+        }
+        let mut ordered_filters = Vec::with_capacity(order.len());
+        for name in order {
+            match filters.iter().position(|filter| filter.name() == name) {
+                Some(index) => ordered_filters.push(filters.remove(index)),
+                None => warn!("No message filter named {name} is registered"),
+            }
+        }
+        for left_out_filter in filters {
+            info!(
+                "Message filter {} is not in the configured order and won't run",
+                left_out_filter.name()
+            );
+        }
+        Self {
+            filters: ordered_filters,
+        }
+    }
+
+    /// Run the message through every filter until one rejects it
+    pub(crate) fn run(&self, message: &mut InboundMessage) -> FilterVerdict {
+        for filter in &self.filters {
+            if let FilterVerdict::Reject(reason) = filter.filter(message) {
+                info!(
+                    "Message filter {} rejected a message from {} at {}: {reason}",
+                    filter.name(),
+                    message.author,
+                    message.ip_addr
+                );
+                return FilterVerdict::Reject(reason);
+            }
+        }
+        FilterVerdict::Pass
+    }
+}
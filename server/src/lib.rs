@@ -0,0 +1,29 @@
+//! # Server
+//! Reference implementation of the chat server. Deployments build a
+//! [`Server`] from a [`ServerConfig`] and add their own [`Command`]s and
+//! [`MessageFilter`]s on top of the built-in ones before running it
+
+mod access;
+mod accounts;
+mod commands;
+mod config;
+mod content_filter;
+mod error;
+mod file_store;
+mod filters;
+mod message_log;
+mod moderation;
+mod scripts;
+mod server;
+mod session;
+mod transfer;
+mod username;
+
+pub use commands::{Command, CommandAction, CommandContext};
+pub use config::{ClapArgConfig, ServerConfig};
+pub use content_filter::ContentFilter;
+pub use error::{CommandError, ContentFilterError, ModerationError, ServerError, UsernameError};
+pub use filters::{FilterVerdict, InboundMessage, MessageFilter};
+pub use message_log::Audience;
+pub use moderation::{ModerationCommand, Sanction};
+pub use server::{Server, ServerBuilder};
@@ -1,12 +1,6 @@
-mod config;
-mod error;
-mod server;
-
 use clap::Parser;
-use config::ClapArgConfig;
-use error::ServerError;
 use log::*;
-use server::Server;
+use server::{ClapArgConfig, Server, ServerError};
 
 #[tokio::main]
 async fn main() -> Result<(), ServerError> {
@@ -47,7 +41,8 @@ async fn main() -> Result<(), ServerError> {
         //TODO: Create config editor functionality
         todo!()
     } else {
-        let server = Server::new(server_config);
+        let server_builder = Server::builder(server_config).content_filter_from_config()?;
+        let server = server_builder.build();
         server.run_server().await
     }
 }
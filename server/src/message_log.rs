@@ -0,0 +1,117 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use shared_types::messages::ServerMessage;
+
+use crate::config::ServerConfig;
+
+/// Who a logged message was sent to
+#[derive(Debug, Clone)]
+pub enum Audience {
+    Everyone,
+    /// Everyone other than the author, who already has the message
+    EveryoneBut(String),
+    User(String),
+}
+
+impl Audience {
+    pub(crate) fn includes(&self, username: &str) -> bool {
+        match self {
+            Audience::Everyone => true,
+            Audience::EveryoneBut(author) => author != username,
+            Audience::User(recipient) => recipient == username,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LoggedMessage {
+    sent_at: Instant,
+    audience: Audience,
+    message: ServerMessage,
+}
+
+/// # Message Log
+/// Chat messages numbered in the order they were sent. Each one is kept for
+/// the resume window so a client resuming its session can be sent what it
+/// missed while it was gone
+pub(crate) struct MessageLog {
+    resume_window: Duration,
+    /// Zero until the first message is sent
+    last_sequence: u64,
+    messages: VecDeque<LoggedMessage>,
+}
+
+impl MessageLog {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        Self {
+            resume_window: config.resume_window,
+            last_sequence: 0,
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Number the message and keep it around for replaying, returns the
+    /// numbered message to send
+    pub(crate) fn record(
+        &mut self,
+        mut message: ServerMessage,
+        audience: Audience,
+    ) -> ServerMessage {
+        self.remove_expired();
+        self.last_sequence += 1;
+        message.sequence = Some(self.last_sequence);
+        self.messages.push_back(LoggedMessage {
+            sent_at: Instant::now(),
+            audience,
+            message: message.clone(),
+        });
+        message
+    }
+
+    pub(crate) fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Whether every message sent after `last_seen_sequence` is still kept
+    pub(crate) fn can_replay_since(&self, last_seen_sequence: u64) -> bool {
+        let first_kept_sequence = self
+            .messages
+            .front()
+            .and_then(|logged_message| logged_message.message.sequence)
+            .unwrap_or(self.last_sequence.saturating_add(1));
+        last_seen_sequence.saturating_add(1) >= first_kept_sequence
+    }
+
+    /// Messages `username` was sent after `last_seen_sequence` that are still kept
+    pub(crate) fn missed_messages(
+        &mut self,
+        username: &str,
+        last_seen_sequence: u64,
+    ) -> Vec<ServerMessage> {
+        self.remove_expired();
+        self.messages
+            .iter()
+            .filter(|logged_message| {
+                logged_message
+                    .message
+                    .sequence
+                    .is_some_and(|sequence| sequence > last_seen_sequence)
+                    && logged_message.audience.includes(username)
+            })
+            .map(|logged_message| logged_message.message.clone())
+            .collect()
+    }
+
+    fn remove_expired(&mut self) {
+        while self
+            .messages
+            .front()
+            .is_some_and(|logged_message| logged_message.sent_at.elapsed() > self.resume_window)
+        {
+            self.messages.pop_front();
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::ErrorKind,
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access::{AccessRules, IpRange},
+    config::ServerConfig,
+    error::ModerationError,
+    username::confusable_skeleton,
+};
+
+pub(crate) use shared_types::messages::{KICK_COMMAND, Role};
+
+pub(crate) const BAN_COMMAND: &str = "/ban";
+pub(crate) const UNBAN_COMMAND: &str = "/unban";
+pub(crate) const MUTE_COMMAND: &str = "/mute";
+pub(crate) const UNMUTE_COMMAND: &str = "/unmute";
+
+/// Latest expiry a ban or mute can have, the last second of the year 9999,
+/// since later times can't be written down as RFC 3339
+const LATEST_EXPIRY: Duration = Duration::from_secs(253_402_300_799);
+
+/// A command from a moderator or admin, sent as a text message
+#[derive(Debug)]
+pub enum ModerationCommand {
+    /// `/kick <user> [reason]`
+    Kick {
+        target: String,
+        maybe_reason: Option<String>,
+    },
+    /// `/ban <user|range> [duration] [reason]`, forever without a duration.
+    /// Targets that read as an IP address or CIDR range ban those addresses
+    Ban {
+        target: String,
+        maybe_duration: Option<Duration>,
+        maybe_reason: Option<String>,
+    },
+    /// `/unban <user|range>`
+    Unban { target: String },
+    /// `/mute <user> [duration] [reason]`, forever without a duration
+    Mute {
+        target: String,
+        maybe_duration: Option<Duration>,
+        maybe_reason: Option<String>,
+    },
+    /// `/unmute <user>`
+    Unmute { target: String },
+}
+
+impl ModerationCommand {
+    /// Arguments of one of the moderation commands, dispatched by the command
+    /// registry, with the target already split off the rest of them
+    pub(crate) fn parse(command: &str, target: &str, rest: &str) -> Result<Self, ModerationError> {
+        if target.is_empty() {
+            return Err(ModerationError::MissingTarget(command.to_string()));
+        }
+        let target = target.to_string();
+        // Bans and mutes may start with a duration, anything after it is the reason
+        let (first_word, after_first_word) = rest
+            .trim_start()
+            .split_once(' ')
+            .unwrap_or((rest.trim(), ""));
+        let (maybe_duration, reason) = match humantime::parse_duration(first_word) {
+            Ok(duration) => (Some(duration), after_first_word),
+            Err(_) => (None, rest),
+        };
+
+        Ok(match command {
+            KICK_COMMAND => ModerationCommand::Kick {
+                target,
+                maybe_reason: non_empty(rest),
+            },
+            BAN_COMMAND => ModerationCommand::Ban {
+                target,
+                maybe_duration,
+                maybe_reason: non_empty(reason),
+            },
+            UNBAN_COMMAND => ModerationCommand::Unban { target },
+            MUTE_COMMAND => ModerationCommand::Mute {
+                target,
+                maybe_duration,
+                maybe_reason: non_empty(reason),
+            },
+            _ => ModerationCommand::Unmute { target },
+        })
+    }
+
+    pub(crate) fn required_role(&self) -> Role {
+        match self {
+            ModerationCommand::Kick { .. }
+            | ModerationCommand::Mute { .. }
+            | ModerationCommand::Unmute { .. } => Role::Moderator,
+            ModerationCommand::Ban { .. } | ModerationCommand::Unban { .. } => Role::Admin,
+        }
+    }
+
+    pub(crate) fn target(&self) -> &str {
+        match self {
+            ModerationCommand::Kick { target, .. }
+            | ModerationCommand::Ban { target, .. }
+            | ModerationCommand::Unban { target }
+            | ModerationCommand::Mute { target, .. }
+            | ModerationCommand::Unmute { target } => target,
+        }
+    }
+}
+
+fn non_empty(text: &str) -> Option<String> {
+    Some(text.trim())
+        .filter(|text| !text.is_empty())
+        .map(String::from)
+}
+
+/// Who put a ban or mute in place, why and until when
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sanction {
+    pub(crate) issued_by: String,
+    pub(crate) issued_at: SystemTime,
+    /// Lasts forever without one
+    pub(crate) maybe_expires_at: Option<SystemTime>,
+    pub(crate) maybe_reason: Option<String>,
+}
+
+impl Sanction {
+    pub fn new(
+        issued_by: impl ToString,
+        maybe_duration: Option<Duration>,
+        maybe_reason: Option<String>,
+    ) -> Result<Self, ModerationError> {
+        let issued_at = SystemTime::now();
+        let maybe_expires_at = maybe_duration
+            .map(|duration| {
+                issued_at
+                    .checked_add(duration)
+                    .filter(|expires_at| *expires_at <= UNIX_EPOCH + LATEST_EXPIRY)
+                    .ok_or(ModerationError::DurationTooLong)
+            })
+            .transpose()?;
+        Ok(Self {
+            issued_by: issued_by.to_string(),
+            issued_at,
+            maybe_expires_at,
+            maybe_reason,
+        })
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.maybe_expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+}
+
+/// Formats as ` until <time>: <reason>`, leaving out whatever isn't set
+impl Display for Sanction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(expires_at) = self.maybe_expires_at {
+            write!(
+                f,
+                " until {}",
+                humantime::format_rfc3339_seconds(expires_at)
+            )?;
+        }
+        if let Some(reason) = &self.maybe_reason {
+            write!(f, ": {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A ban of a username, of an IP range, or of a user along with the address
+/// they were online from at the time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Ban {
+    /// Spelled the way it was when banned
+    #[serde(alias = "username")]
+    maybe_username: Option<String>,
+    #[serde(alias = "maybe_ip_addr")]
+    maybe_ip_range: Option<IpRange>,
+    pub(crate) sanction: Sanction,
+}
+
+impl Ban {
+    pub(crate) fn username(
+        username: &str,
+        maybe_ip_addr: Option<IpAddr>,
+        sanction: Sanction,
+    ) -> Self {
+        Self {
+            maybe_username: Some(username.to_string()),
+            maybe_ip_range: maybe_ip_addr.map(IpRange::from),
+            sanction,
+        }
+    }
+
+    pub(crate) fn ip_range(ip_range: IpRange, sanction: Sanction) -> Self {
+        Self {
+            maybe_username: None,
+            maybe_ip_range: Some(ip_range),
+            sanction,
+        }
+    }
+
+    /// Key in the ban store, see [`ban_key`]
+    fn key(&self) -> String {
+        match (&self.maybe_username, self.maybe_ip_range) {
+            (Some(username), _) => confusable_skeleton(username),
+            (None, Some(ip_range)) => ip_range.to_string(),
+            (None, None) => String::new(),
+        }
+    }
+
+    pub(crate) fn applies_to_ip_addr(&self, ip_addr: IpAddr) -> bool {
+        self.maybe_ip_range
+            .is_some_and(|ip_range| ip_range.contains(ip_addr))
+    }
+
+    pub(crate) fn applies_to_username(&self, username_skeleton: &str) -> bool {
+        self.maybe_username
+            .as_ref()
+            .is_some_and(|username| confusable_skeleton(username) == username_skeleton)
+    }
+}
+
+/// Bans are stored by the range they ban when the target reads as one, and
+/// by the confusable skeleton of the username otherwise
+fn ban_key(target: &str) -> String {
+    match target.parse::<IpRange>() {
+        Ok(ip_range) => ip_range.to_string(),
+        Err(_) => confusable_skeleton(target),
+    }
+}
+
+/// Contents of the ban store file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Sanctions {
+    /// By [`ban_key`]
+    bans: HashMap<String, Ban>,
+    /// By the confusable skeleton of the username
+    mutes: HashMap<String, Sanction>,
+}
+
+/// # Moderation
+/// Who the admins and moderators are, and the bans and mutes they put in
+/// place at runtime. Bans and mutes are kept in the ban store file so they
+/// outlast a restart
+pub(crate) struct Moderation {
+    ban_store_file: PathBuf,
+    /// Without the ban store bans, which are added to each copy handed out
+    access_rules: AccessRules,
+    /// Skeletons of the usernames of everyone with a role
+    roles: HashMap<String, Role>,
+    sanctions: Sanctions,
+}
+
+impl Moderation {
+    pub(crate) async fn open(config: &ServerConfig) -> Result<Self, ModerationError> {
+        let sanctions = match tokio::fs::read(&config.ban_store_file).await {
+            Ok(sanctions) => serde_json::from_slice(&sanctions)?,
+            Err(error) if error.kind() == ErrorKind::NotFound => Sanctions::default(),
+            Err(error) => return Err(error.into()),
+        };
+        let moderators = config
+            .moderators
+            .iter()
+            .map(|moderator| (confusable_skeleton(moderator), Role::Moderator));
+        let admins = config
+            .admins
+            .iter()
+            .map(|admin| (confusable_skeleton(admin), Role::Admin));
+        let moderation = Self {
+            ban_store_file: config.ban_store_file.clone(),
+            access_rules: AccessRules::new(config),
+            roles: moderators.chain(admins).collect(),
+            sanctions,
+        };
+        info!(
+            "Opened ban store {} with {} bans and {} mutes",
+            moderation.ban_store_file.display(),
+            moderation.sanctions.bans.len(),
+            moderation.sanctions.mutes.len()
+        );
+        Ok(moderation)
+    }
+
+    /// Role the username is configured with, whether or not whoever uses
+    /// it is signed in to the account
+    pub(crate) fn configured_role(&self, username: &str) -> Role {
+        self.roles
+            .get(&confusable_skeleton(username))
+            .copied()
+            .unwrap_or(Role::User)
+    }
+
+    /// Roles are only given to accounts, anyone could use the name of a guest
+    pub(crate) fn role(&self, username: &str, is_account: bool) -> Role {
+        if is_account {
+            self.configured_role(username)
+        } else {
+            Role::User
+        }
+    }
+
+    /// Copy of everything deciding who may connect, for a handshake to check
+    pub(crate) fn access_rules(&self) -> AccessRules {
+        let bans = self
+            .sanctions
+            .bans
+            .values()
+            .filter(|ban| !ban.sanction.is_expired())
+            .cloned()
+            .collect();
+        self.access_rules.with_bans(bans)
+    }
+
+    pub(crate) fn find_mute(&self, username: &str) -> Option<&Sanction> {
+        self.sanctions
+            .mutes
+            .get(&confusable_skeleton(username))
+            .filter(|sanction| !sanction.is_expired())
+    }
+
+    pub(crate) async fn ban(&mut self, ban: Ban) -> Result<(), ModerationError> {
+        self.sanctions.bans.insert(ban.key(), ban);
+        self.save().await
+    }
+
+    /// `false` if the user or range wasn't banned
+    pub(crate) async fn unban(&mut self, target: &str) -> Result<bool, ModerationError> {
+        let was_banned = self.sanctions.bans.remove(&ban_key(target)).is_some();
+        self.save().await?;
+        Ok(was_banned)
+    }
+
+    pub(crate) async fn mute(
+        &mut self,
+        username: &str,
+        sanction: Sanction,
+    ) -> Result<(), ModerationError> {
+        self.sanctions
+            .mutes
+            .insert(confusable_skeleton(username), sanction);
+        self.save().await
+    }
+
+    /// `false` if the user wasn't muted
+    pub(crate) async fn unmute(&mut self, username: &str) -> Result<bool, ModerationError> {
+        let was_muted = self
+            .sanctions
+            .mutes
+            .remove(&confusable_skeleton(username))
+            .is_some();
+        self.save().await?;
+        Ok(was_muted)
+    }
+
+    /// Expired bans and mutes are dropped whenever the store is written
+    async fn save(&mut self) -> Result<(), ModerationError> {
+        self.sanctions
+            .bans
+            .retain(|_, ban| !ban.sanction.is_expired());
+        self.sanctions
+            .mutes
+            .retain(|_, sanction| !sanction.is_expired());
+
+        let sanctions = serde_json::to_vec_pretty(&self.sanctions)?;
+        let temporary_file = self.ban_store_file.with_extension("tmp");
+        tokio::fs::write(&temporary_file, sanctions).await?;
+        tokio::fs::rename(&temporary_file, &self.ban_store_file).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,598 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::*;
+use rhai::{AST, CallFnOptions, Dynamic, Engine, Scope, module_resolvers::DummyModuleResolver};
+use shared_types::messages::{MessageContents, ServerMessage};
+use tokio::sync::RwLock;
+
+use crate::{
+    commands::{Command, CommandAction, CommandContext, CommandRegistry},
+    config::ServerConfig,
+    error::{CommandError, ScriptError},
+    message_log::Audience,
+};
+
+const SCRIPT_EXTENSION: &str = "rhai";
+/// How often the scripts directory is checked for added, changed or removed scripts
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+const ON_JOIN_FUNCTION: &str = "on_join";
+const ON_LEAVE_FUNCTION: &str = "on_leave";
+const ON_MESSAGE_FUNCTION: &str = "on_message";
+
+// Bounds on what a script may build up, on top of the operation limit from the config
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FUNCTION_EXPR_DEPTH: usize = 32;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 10_000;
+const MAX_MAP_SIZE: usize = 10_000;
+/// Operations between looks at the clock, checking on every one would slow scripts down
+const TIME_LIMIT_CHECK_INTERVAL: u64 = 1_000;
+
+/// Messages scripts asked to send, along with who they are for
+type ScriptMessages = Vec<(ServerMessage, Audience)>;
+
+/// Where scripts put what they send during a call
+type Outbox = Arc<Mutex<ScriptMessages>>;
+
+/// Text message from the server, scripts speak as the server
+fn script_message(text: &str) -> ServerMessage {
+    ServerMessage::server_announcement(MessageContents::Text(text.to_string()))
+}
+
+/// Something that happened in the chat for scripts to react to
+#[derive(Debug)]
+pub(crate) enum ScriptEvent {
+    Join {
+        username: String,
+    },
+    Leave {
+        username: String,
+    },
+    /// Chat text, commands go to the scripts that registered them instead
+    Message {
+        username: String,
+        text: String,
+    },
+}
+
+impl ScriptEvent {
+    fn function_name(&self) -> &'static str {
+        match self {
+            ScriptEvent::Join { .. } => ON_JOIN_FUNCTION,
+            ScriptEvent::Leave { .. } => ON_LEAVE_FUNCTION,
+            ScriptEvent::Message { .. } => ON_MESSAGE_FUNCTION,
+        }
+    }
+
+    fn args(self) -> Vec<String> {
+        match self {
+            ScriptEvent::Join { username } | ScriptEvent::Leave { username } => vec![username],
+            ScriptEvent::Message { username, text } => vec![username, text],
+        }
+    }
+}
+
+/// A slash command a script registered while loading
+#[derive(Debug)]
+struct ScriptCommandSpec {
+    name: String,
+    usage: String,
+    description: String,
+    /// Script function called with the caller's name and the arguments
+    function_name: String,
+}
+
+struct LoadedScript {
+    modified: SystemTime,
+    /// None when the script failed to load, until its file changes again
+    maybe_ast: Option<AST>,
+    command_names: Vec<String>,
+}
+
+/// # Scripts
+/// Rhai scripts from the scripts directory that react to users joining,
+/// leaving and chatting, and add slash commands. Scripts can't reach files
+/// or the network and are stopped once they run past the operation or time
+/// limit. Each script is reloaded when its file changes, or unloaded when removed
+///
+/// A script may define `on_join(user)`, `on_leave(user)` and
+/// `on_message(user, text)`, and call:
+/// - `say(text)` to send text from the server to everyone
+/// - `tell(user, text)` to send text from the server to a single user
+/// - `register_command(name, [usage,] description, function)` while it
+///   loads, to call `function(user, args)` for the command. Text it returns
+///   is the reply to whoever ran the command
+pub(crate) struct Scripts {
+    scripts_dir: PathBuf,
+    engine: Engine,
+    outbox: Outbox,
+    registered_commands: Arc<Mutex<Vec<ScriptCommandSpec>>>,
+    time_limit: Duration,
+    /// When the call running now has to be done by, scripts are stopped after it
+    call_deadline: Arc<Mutex<Instant>>,
+    /// By path so events reach scripts in the order of their file names.
+    /// Locked for every call into a script, so each call has the outbox to
+    /// itself
+    loaded_scripts: Mutex<BTreeMap<PathBuf, LoadedScript>>,
+}
+
+impl Scripts {
+    pub(crate) fn new(config: &ServerConfig, scripts_dir: PathBuf) -> Self {
+        let mut engine = Engine::new();
+        // Scripts only get at what the server hands them
+        engine.set_module_resolver(DummyModuleResolver::new());
+        engine.disable_symbol("eval");
+        engine.set_max_operations(config.script_max_operations);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FUNCTION_EXPR_DEPTH);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_ARRAY_SIZE);
+        engine.set_max_map_size(MAX_MAP_SIZE);
+        let call_deadline = Arc::new(Mutex::new(Instant::now()));
+        let progress_call_deadline = call_deadline.clone();
+        engine.on_progress(move |operations| {
+            let is_past_deadline = operations % TIME_LIMIT_CHECK_INTERVAL == 0
+                && Instant::now()
+                    > *progress_call_deadline
+                        .lock()
+                        .expect("script call deadline lock to not be poisoned");
+            is_past_deadline.then(|| Dynamic::from("ran past the time limit"))
+        });
+        engine.on_print(|text| info!("Script: {text}"));
+        engine.on_debug(|text, maybe_source, position| {
+            debug!(
+                "Script {} at {position}: {text}",
+                maybe_source.unwrap_or_default()
+            )
+        });
+
+        let outbox = Outbox::default();
+        let say_outbox = outbox.clone();
+        engine.register_fn("say", move |text: &str| {
+            say_outbox
+                .lock()
+                .expect("script outbox lock to not be poisoned")
+                .push((script_message(text), Audience::Everyone));
+        });
+        let tell_outbox = outbox.clone();
+        engine.register_fn("tell", move |username: &str, text: &str| {
+            tell_outbox
+                .lock()
+                .expect("script outbox lock to not be poisoned")
+                .push((script_message(text), Audience::User(username.to_string())));
+        });
+
+        let registered_commands: Arc<Mutex<Vec<ScriptCommandSpec>>> = Arc::default();
+        let register_commands = registered_commands.clone();
+        let register_command = move |name: &str, usage: &str, description: &str, function: &str| {
+            let name = match name.starts_with('/') {
+                true => name.to_string(),
+                false => format!("/{name}"),
+            };
+            register_commands
+                .lock()
+                .expect("script commands lock to not be poisoned")
+                .push(ScriptCommandSpec {
+                    name,
+                    usage: usage.to_string(),
+                    description: description.to_string(),
+                    function_name: function.to_string(),
+                });
+        };
+        let register_command_without_usage = register_command.clone();
+        engine.register_fn("register_command", register_command);
+        engine.register_fn(
+            "register_command",
+            move |name: &str, description: &str, function: &str| {
+                register_command_without_usage(name, "", description, function)
+            },
+        );
+
+        Self {
+            scripts_dir,
+            engine,
+            outbox,
+            registered_commands,
+            time_limit: config.script_time_limit,
+            call_deadline,
+            loaded_scripts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Call the event's function in every script that defines it on the
+    /// blocking thread pool, returns what they asked to send
+    pub(crate) async fn run_event(self: &Arc<Self>, event: ScriptEvent) -> ScriptMessages {
+        let scripts = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let function_name = event.function_name();
+            scripts.call_event(function_name, event.args())
+        })
+        .await
+        .unwrap_or_else(|error| {
+            error!("Script event stopped before it finished: {error}");
+            Vec::new()
+        })
+    }
+
+    fn call_event(&self, function_name: &str, args: Vec<String>) -> ScriptMessages {
+        let loaded_scripts = self
+            .loaded_scripts
+            .lock()
+            .expect("loaded scripts lock to not be poisoned");
+        for (path, loaded_script) in loaded_scripts.iter() {
+            let Some(ast) = &loaded_script.maybe_ast else {
+                continue;
+            };
+            if !ast.iter_functions().any(|function| {
+                function.name == function_name && function.params.len() == args.len()
+            }) {
+                continue;
+            }
+            let args: Vec<Dynamic> = args.iter().cloned().map(Dynamic::from).collect();
+            if let Err(error) = self.call(ast, function_name, args) {
+                warn!(
+                    "{function_name} in script {} failed: {error}",
+                    path.display()
+                );
+            }
+        }
+        self.take_outbox()
+    }
+
+    /// Run a command's function, returns the reply along with what the
+    /// script asked to send. Blocks until every script call before it is
+    /// done, so it only runs on the blocking thread pool
+    fn run_command(
+        &self,
+        script_path: &Path,
+        function_name: &str,
+        caller: &str,
+        command_args: &str,
+    ) -> Result<(Option<String>, ScriptMessages), ScriptError> {
+        let loaded_scripts = self
+            .loaded_scripts
+            .lock()
+            .expect("loaded scripts lock to not be poisoned");
+        let ast = loaded_scripts
+            .get(script_path)
+            .and_then(|loaded_script| loaded_script.maybe_ast.as_ref())
+            .ok_or(ScriptError::NotLoaded)?;
+        let args = vec![
+            Dynamic::from(caller.to_string()),
+            Dynamic::from(command_args.to_string()),
+        ];
+        let call_result = self.call(ast, function_name, args);
+        // Messages sent before a failure still go out
+        let sent_messages = self.take_outbox();
+        let reply = call_result?;
+        let maybe_reply = match reply.is_unit() {
+            true => None,
+            false => Some(reply.to_string()),
+        };
+        Ok((maybe_reply, sent_messages))
+    }
+
+    fn call(
+        &self,
+        ast: &AST,
+        function_name: &str,
+        args: Vec<Dynamic>,
+    ) -> Result<Dynamic, ScriptError> {
+        // Top level statements only run when the script is loaded
+        let options = CallFnOptions::new().eval_ast(false);
+        self.start_call_clock();
+        Ok(self.engine.call_fn_with_options(
+            options,
+            &mut Scope::new(),
+            ast,
+            function_name,
+            args,
+        )?)
+    }
+
+    fn start_call_clock(&self) {
+        *self
+            .call_deadline
+            .lock()
+            .expect("script call deadline lock to not be poisoned") =
+            Instant::now() + self.time_limit;
+    }
+
+    fn take_outbox(&self) -> ScriptMessages {
+        std::mem::take(
+            &mut *self
+                .outbox
+                .lock()
+                .expect("script outbox lock to not be poisoned"),
+        )
+    }
+
+    /// Compile a script and run its top level statements, returns the
+    /// commands it registered
+    fn load(&self, source: &str) -> Result<(AST, Vec<ScriptCommandSpec>), ScriptError> {
+        let ast = self.engine.compile(source)?;
+        self.start_call_clock();
+        let run_result = self.engine.run_ast(&ast);
+        // Loading isn't an event, there is nobody to send anything to yet
+        self.take_outbox();
+        let command_specs = std::mem::take(
+            &mut *self
+                .registered_commands
+                .lock()
+                .expect("script commands lock to not be poisoned"),
+        );
+        run_result?;
+        Ok((ast, command_specs))
+    }
+
+    /// Load scripts added or changed since the last check and unload removed
+    /// ones, swapping their commands in the registry along with them
+    pub(crate) async fn reload(self: &Arc<Self>, commands: &RwLock<CommandRegistry>) {
+        let script_files = match self.script_files().await {
+            Ok(script_files) => script_files,
+            Err(error) => {
+                warn!(
+                    "Could not read the scripts directory {}: {error}",
+                    self.scripts_dir.display()
+                );
+                return;
+            }
+        };
+        // Top level statements run while loading, and every call into a
+        // script holds the loaded scripts, so loading stays off the async threads
+        let scripts = self.clone();
+        let load_result =
+            tokio::task::spawn_blocking(move || scripts.load_changed(&script_files)).await;
+        let (stale_command_names, new_commands) = match load_result {
+            Ok(changes) => changes,
+            Err(error) => {
+                error!("Loading scripts stopped before it finished: {error}");
+                return;
+            }
+        };
+        if stale_command_names.is_empty() && new_commands.is_empty() {
+            return;
+        }
+
+        let mut commands_lock = commands.write().await;
+        for command_name in &stale_command_names {
+            commands_lock.unregister(command_name);
+        }
+        let mut registered_command_names = Vec::with_capacity(new_commands.len());
+        for (path, command_specs) in new_commands {
+            let mut command_names = Vec::with_capacity(command_specs.len());
+            for command_spec in command_specs {
+                if commands_lock.contains(&command_spec.name) {
+                    warn!(
+                        "Script {} can't add {}, there already is a command of that name",
+                        path.display(),
+                        command_spec.name
+                    );
+                    continue;
+                }
+                command_names.push(command_spec.name.clone());
+                commands_lock.register(ScriptCommand {
+                    spec: command_spec,
+                    script_path: path.clone(),
+                    scripts: self.clone(),
+                });
+            }
+            registered_command_names.push((path, command_names));
+        }
+        drop(commands_lock);
+
+        let scripts = self.clone();
+        let update_result = tokio::task::spawn_blocking(move || {
+            let mut loaded_scripts = scripts
+                .loaded_scripts
+                .lock()
+                .expect("loaded scripts lock to not be poisoned");
+            for (path, command_names) in registered_command_names {
+                if let Some(loaded_script) = loaded_scripts.get_mut(&path) {
+                    loaded_script.command_names = command_names;
+                }
+            }
+        })
+        .await;
+        if let Err(error) = update_result {
+            error!("Could not note the commands of the loaded scripts: {error}");
+        }
+    }
+
+    /// Unload scripts that are no longer in `script_files` and load the
+    /// added and changed ones, returns the names of the commands to remove
+    /// and the commands each loaded script registered
+    fn load_changed(
+        &self,
+        script_files: &BTreeMap<PathBuf, SystemTime>,
+    ) -> (Vec<String>, Vec<(PathBuf, Vec<ScriptCommandSpec>)>) {
+        let mut stale_command_names = Vec::new();
+        let mut new_commands = Vec::new();
+        let mut loaded_scripts = self
+            .loaded_scripts
+            .lock()
+            .expect("loaded scripts lock to not be poisoned");
+        let changed_files: Vec<(PathBuf, SystemTime)> = script_files
+            .iter()
+            .filter(|(path, modified)| {
+                loaded_scripts
+                    .get(*path)
+                    .is_none_or(|loaded_script| loaded_script.modified != **modified)
+            })
+            .map(|(path, modified)| (path.clone(), *modified))
+            .collect();
+        loaded_scripts.retain(|path, loaded_script| {
+            let is_kept = script_files.contains_key(path);
+            if !is_kept {
+                info!("Unloaded script {}", path.display());
+                stale_command_names.append(&mut loaded_script.command_names);
+            }
+            is_kept
+        });
+        for (path, modified) in changed_files {
+            if let Some(mut replaced_script) = loaded_scripts.remove(&path) {
+                stale_command_names.append(&mut replaced_script.command_names);
+            }
+            let load_result = std::fs::read_to_string(&path)
+                .map_err(ScriptError::from)
+                .and_then(|source| self.load(&source));
+            let maybe_ast = match load_result {
+                Ok((ast, command_specs)) => {
+                    info!("Loaded script {}", path.display());
+                    new_commands.push((path.clone(), command_specs));
+                    Some(ast)
+                }
+                Err(error) => {
+                    warn!("Could not load script {}: {error}", path.display());
+                    None
+                }
+            };
+            loaded_scripts.insert(
+                path,
+                LoadedScript {
+                    modified,
+                    maybe_ast,
+                    command_names: Vec::new(),
+                },
+            );
+        }
+        (stale_command_names, new_commands)
+    }
+
+    /// Keep reloading scripts as they change, for as long as the server runs
+    pub(crate) async fn watch(self: Arc<Self>, commands: Arc<RwLock<CommandRegistry>>) {
+        let mut reload_interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            reload_interval.tick().await;
+            self.reload(&commands).await;
+        }
+    }
+
+    /// Every script in the scripts directory along with when it last changed
+    async fn script_files(&self) -> Result<BTreeMap<PathBuf, SystemTime>, std::io::Error> {
+        let mut script_files = BTreeMap::new();
+        let mut dir_entries = tokio::fs::read_dir(&self.scripts_dir).await?;
+        while let Some(dir_entry) = dir_entries.next_entry().await? {
+            let path = dir_entry.path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != SCRIPT_EXTENSION)
+            {
+                continue;
+            }
+            let metadata = dir_entry.metadata().await?;
+            if metadata.is_file() {
+                script_files.insert(path, metadata.modified()?);
+            }
+        }
+        Ok(script_files)
+    }
+}
+
+/// A slash command added by a script
+struct ScriptCommand {
+    spec: ScriptCommandSpec,
+    script_path: PathBuf,
+    scripts: Arc<Scripts>,
+}
+
+impl Command for ScriptCommand {
+    fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    fn usage(&self) -> &str {
+        &self.spec.usage
+    }
+
+    fn description(&self) -> &str {
+        &self.spec.description
+    }
+
+    /// Scripts may take up to their time limit, so the script is only
+    /// called once the server has let go of the users
+    fn run(
+        &self,
+        context: &CommandContext,
+        command_args: &str,
+    ) -> Result<Vec<CommandAction>, CommandError> {
+        Ok(vec![CommandAction::RunScript(ScriptCall {
+            command_name: self.spec.name.clone(),
+            function_name: self.spec.function_name.clone(),
+            script_path: self.script_path.clone(),
+            scripts: self.scripts.clone(),
+            caller: context.caller.to_string(),
+            command_args: command_args.to_string(),
+        })])
+    }
+}
+
+/// # Script Call
+/// A script command that was run, waiting to call into its script on the
+/// blocking thread pool
+pub struct ScriptCall {
+    command_name: String,
+    function_name: String,
+    script_path: PathBuf,
+    scripts: Arc<Scripts>,
+    caller: String,
+    command_args: String,
+}
+
+impl ScriptCall {
+    /// Call the command's function, returns the reply and what the script
+    /// asked to send as actions for the caller
+    pub(crate) async fn run(self) -> Result<Vec<CommandAction>, CommandError> {
+        let Self {
+            command_name,
+            function_name,
+            script_path,
+            scripts,
+            caller,
+            command_args,
+        } = self;
+        let command_result = tokio::task::spawn_blocking({
+            let script_path = script_path.clone();
+            move || scripts.run_command(&script_path, &function_name, &caller, &command_args)
+        })
+        .await
+        .map_err(|error| {
+            error!(
+                "{command_name} from script {} stopped before it finished: {error}",
+                script_path.display()
+            );
+            CommandError::Script(command_name.clone())
+        })?;
+        let (maybe_reply, sent_messages) = command_result.map_err(|error| {
+            warn!(
+                "{command_name} from script {} failed: {error}",
+                script_path.display()
+            );
+            CommandError::Script(command_name.clone())
+        })?;
+        let mut command_actions: Vec<CommandAction> = sent_messages
+            .into_iter()
+            .map(|(message, audience)| CommandAction::Deliver { message, audience })
+            .collect();
+        command_actions.extend(maybe_reply.map(CommandAction::Reply));
+        Ok(command_actions)
+    }
+}
+
+impl std::fmt::Debug for ScriptCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScriptCall")
+            .field("command_name", &self.command_name)
+            .field("script_path", &self.script_path)
+            .field("caller", &self.caller)
+            .field("command_args", &self.command_args)
+            .finish_non_exhaustive()
+    }
+}
@@ -5,135 +5,1648 @@ use futures::{
     stream::{SplitSink, SplitStream},
 };
 use log::*;
-use shared_types::messages::{MessageContents, ServerMessage};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use shared_types::{
+    messages::{
+        ClientRequest, DOWNLOAD_COMMAND, FileMetadata, Frame, MessageContents, OnlineUser,
+        ServerMessage, chat_text,
+    },
+    protocol::{
+        ACCOUNT_ACTION_KEY, AccountAction, CAPABILITIES_KEY, Capabilities, Capability,
+        ENCODED_REQUESTS_PROTOCOL_VERSION, LAST_SEQUENCE_KEY, MINIMUM_PROTOCOL_VERSION,
+        NEW_PASSWORD_KEY, PASSWORD_KEY, PROTOCOL_VERSION, PROTOCOL_VERSION_KEY, REFUSAL_KEY,
+        RESUMED_KEY, Refusal, SESSION_TOKEN_KEY, USERNAME_KEY, decode_header_text,
+        encode_header_text, is_supported_protocol_version, parse_protocol_version,
+    },
+    transfer::{CHUNK_SIZE, ChunkHeader, FileChunk, mime_type},
+    wire_format::{EncodeError, SUBPROTOCOL_HEADER, WireFormat},
+};
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{Mutex, RwLock, mpsc};
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
 use tokio_tungstenite::{
     WebSocketStream,
     tungstenite::{
         Message,
         handshake::server::{ErrorResponse, Request, Response},
-        http::HeaderValue,
+        http::{HeaderMap, HeaderValue, StatusCode},
+        protocol::{CloseFrame, frame::coding::CloseCode},
     },
 };
 
-use crate::{config::ServerConfig, error::ServerError};
+use crate::{
+    access::{IpRange, check_capacity, resolve_client_ip_addr},
+    accounts::{AccountMode, AccountStore, Credentials, RegisteredNames, SignIn},
+    commands::{Command, CommandAction, CommandContext, CommandRegistry},
+    config::ServerConfig,
+    content_filter::ContentFilter,
+    error::{
+        AccountError, CapacityError, CommandError, DownloadError, ModerationError, RequestError,
+        ServerError, TransferError, UsernameError,
+    },
+    file_store::FileStore,
+    filters::{FilterVerdict, InboundMessage, MessageFilter, MessageFilters},
+    message_log::{Audience, MessageLog},
+    moderation::{Ban, Moderation, ModerationCommand, Role, Sanction},
+    scripts::{ScriptCall, ScriptEvent, Scripts},
+    session::SessionSigner,
+    transfer::{ChunkOutcome, Transfers, remove_partial_file},
+    username::{confusable_skeleton, normalize_username, validate_username},
+};
+
+/// Write half of a user's connection, shared with the tasks streaming
+/// downloads to them so those don't keep every connected user locked
+type MessageSink = Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>;
+
+/// Script events that can wait for the scripts before newer ones are dropped
+const SCRIPT_EVENT_QUEUE_SIZE: usize = 256;
+
+/// How long a new connection has to send its whole handshake request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Larger handshake requests are dropped
+const MAX_HANDSHAKE_REQUEST_SIZE: usize = 16 * 1024;
+/// Same as tungstenite's own limit
+const MAX_HANDSHAKE_HEADERS: usize = 124;
+/// Wait before looking at a handshake request again that hasn't fully arrived
+const HANDSHAKE_PEEK_INTERVAL: Duration = Duration::from_millis(10);
 
 struct User {
     name: String,
-    writable_message_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+    writable_message_sink: MessageSink,
+    /// How messages to this user are encoded, agreed on during the handshake
+    wire_format: WireFormat,
+    /// Supported by both the server and this user's client
+    capabilities: Capabilities,
+    role: Role,
+    /// Signed in to an account rather than joined as a guest
+    is_account: bool,
+    /// Of the client itself rather than any trusted proxy in front of it
+    ip_addr: IpAddr,
+    /// Only a token issued for this session may take its connection over
+    session_id: u64,
+}
+
+impl User {
+    /// Serialize and send a message to this user, logging any failure since
+    /// one unreachable user shouldn't stop messages reaching everyone else
+    async fn send(&mut self, message: &ServerMessage) {
+        // Clients are never sent messages about features they don't support
+        if let Some(capability) = message.contents.required_capability()
+            && !self.capabilities.contains(capability)
+        {
+            return;
+        }
+        let ws_message = match encode_message(message, self.wire_format) {
+            Ok(ws_message) => ws_message,
+            Err(error) => {
+                error!("Could not serialize message: {error:?}");
+                return;
+            }
+        };
+        if let Err(error) = self
+            .writable_message_sink
+            .lock()
+            .await
+            .send(ws_message)
+            .await
+        {
+            warn!("Could not send message to {}: {error:?}", self.name);
+        }
+    }
+
+    /// Close the connection, telling the client why
+    async fn close(&mut self, reason: &str) {
+        let close_frame = policy_close_frame(&reason);
+        if let Err(error) = self
+            .writable_message_sink
+            .lock()
+            .await
+            .send(Message::Close(Some(close_frame)))
+            .await
+        {
+            warn!("Could not close the connection of {}: {error:?}", self.name);
+        }
+    }
+}
+
+/// Hand an event to the scripts, which get to it once they are done with the
+/// ones before it. Dropped when they are too far behind
+fn queue_script_event(
+    maybe_script_events: Option<&mpsc::Sender<ScriptEvent>>,
+    script_event: ScriptEvent,
+) {
+    if let Some(script_events) = maybe_script_events
+        && let Err(error) = script_events.try_send(script_event)
+    {
+        warn!(
+            "Scripts are too far behind, dropping {:?}",
+            error.into_inner()
+        );
+    }
+}
+
+/// Tells the client why the server is closing its connection
+fn policy_close_frame(reason: &impl ToString) -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.to_string().into(),
+    }
+}
+
+/// The WebSocket message to send `message` as to a client that agreed on `wire_format`
+fn encode_message(
+    message: &ServerMessage,
+    wire_format: WireFormat,
+) -> Result<Message, EncodeError> {
+    Ok(match message.encode(wire_format)? {
+        Frame::Text(serialized_message) => Message::text(serialized_message),
+        Frame::Binary(frame) => Message::binary(frame),
+    })
+}
+
+/// Send the file piece by piece, only holding on to one piece at a time
+async fn stream_file_contents(
+    sink: &MessageSink,
+    wire_format: WireFormat,
+    sha256: &str,
+    mut file: File,
+    total_size: u64,
+) -> Result<(), DownloadError> {
+    let mut offset = 0;
+    loop {
+        let mut contents = Vec::with_capacity(CHUNK_SIZE.min(total_size - offset) as usize);
+        let read_bytes = (&mut file)
+            .take(CHUNK_SIZE)
+            .read_to_end(&mut contents)
+            .await? as u64;
+        // Stored files never change, but a file cut short shouldn't leave
+        // the client waiting for the rest
+        if read_bytes == 0 && offset < total_size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let message = ServerMessage::file_contents(sha256, offset, total_size, contents);
+        let ws_message = encode_message(&message, wire_format)?;
+        sink.lock().await.send(ws_message).await?;
+        offset += read_bytes;
+        if offset >= total_size {
+            return Ok(());
+        }
+    }
+}
+
+/// What a client asked for, whichever protocol version it speaks
+enum InboundRequest {
+    /// Chat or a command, direct messages typed out as one included
+    Text(String),
+    /// Sent as a request of its own by clients that encode their requests
+    DirectMessage {
+        recipient: String,
+        text: String,
+    },
+    /// SHA-256 of the file to send the contents of
+    DownloadFile(String),
+    FileChunk(FileChunk),
+}
+
+impl InboundRequest {
+    fn new(client_request: ClientRequest, chunk_data: &[u8]) -> Self {
+        match client_request {
+            ClientRequest::Text(text_message) => InboundRequest::Text(text_message),
+            ClientRequest::DirectMessage { recipient, text } => {
+                InboundRequest::DirectMessage { recipient, text }
+            }
+            ClientRequest::DownloadFile { sha256 } => InboundRequest::DownloadFile(sha256),
+            ClientRequest::FileChunk(header) => InboundRequest::FileChunk(FileChunk {
+                header,
+                data: chunk_data.to_vec(),
+            }),
+        }
+    }
+}
+
+/// Read what the client sent the way its protocol version lays it out,
+/// `None` for anything that isn't a request such as pings
+fn decode_request(
+    message: Message,
+    wire_format: WireFormat,
+    protocol_version: u32,
+) -> Result<Option<InboundRequest>, RequestError> {
+    let is_encoded = protocol_version >= ENCODED_REQUESTS_PROTOCOL_VERSION;
+    Ok(Some(match message {
+        Message::Text(text_message) if is_encoded => {
+            InboundRequest::new(ClientRequest::decode_text(&text_message)?, &[])
+        }
+        Message::Binary(frame) if is_encoded => {
+            let (client_request, chunk_data) = ClientRequest::decode_binary(wire_format, &frame)?;
+            InboundRequest::new(client_request, chunk_data)
+        }
+        // Older clients send text as is and file chunks as bare frames
+        Message::Text(text_message) => InboundRequest::Text(text_message.to_string()),
+        Message::Binary(frame) => {
+            InboundRequest::FileChunk(FileChunk::decode(wire_format, &frame)?)
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/// Text message from the server to a single user
+fn announcement(text: impl ToString) -> ServerMessage {
+    ServerMessage::server_announcement(MessageContents::Text(text.to_string()))
 }
 
 type Users = Arc<Mutex<HashMap<SocketAddr, User>>>;
 
-pub(crate) struct Server {
+/// Send the message to every connected user, other than the one at
+/// `skipped_socket_addr` if provided
+async fn broadcast(
+    connected_users: &mut HashMap<SocketAddr, User>,
+    message: &ServerMessage,
+    skipped_socket_addr: Option<SocketAddr>,
+) {
+    join_all(
+        connected_users
+            .iter_mut()
+            .filter(|(addr, _)| Some(**addr) != skipped_socket_addr)
+            .map(|(_, user)| user.send(message)),
+    )
+    .await;
+}
+
+/// Response refusing a handshake, saying why in the refusal header for
+/// the client and in the body for whoever reads it
+fn refuse_handshake(refusal: Refusal, reason: impl Into<String>) -> ErrorResponse {
+    let mut err_response = ErrorResponse::new(Some(reason.into()));
+    *err_response.status_mut() = match refusal {
+        Refusal::UsernameTaken => StatusCode::CONFLICT,
+        Refusal::AccountRequired | Refusal::BadCredentials => StatusCode::UNAUTHORIZED,
+        Refusal::Forbidden => StatusCode::FORBIDDEN,
+        Refusal::ServerFull => StatusCode::SERVICE_UNAVAILABLE,
+        Refusal::TooManyConnections => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    err_response
+        .headers_mut()
+        .insert(REFUSAL_KEY, HeaderValue::from_static(refusal.code()));
+    err_response
+}
+
+/// Read the handshake request without taking it off the socket, tungstenite
+/// reads it again to complete the handshake
+async fn peek_handshake_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut buffer = vec![0; MAX_HANDSHAKE_REQUEST_SIZE];
+    loop {
+        let peeked_len = stream.peek(&mut buffer).await?;
+        if peeked_len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HANDSHAKE_HEADERS];
+        let mut parsed_request = httparse::Request::new(&mut headers);
+        match parsed_request.parse(&buffer[..peeked_len]) {
+            Ok(httparse::Status::Complete(_)) => {
+                let mut request_builder = Request::builder()
+                    .method(parsed_request.method.unwrap_or_default())
+                    .uri(parsed_request.path.unwrap_or_default());
+                for header in parsed_request.headers.iter() {
+                    request_builder = request_builder.header(header.name, header.value);
+                }
+                return request_builder
+                    .body(())
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+            }
+            // The rest of the request is still on its way
+            Ok(httparse::Status::Partial) if peeked_len < buffer.len() => {
+                tokio::time::sleep(HANDSHAKE_PEEK_INTERVAL).await;
+            }
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Handshake request is too large",
+                ));
+            }
+            Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+        }
+    }
+}
+
+/// Account credentials sent with the handshake, `None` for guests
+fn handshake_credentials(req: &Request) -> Result<Option<Credentials>, AccountError> {
+    let Some(action) = req.headers().get(ACCOUNT_ACTION_KEY) else {
+        return Ok(None);
+    };
+    let action = String::from_utf8_lossy(action.as_bytes());
+    let action = AccountAction::from_code(&action)
+        .ok_or_else(|| AccountError::UnknownAction(action.into()))?;
+    let header_text = |key| {
+        req.headers()
+            .get(key)
+            .and_then(|value: &HeaderValue| decode_header_text(value.as_bytes()).ok())
+    };
+    Ok(Some(Credentials {
+        action,
+        password: header_text(PASSWORD_KEY).ok_or(AccountError::MissingPassword)?,
+        maybe_new_password: header_text(NEW_PASSWORD_KEY),
+    }))
+}
+
+/// Sign in with the session token sent with the handshake, `None` if there
+/// is none or it can't be used for this username anymore, in which case the
+/// user signs in as usual. Clients asking for an account action such as
+/// changing the password always sign in with their credentials
+fn resume_sign_in(
+    req: &Request,
+    username: &str,
+    session_signer: &SessionSigner,
+    account_store: &AccountStore,
+) -> Option<SignIn> {
+    if req.headers().contains_key(ACCOUNT_ACTION_KEY) {
+        return None;
+    }
+    let session_token = req.headers().get(SESSION_TOKEN_KEY)?;
+    let resume_result = session_signer
+        .verify(session_token.to_str().unwrap_or_default())
+        .map_err(|error| error.to_string())
+        .and_then(|claims| {
+            if confusable_skeleton(&claims.username) != confusable_skeleton(username) {
+                return Err(format!("Session belongs to {}", claims.username));
+            }
+            if !session_signer.is_name_holder(&claims.username, claims.session_id) {
+                return Err(String::from("Someone else joined with the name since"));
+            }
+            account_store
+                .resume(
+                    &claims.username,
+                    claims.is_account,
+                    claims.token_generation,
+                    claims.session_id,
+                )
+                .map_err(|error| error.to_string())
+        });
+    match resume_result {
+        Ok(sign_in) => Some(sign_in),
+        Err(reason) => {
+            info!("Could not resume the session of {username}: {reason}");
+            None
+        }
+    }
+}
+
+fn online_users(connected_users: &HashMap<SocketAddr, User>) -> Vec<OnlineUser> {
+    let mut online_users: Vec<OnlineUser> = connected_users
+        .values()
+        .map(|user| OnlineUser {
+            name: user.name.clone(),
+            role: user.role,
+        })
+        .collect();
+    online_users.sort_by(|a, b| a.name.cmp(&b.name));
+    online_users
+}
+
+/// Handles to the state every connection shares, cloned for each one
+#[derive(Clone)]
+struct SharedState {
+    connected_users: Users,
+    transfers: Arc<Mutex<Transfers>>,
+    file_store: Arc<Mutex<FileStore>>,
+    message_log: Arc<Mutex<MessageLog>>,
+    moderation: Arc<Mutex<Moderation>>,
+    /// Scripts add and remove commands while the server runs
+    commands: Arc<RwLock<CommandRegistry>>,
+    message_filters: Arc<MessageFilters>,
+    maybe_script_events: Option<mpsc::Sender<ScriptEvent>>,
+    topic: Arc<Mutex<Option<String>>>,
+    registered_names: RegisteredNames,
+    config: Arc<ServerConfig>,
+}
+
+pub struct Server {
     connected_users: Users,
+    /// Only recorded to while holding the lock on the connected users, so
+    /// the order of the log is the order users are sent messages in
+    message_log: Arc<Mutex<MessageLog>>,
+    commands: CommandRegistry,
+    message_filters: MessageFilters,
+    maybe_scripts: Option<Arc<Scripts>>,
     config: ServerConfig,
 }
 
-impl Server {
-    pub(crate) fn new(config: ServerConfig) -> Self {
-        Self {
+/// # Server Builder
+/// Sets up a [`Server`] along with the commands and message filters a
+/// deployment adds on top of the built-in ones
+pub struct ServerBuilder {
+    config: ServerConfig,
+    commands: CommandRegistry,
+    message_filters: Vec<Box<dyn MessageFilter>>,
+    maybe_scripts: Option<Arc<Scripts>>,
+}
+
+impl ServerBuilder {
+    /// Replaces a built-in command of the same name
+    pub fn command(mut self, command: impl Command + 'static) -> Self {
+        self.commands.register(command);
+        self
+    }
+
+    /// Filters run in the order they are added in, unless the config says otherwise
+    pub fn message_filter(mut self, message_filter: impl MessageFilter + 'static) -> Self {
+        self.message_filters.push(Box::new(message_filter));
+        self
+    }
+
+    /// Adds the [`ContentFilter`] from the config's content filter file as
+    /// the next filter, if the config names one
+    pub fn content_filter_from_config(self) -> Result<Self, ServerError> {
+        let Some(content_filter_file) = self.config.content_filter_file.as_deref() else {
+            return Ok(self);
+        };
+        let content_filter = ContentFilter::open(content_filter_file).map_err(|error| {
+            error!("Error opening the content filter file: {error:?}");
+            ServerError::OpenContentFilter(error)
+        })?;
+        Ok(self.message_filter(content_filter))
+    }
+
+    pub fn build(self) -> Server {
+        Server {
             connected_users: Arc::new(Mutex::new(HashMap::new())),
+            message_log: Arc::new(Mutex::new(MessageLog::new(&self.config))),
+            commands: self.commands,
+            message_filters: MessageFilters::new(
+                self.message_filters,
+                &self.config.message_filters,
+            ),
+            maybe_scripts: self.maybe_scripts,
+            config: self.config,
+        }
+    }
+}
+
+impl Server {
+    /// Scripts, when there is a scripts directory, see messages after every
+    /// filter added to the builder
+    pub fn builder(config: ServerConfig) -> ServerBuilder {
+        let maybe_scripts = config
+            .scripts_dir
+            .clone()
+            .map(|scripts_dir| Arc::new(Scripts::new(&config, scripts_dir)));
+        ServerBuilder {
             config,
+            commands: CommandRegistry::with_builtins(),
+            message_filters: Vec::new(),
+            maybe_scripts,
         }
     }
 
     async fn accept_connection(
         mut stream: SplitStream<WebSocketStream<TcpStream>>,
         client_socket_addr: SocketAddr,
-        connected_users: Users,
+        client_ip_addr: IpAddr,
+        wire_format: WireFormat,
+        protocol_version: u32,
+        shared_state: SharedState,
     ) {
         //TODO: Impl crypto https://docs.rs/simple_crypt/latest/simple_crypt/
-        debug!("Polling {client_socket_addr} for messages");
+        debug!("Polling {client_ip_addr} for messages");
         while let Some(Ok(message)) = stream.next().await {
             debug!(
-                "New message: {message} \n\t from {client_socket_addr}, propogating to connected clients"
+                "New message: {message} \n\t from {client_ip_addr}, propogating to connected clients"
             );
-            let mut connected_users_lock = connected_users.lock().await;
-            let client_name = connected_users_lock
+            let mut connected_users_lock = shared_state.connected_users.lock().await;
+            // Kicked users are removed before their connection finishes closing
+            let Some(client_name) = connected_users_lock
                 .get(&client_socket_addr)
-                .expect(
-                    "Should not return None as hashmap must be synced with active ws connections",
-                )
-                .name
-                .clone();
-            let try_serialized_message = match message {
-                Message::Text(text_message) => {
-                    let client_message = ServerMessage {
-                        author: client_name,
-                        contents: MessageContents::Text(text_message),
+                .map(|user| user.name.clone())
+            else {
+                break;
+            };
+            if message.is_close() {
+                break;
+            }
+            let request = match decode_request(message, wire_format, protocol_version) {
+                Ok(Some(request)) => request,
+                // Pings are answered by tungstenite itself
+                Ok(None) => continue,
+                Err(error) => {
+                    warn!("Could not read message from {client_ip_addr}: {error}");
+                    if let Some(client) = connected_users_lock.get_mut(&client_socket_addr) {
+                        client
+                            .send(&announcement(format!("Could not read message: {error}")))
+                            .await;
+                    }
+                    continue;
+                }
+            };
+            // Commands check for themselves whether they would let a muted user speak
+            let is_chat_message = match &request {
+                InboundRequest::Text(text_message) => chat_text(text_message).is_some(),
+                InboundRequest::DirectMessage { .. } | InboundRequest::FileChunk(_) => true,
+                InboundRequest::DownloadFile(_) => false,
+            };
+            if is_chat_message
+                && let Err(error) =
+                    Self::check_not_muted(&shared_state.moderation, &client_name).await
+            {
+                if let Some(client) = connected_users_lock.get_mut(&client_socket_addr) {
+                    client.send(&announcement(error)).await;
+                }
+                continue;
+            }
+            let injected_messages;
+            let message_to_propogate = match request {
+                InboundRequest::DownloadFile(sha256) => {
+                    Self::send_file_contents(
+                        &mut connected_users_lock,
+                        &shared_state.file_store,
+                        client_socket_addr,
+                        &sha256,
+                    )
+                    .await;
+                    continue;
+                }
+                InboundRequest::DirectMessage { recipient, text } => {
+                    let Some(mut inbound_message) = Self::filter_message(
+                        &shared_state,
+                        &mut connected_users_lock,
+                        client_socket_addr,
+                        MessageContents::DirectMessage { recipient, text },
+                    )
+                    .await
+                    else {
+                        continue;
                     };
-                    serde_json::to_string(&client_message)
-                }
-                Message::Binary(file) => {
-                    match stream.next().await {
-                        Some(Ok(Message::Text(filename))) => {
-                            let client_message = ServerMessage {
-                                author: client_name,
-                                contents: MessageContents::File {
-                                    name: filename,
-                                    contents: file,
-                                },
-                            };
-
-                            let serialized_message = serde_json::to_string(&client_message);
-                            serialized_message
-                        }
-                        _ => {
-                            //TODO: Add error type to match serde and the situation
-                            //      where no file name is sent and make this varia-
-                            //      ble use the library error type
-                            todo!()
+                    let injected_messages = inbound_message.take_injected_messages();
+                    let MessageContents::DirectMessage { recipient, text } =
+                        inbound_message.contents
+                    else {
+                        warn!(
+                            "Message filters turned a direct message from {client_ip_addr} into something else, dropping it"
+                        );
+                        continue;
+                    };
+                    // Carried out the way the command would, without reading
+                    // the recipient back out of text
+                    let direct_message = CommandAction::DirectMessage { recipient, text };
+                    if let Err(error) = Self::carry_out(
+                        &shared_state,
+                        &mut connected_users_lock,
+                        client_socket_addr,
+                        direct_message,
+                    )
+                    .await
+                    {
+                        info!("Refused direct message from {client_ip_addr}: {error}");
+                        if let Some(client) = connected_users_lock.get_mut(&client_socket_addr) {
+                            client.send(&announcement(error)).await;
                         }
                     }
+                    Self::deliver_messages(
+                        &mut connected_users_lock,
+                        &shared_state.message_log,
+                        injected_messages,
+                    )
+                    .await;
+                    continue;
                 }
-                Message::Close(_) => {
-                    let client_message = ServerMessage {
-                        author: String::from("Server"),
-                        contents: MessageContents::Text(format!("{client_name} has disconnected")),
+                InboundRequest::Text(text_message) => {
+                    if let Some(sha256) = text_message
+                        .strip_prefix(DOWNLOAD_COMMAND)
+                        .and_then(|command_args| command_args.strip_prefix(' '))
+                    {
+                        Self::send_file_contents(
+                            &mut connected_users_lock,
+                            &shared_state.file_store,
+                            client_socket_addr,
+                            sha256,
+                        )
+                        .await;
+                        continue;
+                    }
+                    let Some(mut inbound_message) = Self::filter_message(
+                        &shared_state,
+                        &mut connected_users_lock,
+                        client_socket_addr,
+                        MessageContents::Text(text_message),
+                    )
+                    .await
+                    else {
+                        continue;
                     };
-                    let serialized_message = serde_json::to_string(&client_message);
+                    injected_messages = inbound_message.take_injected_messages();
+                    let MessageContents::Text(text_message) = inbound_message.contents else {
+                        warn!(
+                            "Message filters turned a text message from {client_ip_addr} into something else, dropping it"
+                        );
+                        continue;
+                    };
+                    let Some(chat_text) = chat_text(&text_message) else {
+                        let command_result = Self::run_command(
+                            &shared_state,
+                            &mut connected_users_lock,
+                            client_socket_addr,
+                            &text_message,
+                        )
+                        .await;
+                        let maybe_script_call = match command_result {
+                            Ok(maybe_script_call) => maybe_script_call,
+                            Err(error) => {
+                                info!("Refused command from {client_ip_addr}: {error}");
+                                if let Some(client) =
+                                    connected_users_lock.get_mut(&client_socket_addr)
+                                {
+                                    client.send(&announcement(error)).await;
+                                }
+                                None
+                            }
+                        };
+                        Self::deliver_messages(
+                            &mut connected_users_lock,
+                            &shared_state.message_log,
+                            injected_messages,
+                        )
+                        .await;
+                        if let Some(script_call) = maybe_script_call {
+                            // Everyone else keeps chatting while the script runs
+                            drop(connected_users_lock);
+                            Self::run_script_command(
+                                &shared_state,
+                                client_socket_addr,
+                                client_ip_addr,
+                                script_call,
+                            )
+                            .await;
+                        }
+                        continue;
+                    };
+                    // Scripts only answer once the message is out, since
+                    // they need the users lock held until then to deliver
+                    queue_script_event(
+                        shared_state.maybe_script_events.as_ref(),
+                        ScriptEvent::Message {
+                            username: client_name.clone(),
+                            text: chat_text.to_string(),
+                        },
+                    );
+                    ServerMessage::text(client_name, chat_text)
+                }
+                InboundRequest::FileChunk(file_chunk) => {
+                    // Everyone else keeps chatting while the chunk is written
+                    drop(connected_users_lock);
+                    let maybe_file_message = Self::receive_file_chunk(
+                        &shared_state,
+                        client_socket_addr,
+                        &client_name,
+                        file_chunk,
+                    )
+                    .await;
+                    connected_users_lock = shared_state.connected_users.lock().await;
+                    match maybe_file_message {
+                        // The message filters had their look at the first chunk
+                        Some(file_message) => {
+                            injected_messages = Vec::new();
+                            file_message
+                        }
+                        // The rest of the file is still on its way
+                        None => continue,
+                    }
+                }
+            };
+            let author = message_to_propogate.author.clone();
+            let message_to_propogate = shared_state
+                .message_log
+                .lock()
+                .await
+                .record(message_to_propogate, Audience::EveryoneBut(author));
+            // Send message to everyone else but the user that sent it
+            broadcast(
+                &mut connected_users_lock,
+                &message_to_propogate,
+                Some(client_socket_addr),
+            )
+            .await;
+            Self::deliver_messages(
+                &mut connected_users_lock,
+                &shared_state.message_log,
+                injected_messages,
+            )
+            .await;
+        }
+
+        // Reached on a close message and when the connection drops without one
+        Self::disconnect_user(
+            client_socket_addr,
+            &shared_state.connected_users,
+            &shared_state.message_log,
+            shared_state.maybe_script_events.as_ref(),
+        )
+        .await;
+    }
+
+    /// Run an inbound message through the message filters. A rejected message
+    /// comes back as None, after its author is told why and whatever the
+    /// filters injected is sent
+    async fn filter_message(
+        shared_state: &SharedState,
+        connected_users: &mut HashMap<SocketAddr, User>,
+        author_socket_addr: SocketAddr,
+        contents: MessageContents,
+    ) -> Option<InboundMessage> {
+        let (mut inbound_message, filter_verdict) =
+            Self::run_message_filters(shared_state, connected_users, author_socket_addr, contents)
+                .await?;
+        let FilterVerdict::Reject(reason) = filter_verdict else {
+            return Some(inbound_message);
+        };
+        if let Some(author) = connected_users.get_mut(&author_socket_addr) {
+            author
+                .send(&announcement(format!(
+                    "Your message was not sent: {reason}"
+                )))
+                .await;
+        }
+        Self::deliver_messages(
+            connected_users,
+            &shared_state.message_log,
+            inbound_message.take_injected_messages(),
+        )
+        .await;
+        None
+    }
+
+    /// Run the message filters and mute the author if one of them asked to,
+    /// leaving what to do with the verdict to the caller
+    async fn run_message_filters(
+        shared_state: &SharedState,
+        connected_users: &mut HashMap<SocketAddr, User>,
+        author_socket_addr: SocketAddr,
+        contents: MessageContents,
+    ) -> Option<(InboundMessage, FilterVerdict)> {
+        let author = connected_users.get(&author_socket_addr)?;
+        let mut inbound_message = InboundMessage::new(&author.name, author.ip_addr, contents);
+        let filter_verdict = shared_state.message_filters.run(&mut inbound_message);
+        if let Some(mute) = inbound_message.take_author_mute() {
+            let author_name = inbound_message.author.clone();
+            Self::mute_author(shared_state, connected_users, &author_name, mute).await;
+        }
+        Some((inbound_message, filter_verdict))
+    }
+
+    /// Run the name of a new upload through the message filters before any of
+    /// the file is taken in, as file metadata without a hash yet. Whatever the
+    /// filters injected is sent straight away, and the name they left is the
+    /// one the file is announced under
+    async fn filter_filename(
+        shared_state: &SharedState,
+        connected_users: &mut HashMap<SocketAddr, User>,
+        sender_socket_addr: SocketAddr,
+        header: &ChunkHeader,
+    ) -> Result<Option<String>, TransferError> {
+        let file_metadata = FileMetadata {
+            name: header.filename.clone(),
+            size: header.total_size,
+            mime_type: mime_type(&header.filename),
+            sha256: String::new(),
+        };
+        let Some((mut inbound_message, filter_verdict)) = Self::run_message_filters(
+            shared_state,
+            connected_users,
+            sender_socket_addr,
+            MessageContents::File(file_metadata),
+        )
+        .await
+        else {
+            return Ok(None);
+        };
+        Self::deliver_messages(
+            connected_users,
+            &shared_state.message_log,
+            inbound_message.take_injected_messages(),
+        )
+        .await;
+        match (filter_verdict, inbound_message.contents) {
+            (FilterVerdict::Reject(reason), _) => Err(TransferError::FilenameRejected(reason)),
+            (FilterVerdict::Pass, MessageContents::File(file_metadata)) => {
+                Ok(Some(file_metadata.name))
+            }
+            (FilterVerdict::Pass, _) => {
+                warn!(
+                    "Message filters turned the file {} into something else, keeping its name",
+                    header.filename
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Mute the author of a message for a message filter and tell everyone
+    async fn mute_author(
+        shared_state: &SharedState,
+        connected_users: &mut HashMap<SocketAddr, User>,
+        author_name: &str,
+        mute: Sanction,
+    ) {
+        let public_announcement = format!("{author_name} was muted by {}{mute}", mute.issued_by);
+        info!("{public_announcement}");
+        if let Err(error) = shared_state
+            .moderation
+            .lock()
+            .await
+            .mute(author_name, mute)
+            .await
+        {
+            error!("Could not save the mute of {author_name}: {error:?}");
+        }
+        Self::announce(
+            connected_users,
+            &shared_state.message_log,
+            public_announcement,
+        )
+        .await;
+    }
+
+    /// Record messages made by message filters, scripts or commands and send
+    /// them to whoever they are for
+    async fn deliver_messages(
+        connected_users: &mut HashMap<SocketAddr, User>,
+        message_log: &Mutex<MessageLog>,
+        messages: Vec<(ServerMessage, Audience)>,
+    ) {
+        for (message, audience) in messages {
+            let message = message_log.lock().await.record(message, audience.clone());
+            join_all(
+                connected_users
+                    .values_mut()
+                    .filter(|user| audience.includes(&user.name))
+                    .map(|user| user.send(&message)),
+            )
+            .await;
+        }
+    }
 
-                    connected_users_lock.remove(&client_socket_addr);
+    /// Muted users may still run commands, as long as they don't say anything
+    async fn check_not_muted(
+        moderation: &Mutex<Moderation>,
+        username: &str,
+    ) -> Result<(), CommandError> {
+        match moderation.lock().await.find_mute(username) {
+            Some(mute) => Err(CommandError::Muted(mute.to_string())),
+            None => Ok(()),
+        }
+    }
 
-                    serialized_message
+    /// Run a slash command and carry out what it asks for, the error is for
+    /// whoever sent it. A call into a script comes back to be run once the
+    /// users lock is released
+    async fn run_command(
+        shared_state: &SharedState,
+        connected_users: &mut HashMap<SocketAddr, User>,
+        caller_socket_addr: SocketAddr,
+        text: &str,
+    ) -> Result<Option<ScriptCall>, CommandError> {
+        let Some(caller) = connected_users.get(&caller_socket_addr) else {
+            return Ok(None);
+        };
+        debug!("{} sent the command {text}", caller.name);
+        let commands_lock = shared_state.commands.read().await;
+        let topic_lock = shared_state.topic.lock().await;
+        let mut online_users: Vec<(&str, Role)> = connected_users
+            .values()
+            .map(|user| (user.name.as_str(), user.role))
+            .collect();
+        online_users.sort_by_key(|(name, _)| *name);
+        let context = CommandContext {
+            caller: &caller.name,
+            caller_role: caller.role,
+            online_users,
+            maybe_topic: topic_lock.as_deref(),
+            commands: &commands_lock,
+        };
+        let command_actions = commands_lock.run(&context, text)?;
+        drop(topic_lock);
+        drop(commands_lock);
+
+        let mut maybe_script_call = None;
+        for command_action in command_actions {
+            match command_action {
+                CommandAction::RunScript(script_call) => maybe_script_call = Some(script_call),
+                command_action => {
+                    Self::carry_out(
+                        shared_state,
+                        connected_users,
+                        caller_socket_addr,
+                        command_action,
+                    )
+                    .await?
                 }
-                _ => todo!(),
-            };
-            match try_serialized_message {
-                Ok(serialized_message) => {
-                    let message_to_propogate = Message::text(serialized_message);
+            }
+        }
+        Ok(maybe_script_call)
+    }
+
+    /// Call into the script of a script command without holding the users,
+    /// then carry out what it asked for
+    async fn run_script_command(
+        shared_state: &SharedState,
+        caller_socket_addr: SocketAddr,
+        caller_ip_addr: IpAddr,
+        script_call: ScriptCall,
+    ) {
+        let command_result = script_call.run().await;
+        let mut connected_users_lock = shared_state.connected_users.lock().await;
+        let carry_out_result = async {
+            for command_action in command_result? {
+                Self::carry_out(
+                    shared_state,
+                    &mut connected_users_lock,
+                    caller_socket_addr,
+                    command_action,
+                )
+                .await?;
+            }
+            Ok::<_, CommandError>(())
+        }
+        .await;
+        if let Err(error) = carry_out_result {
+            info!("Script command from {caller_ip_addr} failed: {error}");
+            if let Some(caller) = connected_users_lock.get_mut(&caller_socket_addr) {
+                caller.send(&announcement(error)).await;
+            }
+        }
+    }
+
+    async fn carry_out(
+        shared_state: &SharedState,
+        connected_users: &mut HashMap<SocketAddr, User>,
+        caller_socket_addr: SocketAddr,
+        command_action: CommandAction,
+    ) -> Result<(), CommandError> {
+        let Some(caller_name) = connected_users
+            .get(&caller_socket_addr)
+            .map(|caller| caller.name.clone())
+        else {
+            return Ok(());
+        };
+        match command_action {
+            CommandAction::Reply(text) => {
+                if let Some(caller) = connected_users.get_mut(&caller_socket_addr) {
+                    caller.send(&announcement(text)).await;
+                }
+            }
+            CommandAction::Say(text) => {
+                Self::check_not_muted(&shared_state.moderation, &caller_name).await?;
+                let chat_message = shared_state
+                    .message_log
+                    .lock()
+                    .await
+                    .record(ServerMessage::text(&caller_name, text), Audience::Everyone);
+                broadcast(connected_users, &chat_message, None).await;
+            }
+            CommandAction::DirectMessage { recipient, text } => {
+                Self::check_not_muted(&shared_state.moderation, &caller_name).await?;
+                Self::send_direct_message(
+                    connected_users,
+                    &shared_state.message_log,
+                    &caller_name,
+                    &recipient,
+                    &text,
+                )
+                .await?;
+            }
+            CommandAction::Rename(requested_name) => {
+                Self::check_not_muted(&shared_state.moderation, &caller_name).await?;
+                Self::rename_user(
+                    shared_state,
+                    connected_users,
+                    caller_socket_addr,
+                    &requested_name,
+                )
+                .await?;
+            }
+            CommandAction::SetTopic(topic) => {
+                Self::check_not_muted(&shared_state.moderation, &caller_name).await?;
+                info!("{caller_name} set the topic to: {topic}");
+                *shared_state.topic.lock().await = Some(topic.clone());
+                Self::announce(
+                    connected_users,
+                    &shared_state.message_log,
+                    format!("{caller_name} set the topic to: {topic}"),
+                )
+                .await;
+            }
+            CommandAction::Moderate(moderation_command) => {
+                Self::moderate(
+                    connected_users,
+                    &shared_state.message_log,
+                    &shared_state.moderation,
+                    shared_state.maybe_script_events.as_ref(),
+                    caller_socket_addr,
+                    moderation_command,
+                )
+                .await?;
+            }
+            CommandAction::Deliver { message, audience } => {
+                Self::deliver_messages(
+                    connected_users,
+                    &shared_state.message_log,
+                    vec![(message, audience)],
+                )
+                .await;
+            }
+            // Scripts only get to run once the users lock is released
+            CommandAction::RunScript(script_call) => {
+                warn!("{script_call:?} can't be run from within another command");
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a message from the server and send it to everyone
+    async fn announce(
+        connected_users: &mut HashMap<SocketAddr, User>,
+        message_log: &Mutex<MessageLog>,
+        text: String,
+    ) {
+        let public_announcement = message_log
+            .lock()
+            .await
+            .record(announcement(text), Audience::Everyone);
+        broadcast(connected_users, &public_announcement, None).await;
+    }
+
+    /// Deliver a direct message to only the recipient
+    async fn send_direct_message(
+        connected_users: &mut HashMap<SocketAddr, User>,
+        message_log: &Mutex<MessageLog>,
+        sender_name: &str,
+        recipient: &str,
+        text: &str,
+    ) -> Result<(), CommandError> {
+        let recipient_skeleton = confusable_skeleton(recipient);
+        let recipient_user = connected_users
+            .values_mut()
+            .find(|user| confusable_skeleton(&user.name) == recipient_skeleton)
+            .ok_or_else(|| CommandError::NotOnline(recipient.to_string()))?;
+        let direct_message = message_log.lock().await.record(
+            ServerMessage::direct_message(sender_name, &recipient_user.name, text),
+            Audience::User(recipient_user.name.clone()),
+        );
+        recipient_user.send(&direct_message).await;
+        Ok(())
+    }
+
+    /// Give a guest a name they could have joined with, accounts keep theirs
+    async fn rename_user(
+        shared_state: &SharedState,
+        connected_users: &mut HashMap<SocketAddr, User>,
+        user_socket_addr: SocketAddr,
+        requested_name: &str,
+    ) -> Result<(), CommandError> {
+        if connected_users
+            .get(&user_socket_addr)
+            .is_none_or(|user| user.is_account)
+        {
+            return Err(CommandError::AccountName);
+        }
+        let new_name = normalize_username(requested_name);
+        // Changing how your own name is written is fine
+        let taken_skeletons: HashSet<String> = connected_users
+            .iter()
+            .filter(|(socket_addr, _)| **socket_addr != user_socket_addr)
+            .map(|(_, user)| confusable_skeleton(&user.name))
+            .collect();
+        validate_username(&new_name, &shared_state.config, &taken_skeletons)?;
+        if shared_state.registered_names.contains(&new_name) {
+            return Err(CommandError::RegisteredName(new_name));
+        }
+        if shared_state
+            .moderation
+            .lock()
+            .await
+            .access_rules()
+            .check_username(&new_name)
+            .is_err()
+        {
+            return Err(CommandError::BannedName(new_name));
+        }
+
+        let Some(user) = connected_users.get_mut(&user_socket_addr) else {
+            return Ok(());
+        };
+        let old_name = std::mem::replace(&mut user.name, new_name.clone());
+        info!("{old_name} is now known as {new_name}");
+        Self::announce(
+            connected_users,
+            &shared_state.message_log,
+            format!("{old_name} is now known as {new_name}"),
+        )
+        .await;
+        let user_list = ServerMessage::user_list(online_users(connected_users));
+        broadcast(connected_users, &user_list, None).await;
+        Ok(())
+    }
+
+    /// Carry out a moderation command, the error is for whoever sent it
+    async fn moderate(
+        connected_users: &mut HashMap<SocketAddr, User>,
+        message_log: &Mutex<MessageLog>,
+        moderation: &Mutex<Moderation>,
+        maybe_script_events: Option<&mpsc::Sender<ScriptEvent>>,
+        moderator_socket_addr: SocketAddr,
+        command: ModerationCommand,
+    ) -> Result<(), ModerationError> {
+        let (moderator_name, moderator_role) = connected_users
+            .get(&moderator_socket_addr)
+            .map(|moderator| (moderator.name.clone(), moderator.role))
+            .ok_or(ModerationError::NotPermitted)?;
+        if moderator_role < command.required_role() {
+            return Err(ModerationError::NotPermitted);
+        }
+
+        // Only bans reach past the users themselves to their addresses
+        let maybe_ip_range = command.target().parse::<IpRange>().ok();
+        if maybe_ip_range.is_some()
+            && !matches!(
+                command,
+                ModerationCommand::Ban { .. } | ModerationCommand::Unban { .. }
+            )
+        {
+            return Err(ModerationError::IpRangeTarget(command.target().to_string()));
+        }
+
+        let target_skeleton = confusable_skeleton(command.target());
+        let maybe_target = connected_users
+            .iter()
+            .find(|(_, user)| confusable_skeleton(&user.name) == target_skeleton)
+            .map(|(socket_addr, user)| (*socket_addr, user.name.clone(), user.role));
+        let mut moderation_lock = moderation.lock().await;
+        let (maybe_target_socket_addr, target_name, target_role) = match maybe_target {
+            Some((socket_addr, name, role)) => (Some(socket_addr), name, role),
+            None => (
+                None,
+                maybe_ip_range.map_or_else(
+                    || command.target().to_string(),
+                    |ip_range| ip_range.to_string(),
+                ),
+                moderation_lock.configured_role(command.target()),
+            ),
+        };
+        if target_role >= moderator_role {
+            return Err(ModerationError::Outranked(target_name));
+        }
+        info!("{moderator_name} moderated {target_name}: {command:?}");
+
+        let public_announcement = match command {
+            ModerationCommand::Kick { maybe_reason, .. } => {
+                let target_socket_addr = maybe_target_socket_addr
+                    .ok_or(ModerationError::NotOnline(target_name.clone()))?;
+                let reason = maybe_reason
+                    .map(|reason| format!(": {reason}"))
+                    .unwrap_or_default();
+                Self::kick_user(
+                    connected_users,
+                    maybe_script_events,
+                    target_socket_addr,
+                    &format!("Kicked by {moderator_name}{reason}"),
+                )
+                .await;
+                format!("{target_name} was kicked by {moderator_name}{reason}")
+            }
+            ModerationCommand::Ban {
+                maybe_duration,
+                maybe_reason,
+                ..
+            } => {
+                let ban = Sanction::new(&moderator_name, maybe_duration, maybe_reason)?;
+                // Everyone online from a banned range goes, other than those
+                // who outrank the admin banning it
+                let banned_socket_addrs: Vec<SocketAddr> = match maybe_ip_range {
+                    Some(ip_range) => {
+                        moderation_lock
+                            .ban(Ban::ip_range(ip_range, ban.clone()))
+                            .await?;
+                        connected_users
+                            .iter()
+                            .filter(|(_, user)| {
+                                ip_range.contains(user.ip_addr) && user.role < moderator_role
+                            })
+                            .map(|(socket_addr, _)| *socket_addr)
+                            .collect()
+                    }
+                    None => {
+                        moderation_lock
+                            .ban(Ban::username(
+                                &target_name,
+                                maybe_target_socket_addr
+                                    .and_then(|socket_addr| connected_users.get(&socket_addr))
+                                    .map(|user| user.ip_addr),
+                                ban.clone(),
+                            ))
+                            .await?;
+                        maybe_target_socket_addr.into_iter().collect()
+                    }
+                };
+                for banned_socket_addr in banned_socket_addrs {
+                    Self::kick_user(
+                        connected_users,
+                        maybe_script_events,
+                        banned_socket_addr,
+                        &format!("Banned by {moderator_name}{ban}"),
+                    )
+                    .await;
+                }
+                format!("{target_name} was banned by {moderator_name}{ban}")
+            }
+            ModerationCommand::Mute {
+                maybe_duration,
+                maybe_reason,
+                ..
+            } => {
+                let mute = Sanction::new(&moderator_name, maybe_duration, maybe_reason)?;
+                moderation_lock.mute(&target_name, mute.clone()).await?;
+                format!("{target_name} was muted by {moderator_name}{mute}")
+            }
+            ModerationCommand::Unban { .. } => {
+                if !moderation_lock.unban(&target_name).await? {
+                    return Err(ModerationError::NotBanned(target_name));
+                }
+                format!("{target_name} was unbanned by {moderator_name}")
+            }
+            ModerationCommand::Unmute { .. } => {
+                if !moderation_lock.unmute(&target_name).await? {
+                    return Err(ModerationError::NotMuted(target_name));
+                }
+                format!("{target_name} was unmuted by {moderator_name}")
+            }
+        };
+        Self::announce(connected_users, message_log, public_announcement).await;
+        Ok(())
+    }
 
-                    let mut futures_batched = vec![];
-                    // Send message to everyone else but the user that sent it
-                    connected_users_lock
-                        .iter_mut()
-                        .filter(|(addr, _)| **addr != client_socket_addr)
-                        .for_each(|(_, user)| {
-                            futures_batched.push(
-                                user.writable_message_sink
-                                    .send(message_to_propogate.clone()),
-                            );
-                        });
-                    join_all(futures_batched).await;
+    /// Close the user's connection with `reason` and update everyone's user list
+    async fn kick_user(
+        connected_users: &mut HashMap<SocketAddr, User>,
+        maybe_script_events: Option<&mpsc::Sender<ScriptEvent>>,
+        target_socket_addr: SocketAddr,
+        reason: &str,
+    ) {
+        let Some(mut user) = connected_users.remove(&target_socket_addr) else {
+            return;
+        };
+        info!(
+            "Closing the connection of {} at {target_socket_addr}: {reason}",
+            user.name
+        );
+        user.close(reason).await;
+        let user_list = ServerMessage::user_list(online_users(connected_users));
+        broadcast(connected_users, &user_list, None).await;
+        queue_script_event(
+            maybe_script_events,
+            ScriptEvent::Leave {
+                username: user.name,
+            },
+        );
+    }
+
+    /// Add a chunk to its file transfer and let the uploader know how much
+    /// has arrived. Once the whole file is there it is stored and the
+    /// message announcing it is returned. The users are only locked to
+    /// filter the filename and to tell the uploader, never while the file
+    /// is written or stored
+    async fn receive_file_chunk(
+        shared_state: &SharedState,
+        sender_socket_addr: SocketAddr,
+        sender_name: &str,
+        file_chunk: FileChunk,
+    ) -> Option<ServerMessage> {
+        let transfers = &shared_state.transfers;
+        let file_store = &shared_state.file_store;
+        let transfer_id = file_chunk.header.transfer_id;
+        let is_last_chunk = file_chunk.header.is_last_chunk();
+        let maybe_refusal_reason = transfers
+            .lock()
+            .await
+            .refusal_reason(sender_name, transfer_id)
+            .map(String::from);
+        if let Some(reason) = maybe_refusal_reason {
+            // The uploader already knows, unless the refusal was lost to a
+            // reconnect, so it is only repeated once the whole file was sent
+            if is_last_chunk {
+                Self::send_to(
+                    &shared_state.connected_users,
+                    sender_socket_addr,
+                    &ServerMessage::transfer_refused(transfer_id, reason),
+                )
+                .await;
+            }
+            return None;
+        }
+
+        let chunk_result = match file_chunk {
+            // Refuse files that won't fit before receiving all of them. The
+            // transfers stay locked so no other upload starts in between
+            // Refuse files with names the message filters won't have too. A
+            // resumed upload sends its first chunk again, which isn't
+            // filtered twice
+            file_chunk if file_chunk.header.chunk_index == 0 => {
+                let is_started = transfers.lock().await.is_started(transfer_id);
+                let maybe_shown_filename = match is_started {
+                    true => Ok(None),
+                    false => {
+                        Self::filter_filename(
+                            shared_state,
+                            &mut *shared_state.connected_users.lock().await,
+                            sender_socket_addr,
+                            &file_chunk.header,
+                        )
+                        .await
+                    }
+                };
+                let mut transfers = transfers.lock().await;
+                let pending_bytes =
+                    transfers.pending_bytes(sender_name, file_chunk.header.transfer_id);
+                let quota_result = file_store.lock().await.check_quota(
+                    sender_name,
+                    file_chunk.header.total_size,
+                    None,
+                    pending_bytes,
+                );
+                match (maybe_shown_filename, quota_result) {
+                    (Ok(maybe_shown_filename), Ok(_)) => {
+                        transfers
+                            .receive_chunk(sender_name, file_chunk, maybe_shown_filename)
+                            .await
+                    }
+                    (Err(error), _) => Err(error),
+                    (_, Err(error)) => Err(error.into()),
+                }
+            }
+            file_chunk => {
+                transfers
+                    .lock()
+                    .await
+                    .receive_chunk(sender_name, file_chunk, None)
+                    .await
+            }
+        };
+        let chunk_result = match chunk_result {
+            Ok(ChunkOutcome::Complete {
+                transfer_id,
+                filename,
+                sha256,
+                size,
+                partial_path,
+            }) => {
+                let pending_bytes = transfers
+                    .lock()
+                    .await
+                    .pending_bytes(sender_name, transfer_id);
+                let store_result = file_store
+                    .lock()
+                    .await
+                    .store(
+                        sender_name,
+                        &filename,
+                        &sha256,
+                        &partial_path,
+                        size,
+                        pending_bytes,
+                    )
+                    .await;
+                if store_result.is_err() {
+                    remove_partial_file(&partial_path).await;
                 }
-                Err(_err) => {
-                    //TODO: Send message back to this client and say that we could not process the message
-                    todo!()
+                store_result
+                    .map(|file_metadata| (transfer_id, file_metadata))
+                    .map_err(TransferError::from)
+            }
+            Ok(ChunkOutcome::Received {
+                transfer_id,
+                received_bytes,
+            }) => {
+                Self::send_to(
+                    &shared_state.connected_users,
+                    sender_socket_addr,
+                    &ServerMessage::transfer_progress(transfer_id, received_bytes),
+                )
+                .await;
+                return None;
+            }
+            Err(error) => Err(error),
+        };
+
+        match chunk_result {
+            Ok((transfer_id, file_metadata)) => {
+                let mut connected_users = shared_state.connected_users.lock().await;
+                let sender = connected_users.get_mut(&sender_socket_addr)?;
+                sender
+                    .send(&ServerMessage::transfer_progress(
+                        transfer_id,
+                        file_metadata.size,
+                    ))
+                    .await;
+                info!(
+                    "{sender_name} sent {} ({} bytes)",
+                    file_metadata.name, file_metadata.size
+                );
+                Some(ServerMessage::file(sender_name, file_metadata))
+            }
+            Err(error) => {
+                transfers
+                    .lock()
+                    .await
+                    .refuse(sender_name, transfer_id, error.to_string());
+                let mut connected_users = shared_state.connected_users.lock().await;
+                let sender = connected_users.get_mut(&sender_socket_addr)?;
+                warn!(
+                    "Refused file transfer {transfer_id} from {sender_name} ({}): {error}",
+                    sender.ip_addr
+                );
+                sender
+                    .send(&ServerMessage::transfer_refused(transfer_id, error))
+                    .await;
+                None
+            }
+        }
+    }
+
+    /// Send a message to a single user, holding the users lock only while
+    /// it goes out
+    async fn send_to(
+        connected_users: &Users,
+        user_socket_addr: SocketAddr,
+        message: &ServerMessage,
+    ) {
+        if let Some(user) = connected_users.lock().await.get_mut(&user_socket_addr) {
+            user.send(message).await;
+        }
+    }
+
+    /// Answer a `/download <sha256>` command by streaming the stored file's
+    /// contents from a task of its own, so neither the connected users nor
+    /// the file store stay locked while the file is sent
+    async fn send_file_contents(
+        connected_users: &mut HashMap<SocketAddr, User>,
+        file_store: &Mutex<FileStore>,
+        requester_socket_addr: SocketAddr,
+        sha256: &str,
+    ) {
+        let Some(requester) = connected_users.get_mut(&requester_socket_addr) else {
+            return;
+        };
+        if !requester.capabilities.contains(Capability::Files) {
+            return;
+        }
+        let open_result = file_store.lock().await.open_file(sha256).await;
+        let (file, total_size) = match open_result {
+            Ok(opened_file) => opened_file,
+            Err(error) => {
+                warn!("Could not send file {sha256} to {requester_socket_addr}: {error}");
+                requester
+                    .send(&ServerMessage::download_failed(sha256, error))
+                    .await;
+                return;
+            }
+        };
+        let sink = requester.writable_message_sink.clone();
+        let wire_format = requester.wire_format;
+        let sha256 = sha256.to_string();
+        tokio::spawn(async move {
+            if let Err(error) =
+                stream_file_contents(&sink, wire_format, &sha256, file, total_size).await
+            {
+                warn!("Could not send file {sha256} to {requester_socket_addr}: {error}");
+                // Nothing more to tell a client that can't be sent to anymore
+                if !matches!(error, DownloadError::Send(_))
+                    && let Ok(ws_message) =
+                        encode_message(&ServerMessage::download_failed(&sha256, error), wire_format)
+                {
+                    let _ = sink.lock().await.send(ws_message).await;
                 }
             }
+        });
+    }
+
+    /// Remove the user and let everyone else know they left
+    async fn disconnect_user(
+        client_socket_addr: SocketAddr,
+        connected_users: &Users,
+        message_log: &Mutex<MessageLog>,
+        maybe_script_events: Option<&mpsc::Sender<ScriptEvent>>,
+    ) {
+        let mut connected_users_lock = connected_users.lock().await;
+        let Some(user) = connected_users_lock.remove(&client_socket_addr) else {
+            return;
+        };
+        info!("{} disconnected from {}", user.name, user.ip_addr);
+
+        let leave_announcement = message_log.lock().await.record(
+            ServerMessage::server_announcement(MessageContents::Text(format!(
+                "{} has disconnected",
+                user.name
+            ))),
+            Audience::Everyone,
+        );
+        broadcast(&mut connected_users_lock, &leave_announcement, None).await;
+        let user_list = ServerMessage::user_list(online_users(&connected_users_lock));
+        broadcast(&mut connected_users_lock, &user_list, None).await;
+        queue_script_event(
+            maybe_script_events,
+            ScriptEvent::Leave {
+                username: user.name,
+            },
+        );
+    }
+
+    /// Run script events one at a time in the order they happened and
+    /// deliver what the scripts send in response. Scripts run on the
+    /// blocking thread pool, the users lock is only taken to deliver
+    async fn run_script_events(
+        scripts: Arc<Scripts>,
+        mut script_events: mpsc::Receiver<ScriptEvent>,
+        connected_users: Users,
+        message_log: Arc<Mutex<MessageLog>>,
+    ) {
+        while let Some(script_event) = script_events.recv().await {
+            let script_messages = scripts.run_event(script_event).await;
+            if script_messages.is_empty() {
+                continue;
+            }
+            let mut connected_users_lock = connected_users.lock().await;
+            Self::deliver_messages(&mut connected_users_lock, &message_log, script_messages).await;
         }
     }
 
-    pub(crate) async fn run_server(self) -> Result<(), ServerError> {
+    pub async fn run_server(self) -> Result<(), ServerError> {
         info!("Starting the server");
 
-        let config = self.config;
+        let config = Arc::new(self.config);
         let server_socket_addr = SocketAddr::new(config.ip_addr, config.port);
         debug!("Trying to use socket address: {server_socket_addr}");
 
+        let file_store = FileStore::open(&config).await.map_err(|error| {
+            error!("Error opening file storage: {error:?}");
+            ServerError::OpenFileStore(error)
+        })?;
+        let transfers = Transfers::open(&config).await.map_err(|error| {
+            error!("Error opening the partial uploads directory: {error:?}");
+            ServerError::OpenFileStore(error)
+        })?;
+        // Only used during handshakes
+        let account_store = AccountStore::open(&config).await.map_err(|error| {
+            error!("Error opening accounts file: {error:?}");
+            ServerError::OpenAccountStore(error)
+        })?;
+        let account_store = Arc::new(account_store);
+        let session_signer = Arc::new(SessionSigner::new(&config));
+        let moderation = Moderation::open(&config).await.map_err(|error| {
+            error!("Error opening ban store: {error:?}");
+            ServerError::OpenBanStore(error)
+        })?;
+        let moderation = Arc::new(Mutex::new(moderation));
+        let maybe_script_events = self.maybe_scripts.clone().map(|scripts| {
+            let (script_events, script_event_receiver) = mpsc::channel(SCRIPT_EVENT_QUEUE_SIZE);
+            tokio::spawn(Self::run_script_events(
+                scripts,
+                script_event_receiver,
+                self.connected_users.clone(),
+                self.message_log.clone(),
+            ));
+            script_events
+        });
+        let shared_state = SharedState {
+            connected_users: self.connected_users.clone(),
+            transfers: Arc::new(Mutex::new(transfers)),
+            file_store: Arc::new(Mutex::new(file_store)),
+            message_log: self.message_log.clone(),
+            moderation: moderation.clone(),
+            commands: Arc::new(RwLock::new(self.commands)),
+            message_filters: Arc::new(self.message_filters),
+            maybe_script_events,
+            topic: Arc::new(Mutex::new(None)),
+            registered_names: account_store.registered_names(),
+            config: config.clone(),
+        };
+        if config.account_mode == AccountMode::Off
+            && !(config.admins.is_empty() && config.moderators.is_empty())
+        {
+            warn!(
+                "Admins and moderators need accounts to sign in to, they have no effect while accounts are off"
+            );
+        }
+        // Scripts are in place before anyone can join
+        if let Some(scripts) = &self.maybe_scripts {
+            scripts.reload(&shared_state.commands).await;
+            tokio::spawn(scripts.clone().watch(shared_state.commands.clone()));
+        }
+
         let listener = TcpListener::bind(server_socket_addr)
             .await
             .map_err(|error| {
@@ -143,105 +1656,460 @@ impl Server {
         debug!("TCP server listening on: {server_socket_addr}");
 
         while let Ok((stream, client_socket_addr)) = listener.accept().await {
-            let mut username = String::from("");
-            let try_ws_stream = tokio_tungstenite::accept_hdr_async(
+            // Every handshake runs in a task of its own so a slow one, such as
+            // one hashing a password, doesn't hold up anyone else connecting
+            tokio::spawn(Self::handshake(
                 stream,
-                |req: &Request, mut response: Response| {
-                    debug!("Received a new ws handshake");
-                    debug!("The request's path is: {}", req.uri().path());
-                    debug!("The request's headers are:");
-                    for (ref header, value) in req.headers() {
-                        debug!("* {}: {:?}", header, value);
-                        // Can name yourself with anything but any variations of 'server'
-                        // will be reserved user to send announcements
-                        if let Ok(string_value) = value.to_str() {
-                            if *header == "username" && string_value.to_lowercase() != "server" {
-                                username.push_str(string_value);
-                            }
-                        }
-                    }
-                    if username.is_empty() {
-                        let err_response = ErrorResponse::new(Some(String::from(
-                            "Did not provide valid username",
-                        )));
-                        error!("Did not provide valid username");
-                        Err(err_response)
-                    } else {
-                        // Test val is encrypted, possible that encrypted bytes are not visible ascii
-                        let encrypted_test_value = if let Some(password) = config.auth.as_ref() {
-                            simple_crypt::encrypt(
-                                shared_types::crypt::CRYPT_VALIDATION_VAL.as_bytes(),
-                                password.as_bytes(),
-                            )
-                            //TODO: What format should password be to be valid, check simple_crypt
-                            .expect("Password to be valid")
-                        } else {
-                            shared_types::crypt::CRYPT_VALIDATION_VAL.into()
-                        };
+                client_socket_addr,
+                shared_state.clone(),
+                account_store.clone(),
+                session_signer.clone(),
+            ));
+        }
 
-                        // Ensure bytes are visible ascii for use in header
-                        let base64_encrypted_test_value =
-                            BASE64_STANDARD.encode(encrypted_test_value);
+        Ok(())
+    }
 
-                        response.headers_mut().insert(
-                            shared_types::crypt::CRYPT_VALIDATION_KEY,
-                            HeaderValue::from_str(&base64_encrypted_test_value)
-                                .expect("value to be visible ascii (Base64 encoded)"),
-                        );
-                        Ok(response)
+    /// Run the handshake of a new connection and let the user join once it succeeds
+    // The handshake callback's error type is decided by tungstenite
+    #[allow(clippy::result_large_err)]
+    async fn handshake(
+        stream: TcpStream,
+        client_socket_addr: SocketAddr,
+        shared_state: SharedState,
+        account_store: Arc<AccountStore>,
+        session_signer: Arc<SessionSigner>,
+    ) {
+        let config = &shared_state.config;
+        // Early refusals for names and slots already taken, checked again
+        // when joining since other handshakes run alongside this one
+        let connected_users_lock = shared_state.connected_users.lock().await;
+        let taken_skeletons: HashSet<String> = connected_users_lock
+            .values()
+            .map(|user| confusable_skeleton(&user.name))
+            .collect();
+        let online_ip_addrs: Vec<IpAddr> = connected_users_lock
+            .values()
+            .map(|user| user.ip_addr)
+            .collect();
+        drop(connected_users_lock);
+        // Messages after this one are replayed to the new user once it has joined
+        let last_sequence = shared_state.message_log.lock().await.last_sequence();
+        let access_rules = shared_state.moderation.lock().await.access_rules();
+        let mut client_ip_addr = client_socket_addr.ip();
+        let mut username = String::from("");
+        let mut maybe_sign_in = None;
+        let mut is_account = false;
+        let mut session_id = 0;
+        let mut is_resumed = false;
+        let mut maybe_resumed_sequence = None;
+        let mut wire_format = WireFormat::default();
+        let mut protocol_version = MINIMUM_PROTOCOL_VERSION;
+        let mut capabilities = Capabilities::default();
+        // Signing in has to await the hashing, which tungstenite's callback
+        // can't, so the request is checked before tungstenite reads it and
+        // the callback only hands over the outcome
+        let req =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, peek_handshake_request(&stream)).await {
+                Ok(Ok(req)) => req,
+                Ok(Err(error)) => {
+                    debug!("Could not read the handshake from {client_socket_addr}: {error}");
+                    return;
+                }
+                Err(_) => {
+                    debug!("{client_socket_addr} did not send its handshake in time");
+                    return;
+                }
+            };
+        let mut response_headers = HeaderMap::new();
+        let handshake_result: Result<(), ErrorResponse> = async {
+            client_ip_addr = resolve_client_ip_addr(
+                client_socket_addr.ip(),
+                req.headers(),
+                &config.trusted_proxies,
+                config.forwarded_header,
+            );
+            if client_ip_addr != client_socket_addr.ip() {
+                debug!("{client_socket_addr} is a trusted proxy for {client_ip_addr}");
+            }
+            if let Err(error) = access_rules.check_ip_addr(client_ip_addr) {
+                info!("Refused connection from {client_ip_addr}: {error}");
+                return Err(refuse_handshake(Refusal::Forbidden, error.to_string()));
+            }
+            if let Err(error) = check_capacity(config, &online_ip_addrs, client_ip_addr) {
+                info!("Refused connection from {client_ip_addr}: {error}");
+                let refusal = match error {
+                    CapacityError::ServerFull(_) => Refusal::ServerFull,
+                    CapacityError::TooManyConnections(_) => Refusal::TooManyConnections,
+                };
+                return Err(refuse_handshake(refusal, error.to_string()));
+            }
+
+            let client_protocol_version = req
+                .headers()
+                .get(PROTOCOL_VERSION_KEY)
+                .map(|protocol_version| protocol_version.to_str().unwrap_or_default());
+            let Some(supported_protocol_version) =
+                parse_protocol_version(client_protocol_version)
+                    .filter(|protocol_version| is_supported_protocol_version(*protocol_version))
+            else {
+                error!("Client speaks unsupported protocol version {client_protocol_version:?}");
+                let reason = match client_protocol_version {
+                    Some(client_protocol_version) => format!(
+                        "Protocol version '{client_protocol_version}' is not supported, this server supports versions {MINIMUM_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+                    ),
+                    None => format!(
+                        "No protocol version sent, this client is too old for this server which supports versions {MINIMUM_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+                    ),
+                };
+                return Err(refuse_handshake(Refusal::UnsupportedProtocolVersion, reason));
+            };
+            // Never newer than this server's own version once supported
+            protocol_version = supported_protocol_version;
+
+            let requested_username_result = req
+                .headers()
+                .get(USERNAME_KEY)
+                .ok_or(UsernameError::Missing)
+                .and_then(|requested_username| {
+                    decode_header_text(requested_username.as_bytes())
+                        .map_err(|_| UsernameError::NotUtf8)
+                })
+                .map(|requested_username| normalize_username(&requested_username));
+            // A valid session token stands in for the password, and takes
+            // the name over from the session's own connection if that one
+            // hasn't noticed it dropped yet
+            let maybe_resumed_sign_in =
+                requested_username_result
+                    .as_ref()
+                    .ok()
+                    .and_then(|requested_username| {
+                        resume_sign_in(&req, requested_username, &session_signer, &account_store)
+                    });
+            is_resumed = maybe_resumed_sign_in.is_some();
+            let username_result = requested_username_result.and_then(|requested_username| {
+                let taken_skeletons = if is_resumed {
+                    &HashSet::new()
+                } else {
+                    &taken_skeletons
+                };
+                validate_username(&requested_username, config, taken_skeletons)
+                    .map(|_| requested_username)
+            });
+            let validated_username = match username_result {
+                Ok(validated_username) => validated_username,
+                Err(error) => {
+                    info!("Refused username from {client_ip_addr}: {error}");
+                    let refusal = match error {
+                        UsernameError::Taken(_) => Refusal::UsernameTaken,
+                        _ => Refusal::InvalidUsername,
+                    };
+                    return Err(refuse_handshake(refusal, error.to_string()));
+                }
+            };
+            if let Err(error) = access_rules.check_username(&validated_username) {
+                info!("Refused {validated_username} from {client_ip_addr}: {error}");
+                return Err(refuse_handshake(Refusal::Forbidden, error.to_string()));
+            }
+            let sign_in_result = match maybe_resumed_sign_in {
+                Some(sign_in) => {
+                    // Nobody can have seen a message that wasn't sent yet
+                    maybe_resumed_sequence = req
+                        .headers()
+                        .get(LAST_SEQUENCE_KEY)
+                        .and_then(|sequence| sequence.to_str().ok()?.parse::<u64>().ok())
+                        .filter(|resumed_sequence| *resumed_sequence <= last_sequence);
+                    Ok(sign_in)
+                }
+                None => match handshake_credentials(&req) {
+                    Ok(maybe_credentials) => {
+                        account_store
+                            .sign_in(&validated_username, client_ip_addr, maybe_credentials)
+                            .await
                     }
+                    Err(error) => Err(error),
                 },
-            )
+            };
+            match sign_in_result {
+                Ok(sign_in) => {
+                    response_headers.insert(
+                        SESSION_TOKEN_KEY,
+                        HeaderValue::from_str(
+                            &session_signer.issue(
+                                &sign_in.username,
+                                sign_in.is_account,
+                                sign_in.token_generation,
+                                sign_in.session_id,
+                            ),
+                        )
+                        .expect("session token to be visible ascii (Base64 encoded)"),
+                    );
+                    username = sign_in.username.clone();
+                    is_account = sign_in.is_account;
+                    session_id = sign_in.session_id;
+                    maybe_sign_in = Some(sign_in);
+                }
+                Err(error) => {
+                    info!("Refused sign in from {client_ip_addr}: {error}");
+                    let refusal = match error {
+                        AccountError::Required => Refusal::AccountRequired,
+                        AccountError::BadCredentials => Refusal::BadCredentials,
+                        AccountError::Registered(_) | AccountError::AlreadyRegistered(_) => {
+                            Refusal::UsernameTaken
+                        }
+                        _ => Refusal::AccountRefused,
+                    };
+                    return Err(refuse_handshake(refusal, error.to_string()));
+                }
+            }
+            response_headers.insert(
+                USERNAME_KEY,
+                HeaderValue::from_str(&encode_header_text(&username))
+                    .expect("encoded username to be visible ascii"),
+            );
+            response_headers.insert(LAST_SEQUENCE_KEY, HeaderValue::from(last_sequence));
+            if maybe_resumed_sequence.is_some() {
+                response_headers.insert(RESUMED_KEY, HeaderValue::from_static("true"));
+            }
+            let client_capabilities = match req.headers().get(CAPABILITIES_KEY) {
+                Some(client_capabilities) => Capabilities::from_header_value(
+                    client_capabilities.to_str().unwrap_or_default(),
+                ),
+                None => Capabilities::default(),
+            };
+            capabilities = config.capabilities().intersection(&client_capabilities);
+            response_headers.insert(PROTOCOL_VERSION_KEY, HeaderValue::from(protocol_version));
+            response_headers.insert(
+                CAPABILITIES_KEY,
+                HeaderValue::from_str(&capabilities.to_string())
+                    .expect("capability names to be visible ascii"),
+            );
+
+            // Clients that don't ask for a wire format get JSON
+            if let Some(subprotocol_header) = req.headers().get(SUBPROTOCOL_HEADER) {
+                match subprotocol_header.to_str().ok().and_then(WireFormat::negotiate) {
+                    Some(negotiated_wire_format) => {
+                        wire_format = negotiated_wire_format;
+                        response_headers.insert(
+                            SUBPROTOCOL_HEADER,
+                            HeaderValue::from_static(wire_format.subprotocol()),
+                        );
+                    }
+                    None => {
+                        let supported_wire_formats =
+                            WireFormat::subprotocol_header(&WireFormat::ALL);
+                        error!("Did not request a supported wire format");
+                        return Err(refuse_handshake(
+                            Refusal::UnsupportedWireFormat,
+                            format!(
+                                "No supported wire format requested, expected one of {supported_wire_formats}"
+                            ),
+                        ));
+                    }
+                }
+            }
+            // Test val is encrypted, possible that encrypted bytes are not visible ascii
+            let encrypted_test_value = if let Some(password) = config.auth.as_ref() {
+                simple_crypt::encrypt(
+                    shared_types::crypt::CRYPT_VALIDATION_VAL.as_bytes(),
+                    password.as_bytes(),
+                )
+                //TODO: What format should password be to be valid, check simple_crypt
+                .expect("Password to be valid")
+            } else {
+                shared_types::crypt::CRYPT_VALIDATION_VAL.into()
+            };
+
+            // Ensure bytes are visible ascii for use in header
+            let base64_encrypted_test_value =
+                BASE64_STANDARD.encode(encrypted_test_value);
+
+            response_headers.insert(
+                shared_types::crypt::CRYPT_VALIDATION_KEY,
+                HeaderValue::from_str(&base64_encrypted_test_value)
+                    .expect("value to be visible ascii (Base64 encoded)"),
+            );
+            Ok(())
+        }
+        .await;
+        let try_ws_stream =
+            tokio_tungstenite::accept_hdr_async(stream, |req: &Request, mut response: Response| {
+                debug!("Received a new ws handshake");
+                debug!("The request's path is: {}", req.uri().path());
+                debug!("The request's headers are:");
+                for (ref header, value) in req.headers() {
+                    debug!("* {}: {:?}", header, value);
+                }
+                handshake_result?;
+                response.headers_mut().extend(response_headers);
+                Ok(response)
+            })
             .await
             .map_err(|error| {
                 error!("Error binding to socket address: {error:?}");
-                ServerError::CreateWebsocket(error)
+                ServerError::CreateWebsocket(Box::new(error))
             });
 
-            match try_ws_stream {
-                Ok(mut ws_stream) => {
-                    info!("New websocket connection from: {client_socket_addr}");
-                    let mut connected_users_lock = self.connected_users.lock().await;
-
-                    if connected_users_lock.contains_key(&client_socket_addr) {
-                        info!("New websocket connection denied: {client_socket_addr}");
-                        debug!("User is already connected from this IP");
-                        //TODO: Send announcemnt from the server
-                        let _ = ws_stream.close(None).await;
-                    } else if config.banned_users.contains(&client_socket_addr.ip()) {
-                        info!("New websocket connection denied: {client_socket_addr}");
-                        debug!("User is banned");
-                        //TODO: Send announcemnt from the server
-                        let _ = ws_stream.close(None).await;
-                    } else {
-                        // Splitting into read and write portions of the connections,
-                        // move the readable to the spawned handler as it is not needed for
-                        // anything else, while the writeable to the map of users
-                        let (sink, stream) = ws_stream.split();
-
-                        let new_user = User {
-                            name: username,
-                            writable_message_sink: sink,
-                        };
-                        connected_users_lock.insert(client_socket_addr, new_user);
-                        // Early drop since it is not used anywhere else after
-                        drop(connected_users_lock);
+        // Don't really care why the handshake failed
+        let Ok(mut ws_stream) = try_ws_stream else {
+            return;
+        };
+        info!("New websocket connection from: {client_ip_addr} ({client_socket_addr})");
+        if let Some(sign_in) = maybe_sign_in
+            && let Err(error) = account_store.complete_sign_in(sign_in).await
+        {
+            error!("Could not save the account of {username} ({client_ip_addr}): {error:?}");
+            if let AccountError::AlreadyRegistered(_) = error {
+                let _ = ws_stream.close(Some(policy_close_frame(&error))).await;
+                return;
+            }
+        }
+        let role = shared_state
+            .moderation
+            .lock()
+            .await
+            .role(&username, is_account);
+        let mut connected_users_lock = shared_state.connected_users.lock().await;
+        // Other handshakes ran alongside this one and may have taken
+        // the name or the last free slot in the meantime
+        let username_skeleton = confusable_skeleton(&username);
+        let maybe_name_holder = connected_users_lock
+            .iter()
+            .find(|(_, user)| confusable_skeleton(&user.name) == username_skeleton);
+        let maybe_name_holder_addr =
+            maybe_name_holder.map(|(name_holder_addr, _)| *name_holder_addr);
+        // Only the session's own connection is stale, anyone else holding
+        // the name took it over after the token was issued
+        let maybe_stale_socket_addr = maybe_name_holder
+            .filter(|(_, name_holder)| is_resumed && name_holder.session_id == session_id)
+            .map(|(name_holder_addr, _)| *name_holder_addr);
+        // Someone else may have joined with the name since the handshake checked
+        if is_resumed && !session_signer.is_name_holder(&username, session_id) {
+            info!("{username} could not resume from {client_ip_addr}, the name was taken over");
+            let _ = ws_stream
+                .close(Some(policy_close_frame(
+                    &"Your session was taken over, sign in again",
+                )))
+                .await;
+            return;
+        }
+        let online_ip_addrs: Vec<IpAddr> = connected_users_lock
+            .iter()
+            .filter(|(socket_addr, _)| Some(**socket_addr) != maybe_stale_socket_addr)
+            .map(|(_, user)| user.ip_addr)
+            .collect();
+        let join_result = if maybe_name_holder_addr.is_some() && maybe_stale_socket_addr.is_none() {
+            Err(UsernameError::Taken(username.clone()).to_string())
+        } else {
+            check_capacity(config, &online_ip_addrs, client_ip_addr)
+                .map_err(|error| error.to_string())
+        };
+        if let Err(reason) = join_result {
+            info!("{username} could not join from {client_ip_addr}: {reason}");
+            let _ = ws_stream.close(Some(policy_close_frame(&reason))).await;
+            return;
+        }
+        // The session moves to this connection, so nobody is told it left or joined
+        if let Some(stale_socket_addr) = maybe_stale_socket_addr
+            && let Some(mut stale_user) = connected_users_lock.remove(&stale_socket_addr)
+        {
+            info!("{username} resumed, closing its old connection from {stale_socket_addr}");
+            stale_user.close("You signed in again elsewhere").await;
+        }
 
-                        let handler = Self::accept_connection(
-                            stream,
-                            client_socket_addr,
-                            self.connected_users.clone(),
-                        );
-                        // TODO: Add some sort of 'annoucement' to other users that
-                        // somebody joined
-                        info!("User handshake complete for: {client_socket_addr}");
-                        tokio::spawn(handler);
-                    }
+        match connected_users_lock.entry(client_socket_addr) {
+            Entry::Occupied(_) => {
+                info!("New websocket connection denied: {client_ip_addr} ({client_socket_addr})");
+                debug!("User is already connected from this IP");
+                //TODO: Send announcemnt from the server
+                let _ = ws_stream.close(None).await;
+            }
+            Entry::Vacant(vacant_entry) => {
+                // Splitting into read and write portions of the connections,
+                // move the readable to the spawned handler as it is not needed for
+                // anything else, while the writeable to the map of users
+                let (sink, stream) = ws_stream.split();
+                session_signer.hold_name(&username, session_id);
+
+                let mut message_log_lock = shared_state.message_log.lock().await;
+                let is_new_join = maybe_stale_socket_addr.is_none();
+                let maybe_join_announcement = is_new_join.then(|| {
+                    message_log_lock.record(
+                        ServerMessage::server_announcement(MessageContents::Text(format!(
+                            "{username} has joined"
+                        ))),
+                        Audience::EveryoneBut(username.clone()),
+                    )
+                });
+                let new_user = vacant_entry.insert(User {
+                    name: username,
+                    writable_message_sink: Arc::new(Mutex::new(sink)),
+                    wire_format,
+                    capabilities,
+                    role,
+                    is_account,
+                    ip_addr: client_ip_addr,
+                    session_id,
+                });
+
+                // Catch up on what was sent during the handshake, or
+                // since the last message received when resuming
+                let replay_since = maybe_resumed_sequence.unwrap_or(last_sequence);
+                if !message_log_lock.can_replay_since(replay_since) {
+                    new_user
+                        .send(&ServerMessage::server_announcement(MessageContents::Text(
+                            String::from("Some messages were sent too long ago to be replayed"),
+                        )))
+                        .await;
+                }
+                for missed_message in message_log_lock.missed_messages(&new_user.name, replay_since)
+                {
+                    new_user.send(&missed_message).await;
+                }
+                drop(message_log_lock);
+                if let Some(topic) = shared_state.topic.lock().await.as_deref() {
+                    new_user
+                        .send(&announcement(format!("Topic: {topic}")))
+                        .await;
+                }
+
+                if let Some(join_announcement) = &maybe_join_announcement {
+                    broadcast(
+                        &mut connected_users_lock,
+                        join_announcement,
+                        Some(client_socket_addr),
+                    )
+                    .await;
+                }
+                // Everyone including the new user gets the updated list
+                let user_list = ServerMessage::user_list(online_users(&connected_users_lock));
+                broadcast(&mut connected_users_lock, &user_list, None).await;
+                if is_new_join
+                    && let Some(joined_name) = connected_users_lock
+                        .get(&client_socket_addr)
+                        .map(|user| user.name.clone())
+                {
+                    queue_script_event(
+                        shared_state.maybe_script_events.as_ref(),
+                        ScriptEvent::Join {
+                            username: joined_name,
+                        },
+                    );
                 }
-                Err(_) => continue, // Don't really care why the handshake failed
+                // Early drop since it is not used anywhere else after
+                drop(connected_users_lock);
+
+                let handler = Self::accept_connection(
+                    stream,
+                    client_socket_addr,
+                    client_ip_addr,
+                    wire_format,
+                    protocol_version,
+                    shared_state.clone(),
+                );
+                info!("User handshake complete for: {client_ip_addr}");
+                tokio::spawn(handler);
             }
         }
-
-        Ok(())
     }
 }
@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{config::ServerConfig, error::SessionError, username::confusable_skeleton};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a session token vouches for
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SessionClaims {
+    pub(crate) username: String,
+    /// Signed in to an account rather than joined as a guest
+    pub(crate) is_account: bool,
+    /// The account's token generation when the token was issued, always 0 for guests
+    pub(crate) token_generation: u64,
+    /// Made up for every new sign in and kept when resuming, so the token
+    /// can't resume the session of whoever used the name after it
+    pub(crate) session_id: u64,
+    /// Seconds since the Unix epoch
+    expires_at: u64,
+}
+
+/// # Session Signer
+/// Issues and checks the tokens clients present to resume their session.
+/// A token is its claims and their HMAC-SHA256, both base64 encoded and
+/// joined by a `.`. The key is made up when the server starts, so tokens stop
+/// working on a restart along with the messages they would have replayed.
+/// Only the session that last joined with a name can be resumed
+pub(crate) struct SessionSigner {
+    key: [u8; 32],
+    session_lifetime: Duration,
+    /// Session that last joined with each name, by the skeleton of the name
+    name_holders: Mutex<HashMap<String, NameHolder>>,
+}
+
+struct NameHolder {
+    session_id: u64,
+    joined_at: Instant,
+}
+
+impl SessionSigner {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            key,
+            session_lifetime: config.session_lifetime,
+            name_holders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn issue(
+        &self,
+        username: &str,
+        is_account: bool,
+        token_generation: u64,
+        session_id: u64,
+    ) -> String {
+        let claims = SessionClaims {
+            username: username.to_string(),
+            is_account,
+            token_generation,
+            session_id,
+            expires_at: unix_time(SystemTime::now() + self.session_lifetime),
+        };
+        self.sign(&claims)
+    }
+
+    fn sign(&self, claims: &SessionClaims) -> String {
+        let payload = BASE64_URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(claims).expect("session claims to serialize"));
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Claims of a token this server issued that hasn't expired yet
+    pub(crate) fn verify(&self, session_token: &str) -> Result<SessionClaims, SessionError> {
+        let (payload, signature) = session_token
+            .split_once('.')
+            .ok_or(SessionError::Malformed)?;
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| SessionError::Malformed)?;
+        // Compared in constant time
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| SessionError::BadSignature)?;
+
+        let claims: SessionClaims = BASE64_URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or(SessionError::Malformed)?;
+        if claims.expires_at < unix_time(SystemTime::now()) {
+            return Err(SessionError::Expired);
+        }
+        Ok(claims)
+    }
+
+    /// Whether the session is the last one that joined with `username`
+    pub(crate) fn is_name_holder(&self, username: &str, session_id: u64) -> bool {
+        self.name_holders
+            .lock()
+            .expect("name holders lock to not be poisoned")
+            .get(&confusable_skeleton(username))
+            .is_some_and(|name_holder| name_holder.session_id == session_id)
+    }
+
+    /// Remember the session joined with `username`, after which no other
+    /// session's token can resume with it. Names nobody joined with for a
+    /// whole session lifetime are forgotten, every token for them has
+    /// expired by then
+    pub(crate) fn hold_name(&self, username: &str, session_id: u64) {
+        let mut name_holders_lock = self
+            .name_holders
+            .lock()
+            .expect("name holders lock to not be poisoned");
+        name_holders_lock
+            .retain(|_, name_holder| name_holder.joined_at.elapsed() <= self.session_lifetime);
+        name_holders_lock.insert(
+            confusable_skeleton(username),
+            NameHolder {
+                session_id,
+                joined_at: Instant::now(),
+            },
+        );
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC to take keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> SessionSigner {
+        let config: ServerConfig = toml::from_str("").expect("empty config to use the defaults");
+        SessionSigner::new(&config)
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let signer = signer();
+        let claims = signer
+            .verify(&signer.issue("alice", true, 3, 42))
+            .expect("token to verify");
+        assert_eq!(claims.username, "alice");
+        assert!(claims.is_account);
+        assert_eq!(claims.token_generation, 3);
+        assert_eq!(claims.session_id, 42);
+    }
+
+    #[test]
+    fn refuses_tampered_claims() {
+        let signer = signer();
+        let session_token = signer.issue("alice", false, 0, 42);
+        let (_, signature) = session_token.split_once('.').unwrap();
+        let forged_claims = SessionClaims {
+            username: String::from("alice"),
+            is_account: true,
+            token_generation: 0,
+            session_id: 42,
+            expires_at: u64::MAX,
+        };
+        let forged_payload =
+            BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged_claims).unwrap());
+        assert!(matches!(
+            signer.verify(&format!("{forged_payload}.{signature}")),
+            Err(SessionError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn refuses_tokens_of_other_servers() {
+        let session_token = signer().issue("alice", false, 0, 42);
+        assert!(matches!(
+            signer().verify(&session_token),
+            Err(SessionError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn refuses_expired_tokens() {
+        let signer = signer();
+        let expired_claims = SessionClaims {
+            username: String::from("alice"),
+            is_account: false,
+            token_generation: 0,
+            session_id: 42,
+            expires_at: unix_time(SystemTime::now() - Duration::from_secs(60)),
+        };
+        assert!(matches!(
+            signer.verify(&signer.sign(&expired_claims)),
+            Err(SessionError::Expired)
+        ));
+    }
+
+    #[test]
+    fn refuses_malformed_tokens() {
+        let signer = signer();
+        for session_token in ["", "no dot", "payload.not base64!", "."] {
+            assert!(
+                matches!(
+                    signer.verify(session_token),
+                    Err(SessionError::Malformed | SessionError::BadSignature)
+                ),
+                "{session_token:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn only_resumes_the_last_session_to_hold_a_name() {
+        let signer = signer();
+        signer.hold_name("bob", 1);
+        assert!(signer.is_name_holder("bob", 1));
+        signer.hold_name("bob", 2);
+        assert!(!signer.is_name_holder("bob", 1));
+        assert!(signer.is_name_holder("bob", 2));
+        assert!(!signer.is_name_holder("alice", 2));
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use sha2::{Digest, Sha256};
+use shared_types::transfer::{ChunkHeader, FileChunk, TransferId};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+use crate::{
+    config::ServerConfig,
+    error::{StorageError, TransferError},
+};
+
+/// Partial transfers nobody sent a chunk for in this long are dropped,
+/// which is also how cancelled uploads get cleaned up
+const STALE_TRANSFER_TIMEOUT: Duration = Duration::from_secs(600);
+/// Directory in the file storage directory unfinished uploads are written to
+const PARTIAL_TRANSFERS_DIRNAME: &str = "partial";
+
+/// A file being reassembled from its chunks, kept across reconnects of
+/// the uploader so the transfer can be resumed. Chunks are written to a
+/// temporary file as they arrive rather than held in memory
+struct PartialTransfer {
+    owner: String,
+    filename: String,
+    /// Name the file is announced under, as the message filters left it
+    shown_filename: String,
+    total_size: u64,
+    next_chunk_index: u64,
+    received_bytes: u64,
+    partial_file: File,
+    partial_path: PathBuf,
+    hasher: Sha256,
+    last_chunk_at: Instant,
+}
+
+impl PartialTransfer {
+    fn matches(&self, owner: &str, header: &ChunkHeader) -> bool {
+        self.owner == owner
+            && self.filename == header.filename
+            && self.total_size == header.total_size
+    }
+}
+
+pub(crate) enum ChunkOutcome {
+    /// Stored the chunk, more are needed to finish the file
+    Received {
+        transfer_id: TransferId,
+        received_bytes: u64,
+    },
+    /// The last chunk arrived and the file matched its hash
+    Complete {
+        transfer_id: TransferId,
+        filename: String,
+        sha256: String,
+        size: u64,
+        /// Where the whole file was written to, for the file store to move
+        /// into place
+        partial_path: PathBuf,
+    },
+}
+
+/// Bytes unfinished uploads will take up once they are done, counted
+/// towards the storage quotas before they are
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct PendingBytes {
+    pub(crate) user: u64,
+    pub(crate) total: u64,
+}
+
+struct RefusedTransfer {
+    reason: String,
+    refused_at: Instant,
+}
+
+/// Every unfinished file transfer on the server
+pub(crate) struct Transfers {
+    partial_dir: PathBuf,
+    max_concurrent_uploads: usize,
+    partial_transfers: HashMap<TransferId, PartialTransfer>,
+    /// Transfers the uploader was told were refused, by uploader and id, so
+    /// the chunks still on their way don't get an answer each
+    refused_transfers: HashMap<(String, TransferId), RefusedTransfer>,
+}
+
+impl Transfers {
+    /// Create the directory unfinished uploads are written to, clearing out
+    /// whatever the server left there last time since those transfers can't
+    /// be resumed anymore
+    pub(crate) async fn open(config: &ServerConfig) -> Result<Self, StorageError> {
+        let partial_dir = config.file_storage_dir.join(PARTIAL_TRANSFERS_DIRNAME);
+        match tokio::fs::remove_dir_all(&partial_dir).await {
+            Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        tokio::fs::create_dir_all(&partial_dir).await?;
+        Ok(Self {
+            partial_dir,
+            max_concurrent_uploads: config.max_concurrent_uploads,
+            partial_transfers: HashMap::new(),
+            refused_transfers: HashMap::new(),
+        })
+    }
+
+    /// Remember the uploader was told the transfer was refused
+    pub(crate) fn refuse(&mut self, owner: &str, transfer_id: TransferId, reason: String) {
+        let refused_transfer = RefusedTransfer {
+            reason,
+            refused_at: Instant::now(),
+        };
+        self.refused_transfers
+            .insert((owner.to_string(), transfer_id), refused_transfer);
+    }
+
+    /// Why the transfer was refused, `None` if it wasn't
+    pub(crate) fn refusal_reason(&self, owner: &str, transfer_id: TransferId) -> Option<&str> {
+        self.refused_transfers
+            .get(&(owner.to_string(), transfer_id))
+            .map(|refused_transfer| refused_transfer.reason.as_str())
+    }
+
+    /// Whether the first chunk of the transfer was already taken in
+    pub(crate) fn is_started(&self, transfer_id: TransferId) -> bool {
+        self.partial_transfers.contains_key(&transfer_id)
+    }
+
+    /// Declared size of every unfinished upload other than `except_id`,
+    /// both of `owner` and of everyone together. Sizes are declared by the
+    /// clients, so the sums saturate and an overflow goes over any quota
+    pub(crate) fn pending_bytes(&self, owner: &str, except_id: TransferId) -> PendingBytes {
+        self.partial_transfers
+            .iter()
+            .filter(|(transfer_id, _)| **transfer_id != except_id)
+            .fold(PendingBytes::default(), |pending_bytes, (_, transfer)| {
+                let owner_bytes = match transfer.owner == owner {
+                    true => transfer.total_size,
+                    false => 0,
+                };
+                PendingBytes {
+                    user: pending_bytes.user.saturating_add(owner_bytes),
+                    total: pending_bytes.total.saturating_add(transfer.total_size),
+                }
+            })
+    }
+
+    /// Add the chunk to its transfer. Chunks must arrive in order, although
+    /// chunks that were already stored are accepted again after a resume.
+    /// A new transfer is announced under `maybe_shown_filename` when given
+    pub(crate) async fn receive_chunk(
+        &mut self,
+        owner: &str,
+        file_chunk: FileChunk,
+        maybe_shown_filename: Option<String>,
+    ) -> Result<ChunkOutcome, TransferError> {
+        self.remove_stale().await;
+
+        let FileChunk { header, data } = file_chunk;
+        let transfer_id = header.transfer_id;
+        if data.len() as u64 != header.expected_data_len() {
+            return Err(TransferError::ChunkSize);
+        }
+
+        let owner_transfers = self
+            .partial_transfers
+            .values()
+            .filter(|transfer| transfer.owner == owner)
+            .count();
+        if !self.partial_transfers.contains_key(&transfer_id)
+            && owner_transfers >= self.max_concurrent_uploads
+        {
+            return Err(TransferError::TooManyTransfers {
+                max: self.max_concurrent_uploads,
+            });
+        }
+
+        let transfer = match self.partial_transfers.entry(transfer_id) {
+            Entry::Occupied(occupied_entry) => occupied_entry.into_mut(),
+            Entry::Vacant(vacant_entry) if header.chunk_index == 0 => {
+                // Named by the transfer id so the client's filename never
+                // becomes part of a path
+                let partial_path = self.partial_dir.join(transfer_id.to_string());
+                vacant_entry.insert(PartialTransfer {
+                    owner: owner.to_string(),
+                    filename: header.filename.clone(),
+                    shown_filename: maybe_shown_filename.unwrap_or_else(|| header.filename.clone()),
+                    total_size: header.total_size,
+                    next_chunk_index: 0,
+                    received_bytes: 0,
+                    partial_file: File::create(&partial_path).await?,
+                    partial_path,
+                    hasher: Sha256::new(),
+                    last_chunk_at: Instant::now(),
+                })
+            }
+            Entry::Vacant(_) => return Err(TransferError::UnknownTransfer),
+        };
+        // Nobody gets to add to someone else's file
+        if !transfer.matches(owner, &header) {
+            return Err(TransferError::MetadataMismatch);
+        }
+
+        if header.chunk_index < transfer.next_chunk_index {
+            return Ok(ChunkOutcome::Received {
+                transfer_id,
+                received_bytes: transfer.received_bytes,
+            });
+        }
+        if header.chunk_index > transfer.next_chunk_index {
+            let missing_chunk = TransferError::MissingChunk {
+                expected: transfer.next_chunk_index,
+                received: header.chunk_index,
+            };
+            self.remove(&transfer_id).await;
+            return Err(missing_chunk);
+        }
+
+        if let Err(error) = transfer.partial_file.write_all(&data).await {
+            self.remove(&transfer_id).await;
+            return Err(error.into());
+        }
+        transfer.hasher.update(&data);
+        transfer.received_bytes += data.len() as u64;
+        transfer.next_chunk_index += 1;
+        transfer.last_chunk_at = Instant::now();
+        if !header.is_last_chunk() {
+            return Ok(ChunkOutcome::Received {
+                transfer_id,
+                received_bytes: transfer.received_bytes,
+            });
+        }
+
+        let Some(mut transfer) = self.partial_transfers.remove(&transfer_id) else {
+            return Err(TransferError::UnknownTransfer);
+        };
+        let sha256 = format!("{:x}", transfer.hasher.finalize());
+        if header.sha256.as_ref() != Some(&sha256) {
+            remove_partial_file(&transfer.partial_path).await;
+            return Err(TransferError::HashMismatch);
+        }
+        if let Err(error) = transfer.partial_file.flush().await {
+            remove_partial_file(&transfer.partial_path).await;
+            return Err(error.into());
+        }
+        Ok(ChunkOutcome::Complete {
+            transfer_id,
+            filename: transfer.shown_filename,
+            sha256,
+            size: transfer.received_bytes,
+            partial_path: transfer.partial_path,
+        })
+    }
+
+    async fn remove(&mut self, transfer_id: &TransferId) {
+        if let Some(transfer) = self.partial_transfers.remove(transfer_id) {
+            remove_partial_file(&transfer.partial_path).await;
+        }
+    }
+
+    async fn remove_stale(&mut self) {
+        let stale_transfer_ids: Vec<TransferId> = self
+            .partial_transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.last_chunk_at.elapsed() >= STALE_TRANSFER_TIMEOUT)
+            .map(|(transfer_id, _)| *transfer_id)
+            .collect();
+        for transfer_id in stale_transfer_ids {
+            debug!("Dropping stale file transfer {transfer_id}");
+            self.remove(&transfer_id).await;
+        }
+        self.refused_transfers.retain(|_, refused_transfer| {
+            refused_transfer.refused_at.elapsed() < STALE_TRANSFER_TIMEOUT
+        });
+    }
+}
+
+/// Only logged, a leftover partial file is cleared out on the next start
+pub(crate) async fn remove_partial_file(partial_path: &Path) {
+    if let Err(error) = tokio::fs::remove_file(partial_path).await
+        && error.kind() != ErrorKind::NotFound
+    {
+        warn!(
+            "Could not remove the partial upload {}: {error:?}",
+            partial_path.display()
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+use crate::{config::ServerConfig, error::UsernameError};
+
+/// Kinds of characters a username may be made of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CharacterClass {
+    /// Letters of any script
+    Letters,
+    /// Digits of any script
+    Digits,
+    /// Spaces between words, never at the start or end
+    Spaces,
+    /// ASCII punctuation such as `!` or `#`
+    Punctuation,
+}
+
+impl CharacterClass {
+    fn contains(self, character: char) -> bool {
+        match self {
+            CharacterClass::Letters => character.is_alphabetic(),
+            CharacterClass::Digits => character.is_numeric(),
+            CharacterClass::Spaces => character == ' ',
+            CharacterClass::Punctuation => character.is_ascii_punctuation(),
+        }
+    }
+}
+
+/// NFC normalize so the same name typed on different systems is stored the same way
+pub(crate) fn normalize_username(username: &str) -> String {
+    username.nfc().collect()
+}
+
+/// What a username looks like, used to compare them. Case is ignored and
+/// characters that look alike map to the same one, so `Alice` with a
+/// Cyrillic `А` is the same as `alice`
+pub(crate) fn confusable_skeleton(username: &str) -> String {
+    skeleton(&username.to_lowercase())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Check a normalized username against the configured rules and the
+/// skeletons of everyone already connected
+pub(crate) fn validate_username(
+    username: &str,
+    config: &ServerConfig,
+    taken_skeletons: &HashSet<String>,
+) -> Result<(), UsernameError> {
+    let length = username.chars().count();
+    if length == 0 {
+        return Err(UsernameError::Missing);
+    }
+    if length < config.username_min_length {
+        return Err(UsernameError::TooShort(config.username_min_length));
+    }
+    if length > config.username_max_length {
+        return Err(UsernameError::TooLong(config.username_max_length));
+    }
+
+    if let Some(disallowed_character) = username.chars().find(|character| {
+        !config.username_extra_characters.contains(*character)
+            && !config
+                .username_characters
+                .iter()
+                .any(|character_class| character_class.contains(*character))
+    }) {
+        return Err(UsernameError::DisallowedCharacter(disallowed_character));
+    }
+    if username.starts_with(' ') || username.ends_with(' ') {
+        return Err(UsernameError::SurroundingSpace);
+    }
+
+    let username_skeleton = confusable_skeleton(username);
+    if config
+        .reserved_usernames
+        .iter()
+        .any(|reserved_username| confusable_skeleton(reserved_username) == username_skeleton)
+    {
+        return Err(UsernameError::Reserved(username.to_string()));
+    }
+    if taken_skeletons.contains(&username_skeleton) {
+        return Err(UsernameError::Taken(username.to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! Compares sending file contents as base64 inside JSON text, the format
+//! used before file contents moved to binary frames, against the binary
+//! frames sent now. Measures the round trip the server and client make for
+//! every file: encoding the message and decoding it on the other end.
+//!
+//! Run with `cargo bench -p shared_types --bench file_frames`
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use base64::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shared_types::{
+    messages::{Frame, MessageContents, ServerMessage},
+    wire_format::WireFormat,
+};
+
+const FILE_SIZES: [usize; 4] = [16 * 1024, 256 * 1024, 4 * 1024 * 1024, 32 * 1024 * 1024];
+/// Each format is repeated until at least this much time has passed
+const MEASUREMENT_TIME: Duration = Duration::from_secs(2);
+
+/// Keeps track of the most memory allocated at once so the peak of a
+/// round trip can be compared between the formats
+struct PeakAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAllocator = PeakAllocator;
+
+/// The message as it used to be sent, with the contents inside the JSON
+#[derive(Serialize, Deserialize)]
+struct Base64ServerMessage {
+    author: String,
+    contents: Base64MessageContents,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Base64MessageContents {
+    FileContents {
+        sha256: String,
+        #[serde(serialize_with = "serialize_base64")]
+        #[serde(deserialize_with = "deserialize_base64")]
+        contents: Vec<u8>,
+    },
+}
+
+fn serialize_base64<S: Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    String::serialize(&BASE64_STANDARD.encode(v), s)
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let base64 = String::deserialize(d)?;
+    BASE64_STANDARD
+        .decode(base64.as_bytes())
+        .map_err(serde::de::Error::custom)
+}
+
+/// What is measured for each format, returns the size of what went over the wire
+type RoundTrip = fn(&[u8]) -> usize;
+
+fn base64_json_round_trip(file_contents: &[u8]) -> usize {
+    let message = Base64ServerMessage {
+        author: String::from("Server"),
+        contents: Base64MessageContents::FileContents {
+            sha256: String::from("0").repeat(64),
+            contents: file_contents.to_vec(),
+        },
+    };
+    let sent = serde_json::to_string(&message).expect("message to serialize");
+    let received: Base64ServerMessage = serde_json::from_str(&sent).expect("message to parse");
+    black_box(received);
+    sent.len()
+}
+
+fn binary_frame_round_trip(file_contents: &[u8]) -> usize {
+    let message = ServerMessage::file_contents(
+        String::from("0").repeat(64),
+        0,
+        file_contents.len() as u64,
+        file_contents,
+    );
+    let Ok(Frame::Binary(sent)) = message.encode(WireFormat::Json) else {
+        panic!("file contents to be sent as a binary frame");
+    };
+    let received = ServerMessage::decode_binary(WireFormat::Json, &sent).expect("frame to decode");
+    assert!(matches!(
+        received.contents,
+        MessageContents::FileContents { ref contents, .. } if contents.len() == file_contents.len()
+    ));
+    black_box(received);
+    sent.len()
+}
+
+fn measure(name: &str, round_trip: RoundTrip, file_contents: &[u8]) {
+    // The peak is measured on its own run so the timing loop doesn't affect it
+    let allocated_before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK_ALLOCATED.store(allocated_before, Ordering::Relaxed);
+    let sent_bytes = round_trip(file_contents);
+    let peak_memory = PEAK_ALLOCATED.load(Ordering::Relaxed) - allocated_before;
+
+    let mut iterations = 0;
+    let started_at = Instant::now();
+    while started_at.elapsed() < MEASUREMENT_TIME {
+        round_trip(black_box(file_contents));
+        iterations += 1;
+    }
+    let time_per_iteration = started_at.elapsed() / iterations;
+    let throughput = file_contents.len() as f64 / time_per_iteration.as_secs_f64() / 1_048_576.0;
+
+    println!(
+        "  {name:<12} {throughput:>9.1} MiB/s {:>12?}/file  sent {:>6.2}x  peak memory {:>6.2}x",
+        time_per_iteration,
+        sent_bytes as f64 / file_contents.len() as f64,
+        peak_memory as f64 / file_contents.len() as f64,
+    );
+}
+
+fn main() {
+    // Sizes and peak memory are relative to the size of the file
+    for file_size in FILE_SIZES {
+        let file_contents: Vec<u8> = (0..file_size).map(|i| (i % 251) as u8).collect();
+        println!("{} KiB file", file_size / 1024);
+        measure("base64 JSON", base64_json_round_trip, &file_contents);
+        measure("binary frame", binary_frame_round_trip, &file_contents);
+    }
+}
@@ -1,6 +1,11 @@
-mod base64_serialize;
 /// # Crypt Constants
 ///
 /// Constants used by the server and client to verify the validity of the password
 pub mod crypt;
 pub mod messages;
+/// # Protocol Constants
+///
+/// Version and capability headers exchanged during the handshake
+pub mod protocol;
+pub mod transfer;
+pub mod wire_format;
@@ -1,21 +1,205 @@
-use crate::base64_serialize;
+use crate::{
+    protocol::Capability,
+    transfer::{ChunkHeader, FrameDecodeError, TransferId, decode_frame, encode_frame},
+    wire_format::{DecodeError, EncodeError, WireFormat},
+};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// Prefix of a text message the server should deliver to a single user
+/// instead of the whole chat, sent as `/msg <recipient> <text>`
+pub const DIRECT_MESSAGE_COMMAND: &str = "/msg";
+
+/// Prefix of a text message asking the server to disconnect a user, sent as
+/// `/kick <user> [reason]` by moderators and admins
+pub const KICK_COMMAND: &str = "/kick";
+
+/// How a user is named in the arguments of a command such as `/msg` or
+/// `/kick`, in double quotes when the name has spaces in it
+pub fn command_target(username: &str) -> Cow<'_, str> {
+    if username.contains(' ') {
+        Cow::Owned(format!("\"{username}\""))
+    } else {
+        Cow::Borrowed(username)
+    }
+}
+
+/// Prefix of a text message asking the server for the contents of a stored
+/// file, sent as `/download <sha256>`
+pub const DOWNLOAD_COMMAND: &str = "/download";
+
+/// Text messages starting with this are commands for the server rather than
+/// chat, doubling it sends chat that starts with a single one
+pub const COMMAND_PREFIX: char = '/';
+
+/// Chat message a text message stands for, `None` if it is a command
+pub fn chat_text(text: &str) -> Option<&str> {
+    match text.strip_prefix(COMMAND_PREFIX) {
+        Some(escaped_text) if escaped_text.starts_with(COMMAND_PREFIX) => Some(escaped_text),
+        Some(_) => None,
+        None => Some(text),
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum MessageContents {
     Text(String),
 
-    File {
-        name: String,
-        #[serde(with = "base64_serialize")]
+    /// A file was uploaded, its contents are only sent on request
+    File(FileMetadata),
+
+    /// Part of the contents of a stored file starting at `offset`, sent only
+    /// to the user who asked for them. A file arrives in pieces of at most
+    /// [crate::transfer::CHUNK_SIZE] bytes, in order, until `total_size` is
+    /// reached. Goes out as a binary frame with the contents after the header
+    /// rather than inside it, see [ServerMessage::encode]
+    FileContents {
+        sha256: String,
+        /// Servers from before downloads were split send the whole file at once
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        total_size: u64,
+        #[serde(skip)]
         contents: Vec<u8>,
     },
+
+    /// Sent only to the user who asked for a file the server couldn't send,
+    /// including partway through sending it
+    DownloadFailed {
+        sha256: String,
+        reason: String,
+    },
+
+    /// Text only delivered to `recipient`
+    DirectMessage {
+        recipient: String,
+        text: String,
+    },
+
+    /// Everyone currently connected, sent by the server whenever
+    /// somebody joins or leaves
+    UserList(Vec<OnlineUser>),
+
+    /// Sent only to the uploader after every stored chunk so it knows
+    /// where to resume from if the connection drops
+    TransferProgress {
+        transfer_id: TransferId,
+        received_bytes: u64,
+    },
+
+    /// Sent only to the uploader once the server gave up on a transfer,
+    /// any chunks still on their way are dropped without an answer
+    TransferRefused {
+        transfer_id: TransferId,
+        reason: String,
+    },
+}
+
+impl MessageContents {
+    /// Capability a client needs to be sent this, `None` if every client understands it
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            MessageContents::File(_)
+            | MessageContents::FileContents { .. }
+            | MessageContents::DownloadFailed { .. }
+            | MessageContents::TransferProgress { .. }
+            | MessageContents::TransferRefused { .. } => Some(Capability::Files),
+            _ => None,
+        }
+    }
+}
+
+/// Everything about a stored file other than its contents
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FileMetadata {
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
+    pub mime_type: String,
+    /// Hex encoded SHA-256 of the contents, used to download the file
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OnlineUser {
+    pub name: String,
+    /// Servers from before roles only list users
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// What a user is allowed to do, ordered from least to most
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    #[default]
+    User,
+    /// May kick and mute users
+    Moderator,
+    /// May also ban users
+    Admin,
+}
+
+/// Everything a client sends the server, encoded in the agreed wire format
+/// from [crate::protocol::ENCODED_REQUESTS_PROTOCOL_VERSION] on. Clients
+/// before that send text as raw text frames, direct messages and downloads
+/// as commands, and file chunks as bare [crate::transfer::FileChunk] frames
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ClientRequest {
+    /// Chat, or a command for the server
+    Text(String),
+
+    /// Text only delivered to `recipient`
+    DirectMessage { recipient: String, text: String },
+
+    /// Ask for the contents of the stored file with this SHA-256
+    DownloadFile { sha256: String },
+
+    /// Part of an upload. Goes out as a binary frame with the chunk's data
+    /// after the header, see [ClientRequest::encode]
+    FileChunk(ChunkHeader),
+}
+
+impl ClientRequest {
+    /// Encode the request for a server that agreed on `wire_format`. With
+    /// JSON only file chunks are sent as binary frames, `data` is ignored
+    /// for anything else
+    pub fn encode(&self, wire_format: WireFormat, data: &[u8]) -> Result<Frame, EncodeError> {
+        Ok(match self {
+            ClientRequest::FileChunk(_) => Frame::Binary(encode_frame(wire_format, self, data)?),
+            _ if wire_format.is_binary() => Frame::Binary(encode_frame(wire_format, self, &[])?),
+            _ => Frame::Text(serde_json::to_string(self)?),
+        })
+    }
+
+    pub fn decode_text(text: &str) -> Result<Self, DecodeError> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    /// The request in a binary frame, along with the data of a file chunk
+    pub fn decode_binary(
+        wire_format: WireFormat,
+        frame: &[u8],
+    ) -> Result<(Self, &[u8]), FrameDecodeError> {
+        decode_frame(wire_format, frame)
+    }
+}
+
+/// An encoded message, ready to be sent as a single WebSocket frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ServerMessage {
     pub author: String,
     pub contents: MessageContents,
+    /// Order the server sent the message in, only set on chat messages it can
+    /// replay to a client resuming its session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
 }
 
 impl ServerMessage {
@@ -24,21 +208,86 @@ impl ServerMessage {
         Self {
             author: author.to_string(),
             contents: MessageContents::Text(contents.to_string()),
+            sequence: None,
         }
     }
 
     #[inline]
-    pub fn file(
-        author: impl ToString,
-        filename: impl ToString,
+    pub fn file(author: impl ToString, file_metadata: FileMetadata) -> Self {
+        Self {
+            author: author.to_string(),
+            contents: MessageContents::File(file_metadata),
+            sequence: None,
+        }
+    }
+
+    #[inline]
+    pub fn file_contents(
+        sha256: impl ToString,
+        offset: u64,
+        total_size: u64,
         file_contents: impl Into<Vec<u8>>,
+    ) -> Self {
+        Self::server_announcement(MessageContents::FileContents {
+            sha256: sha256.to_string(),
+            offset,
+            total_size,
+            contents: file_contents.into(),
+        })
+    }
+
+    #[inline]
+    pub fn download_failed(sha256: impl ToString, reason: impl ToString) -> Self {
+        Self::server_announcement(MessageContents::DownloadFailed {
+            sha256: sha256.to_string(),
+            reason: reason.to_string(),
+        })
+    }
+
+    #[inline]
+    pub fn direct_message(
+        author: impl ToString,
+        recipient: impl ToString,
+        contents: impl ToString,
     ) -> Self {
         Self {
             author: author.to_string(),
-            contents: MessageContents::File {
-                name: filename.to_string(),
-                contents: file_contents.into(),
+            contents: MessageContents::DirectMessage {
+                recipient: recipient.to_string(),
+                text: contents.to_string(),
             },
+            sequence: None,
+        }
+    }
+
+    #[inline]
+    pub fn user_list(online_users: Vec<OnlineUser>) -> Self {
+        Self::server_announcement(MessageContents::UserList(online_users))
+    }
+
+    #[inline]
+    pub fn transfer_progress(transfer_id: TransferId, received_bytes: u64) -> Self {
+        Self::server_announcement(MessageContents::TransferProgress {
+            transfer_id,
+            received_bytes,
+        })
+    }
+
+    #[inline]
+    pub fn transfer_refused(transfer_id: TransferId, reason: impl ToString) -> Self {
+        Self::server_announcement(MessageContents::TransferRefused {
+            transfer_id,
+            reason: reason.to_string(),
+        })
+    }
+
+    /// Message authored by the server itself rather than a user
+    #[inline]
+    pub fn server_announcement(contents: MessageContents) -> Self {
+        Self {
+            author: String::from("Server"),
+            contents,
+            sequence: None,
         }
     }
 
@@ -46,6 +295,28 @@ impl ServerMessage {
         ServerMessage {
             author: String::from("Server"),
             contents: MessageContents::Text(String::from("You have been disconnected...")),
+            sequence: None,
+        }
+    }
+
+    /// Encode the message for a client that agreed on `wire_format`. With
+    /// JSON only messages carrying file contents are sent as binary frames,
+    /// so the contents aren't inflated by a text encoding
+    pub fn encode(&self, wire_format: WireFormat) -> Result<Frame, EncodeError> {
+        Ok(match &self.contents {
+            MessageContents::FileContents { contents, .. } => {
+                Frame::Binary(encode_frame(wire_format, self, contents)?)
+            }
+            _ if wire_format.is_binary() => Frame::Binary(encode_frame(wire_format, self, &[])?),
+            _ => Frame::Text(serde_json::to_string(self)?),
+        })
+    }
+
+    pub fn decode_binary(wire_format: WireFormat, frame: &[u8]) -> Result<Self, FrameDecodeError> {
+        let (mut message, data): (Self, &[u8]) = decode_frame(wire_format, frame)?;
+        if let MessageContents::FileContents { contents, .. } = &mut message.contents {
+            *contents = data.to_vec();
         }
+        Ok(message)
     }
 }
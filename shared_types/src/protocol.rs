@@ -0,0 +1,251 @@
+use std::{collections::BTreeSet, fmt::Display, str::Utf8Error};
+
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode, utf8_percent_encode};
+
+/// ## Username header key
+/// Client sends the name it wants to use, the server answers with the name it
+/// got, which for accounts is spelled the way it was registered. See [encode_header_text]
+pub const USERNAME_KEY: &str = "username";
+
+/// ## Account action header key
+/// Sent by clients signing in to an account, see [AccountAction]. Clients
+/// without it join as guests
+pub const ACCOUNT_ACTION_KEY: &str = "msger_account_action";
+
+/// ## Password header key
+/// Password of the account, see [encode_header_text]
+pub const PASSWORD_KEY: &str = "msger_password";
+
+/// ## New password header key
+/// Replaces the account's password when changing it, see [encode_header_text]
+pub const NEW_PASSWORD_KEY: &str = "msger_new_password";
+
+/// ## Session token header key
+/// The server hands out a token with every handshake, presenting it on a later
+/// handshake resumes the session without signing in again
+pub const SESSION_TOKEN_KEY: &str = "msger_session_token";
+
+/// ## Last sequence header key
+/// Client sends the sequence number of the last message it received when
+/// resuming, the server answers with the sequence number of its latest message.
+/// See [crate::messages::ServerMessage::sequence]
+pub const LAST_SEQUENCE_KEY: &str = "msger_last_sequence";
+
+/// ## Resumed header key
+/// Set by the server when it accepted the session token, the messages missed
+/// since the last sequence follow right after the handshake
+pub const RESUMED_KEY: &str = "msger_resumed";
+
+/// Header values can only hold visible ASCII, anything else in usernames
+/// and passwords is percent encoded
+const HEADER_TEXT_ENCODE_SET: &AsciiSet = &CONTROLS.add(b'%').add(b' ');
+
+/// ## Protocol version header key
+/// Client sends the version it speaks with the handshake, the server answers with the
+/// newest version both of them speak, which the connection uses from then on.
+/// Clients and servers from before the header existed can't understand
+/// either version, so a missing header is refused
+pub const PROTOCOL_VERSION_KEY: &str = "msger_protocol_version";
+
+/// ## Capabilities header key
+/// Client sends every capability it supports, the server answers with the ones both of them support.
+/// A missing header means no capabilities are supported
+pub const CAPABILITIES_KEY: &str = "msger_capabilities";
+
+/// ## Protocol version
+/// Bumped whenever a change means older clients or servers can no longer understand each other
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// ## Minimum protocol version
+/// Oldest version still understood, anything older is refused during the handshake
+pub const MINIMUM_PROTOCOL_VERSION: u32 = 1;
+
+/// ## Encoded requests protocol version
+/// First version whose clients send everything as a
+/// [crate::messages::ClientRequest] in the agreed wire format
+pub const ENCODED_REQUESTS_PROTOCOL_VERSION: u32 = 2;
+
+/// ## Refusal header key
+/// Sent with a refused handshake so the client can tell why without reading the body
+pub const REFUSAL_KEY: &str = "msger_refusal";
+
+/// Why the server refused a handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    InvalidUsername,
+    UsernameTaken,
+    UnsupportedProtocolVersion,
+    UnsupportedWireFormat,
+    /// The server only lets in users with an account
+    AccountRequired,
+    BadCredentials,
+    /// Registering or changing a password did not work out
+    AccountRefused,
+    /// Banned, or not from an address the server admits
+    Forbidden,
+    /// The server has as many users as it allows
+    ServerFull,
+    /// The client's address has as many connections as the server allows
+    TooManyConnections,
+}
+
+impl Refusal {
+    pub const ALL: [Refusal; 10] = [
+        Refusal::InvalidUsername,
+        Refusal::UsernameTaken,
+        Refusal::UnsupportedProtocolVersion,
+        Refusal::UnsupportedWireFormat,
+        Refusal::AccountRequired,
+        Refusal::BadCredentials,
+        Refusal::AccountRefused,
+        Refusal::Forbidden,
+        Refusal::ServerFull,
+        Refusal::TooManyConnections,
+    ];
+
+    /// Value of the refusal header
+    pub fn code(self) -> &'static str {
+        match self {
+            Refusal::InvalidUsername => "invalid_username",
+            Refusal::UsernameTaken => "username_taken",
+            Refusal::UnsupportedProtocolVersion => "unsupported_protocol_version",
+            Refusal::UnsupportedWireFormat => "unsupported_wire_format",
+            Refusal::AccountRequired => "account_required",
+            Refusal::BadCredentials => "bad_credentials",
+            Refusal::AccountRefused => "account_refused",
+            Refusal::Forbidden => "forbidden",
+            Refusal::ServerFull => "server_full",
+            Refusal::TooManyConnections => "too_many_connections",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|refusal| refusal.code() == code)
+    }
+}
+
+/// What a client signing in to an account wants to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountAction {
+    Login,
+    /// Create an account for the username, which nobody else can use afterwards
+    Register,
+    /// Log in and replace the password with the one in the new password header
+    ChangePassword,
+}
+
+impl AccountAction {
+    pub const ALL: [AccountAction; 3] = [
+        AccountAction::Login,
+        AccountAction::Register,
+        AccountAction::ChangePassword,
+    ];
+
+    /// Value of the account action header
+    pub fn code(self) -> &'static str {
+        match self {
+            AccountAction::Login => "login",
+            AccountAction::Register => "register",
+            AccountAction::ChangePassword => "change_password",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.code() == code)
+    }
+}
+
+/// Percent encode text so usernames and passwords in any script fit in a header
+pub fn encode_header_text(text: &str) -> String {
+    utf8_percent_encode(text, HEADER_TEXT_ENCODE_SET).to_string()
+}
+
+pub fn decode_header_text(header_value: &[u8]) -> Result<String, Utf8Error> {
+    percent_decode(header_value)
+        .decode_utf8()
+        .map(|text| text.into_owned())
+}
+
+/// Read the version header, `None` if it is missing or not a number
+pub fn parse_protocol_version(header_value: Option<&str>) -> Option<u32> {
+    header_value?.trim().parse().ok()
+}
+
+pub fn is_supported_protocol_version(protocol_version: u32) -> bool {
+    (MINIMUM_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version)
+}
+
+/// Optional features that only work if both the client and server support them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+    Files,
+    Rooms,
+    History,
+    Encryption,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 4] = [
+        Capability::Files,
+        Capability::Rooms,
+        Capability::History,
+        Capability::Encryption,
+    ];
+
+    /// Name of the capability in the capabilities header
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Files => "files",
+            Capability::Rooms => "rooms",
+            Capability::History => "history",
+            Capability::Encryption => "encryption",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|capability| capability.name() == name.trim())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(BTreeSet<Capability>);
+
+impl Capabilities {
+    /// Read a comma separated capabilities header. Names from newer
+    /// versions that aren't known yet are skipped
+    pub fn from_header_value(header_value: &str) -> Self {
+        header_value
+            .split(',')
+            .filter_map(Capability::from_name)
+            .collect()
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0.contains(&capability)
+    }
+
+    /// Capabilities supported by both sides
+    pub fn intersection(&self, other: &Capabilities) -> Self {
+        self.0.intersection(&other.0).copied().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+/// Formats as the value of the capabilities header
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.iter().map(Capability::name).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
@@ -0,0 +1,169 @@
+//! # Chunked File Transfers
+//!
+//! Files are sent to the server as a series of binary WebSocket frames, each
+//! holding one [FileChunk]. Every chunk repeats the transfer metadata so the
+//! server can pick a transfer back up from any chunk after a reconnect.
+//!
+//! File contents coming back from the server use the same frame layout, see
+//! [encode_frame], so neither side has to encode files as text. Headers are
+//! encoded in the [WireFormat] the client and server agreed on.
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::wire_format::{DecodeError, EncodeError, WireFormat};
+
+/// Picked by the sender and reused when resuming the transfer
+pub type TransferId = Uuid;
+
+/// Bytes of file data in every chunk but the last one
+pub const CHUNK_SIZE: u64 = 262_144; // 256 KB
+
+/// Size of the big endian length that comes before the header in a frame
+const HEADER_LENGTH_BYTES: usize = size_of::<u32>();
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedChunkHeader")]
+pub struct ChunkHeader {
+    pub transfer_id: TransferId,
+    pub filename: String,
+    /// Size of the whole file in bytes
+    pub total_size: u64,
+    pub chunk_index: u64,
+    /// Hex encoded SHA-256 of the whole file, only sent with the last chunk
+    /// since the sender hashes the file while reading it
+    pub sha256: Option<String>,
+}
+
+/// A chunk header as the peer sent it, only decoded into a [ChunkHeader]
+/// once its chunk index is known to be in range
+#[derive(Deserialize)]
+struct UncheckedChunkHeader {
+    transfer_id: TransferId,
+    filename: String,
+    total_size: u64,
+    chunk_index: u64,
+    sha256: Option<String>,
+}
+
+#[derive(Error, Debug)]
+#[error("Chunk {chunk_index} is past the last of the file's {chunk_count} chunks")]
+pub struct ChunkOutOfRange {
+    chunk_index: u64,
+    chunk_count: u64,
+}
+
+impl TryFrom<UncheckedChunkHeader> for ChunkHeader {
+    type Error = ChunkOutOfRange;
+
+    fn try_from(header: UncheckedChunkHeader) -> Result<Self, Self::Error> {
+        let chunk_count = chunk_count(header.total_size);
+        if header.chunk_index >= chunk_count {
+            return Err(ChunkOutOfRange {
+                chunk_index: header.chunk_index,
+                chunk_count,
+            });
+        }
+        Ok(Self {
+            transfer_id: header.transfer_id,
+            filename: header.filename,
+            total_size: header.total_size,
+            chunk_index: header.chunk_index,
+            sha256: header.sha256,
+        })
+    }
+}
+
+impl ChunkHeader {
+    /// Even an empty file is sent as a single empty chunk
+    pub fn chunk_count(&self) -> u64 {
+        chunk_count(self.total_size)
+    }
+
+    pub fn is_last_chunk(&self) -> bool {
+        self.chunk_index
+            .checked_add(1)
+            .is_none_or(|next_chunk_index| next_chunk_index >= self.chunk_count())
+    }
+
+    /// How many bytes of data this chunk should hold, none for chunks past the end
+    pub fn expected_data_len(&self) -> u64 {
+        self.chunk_index
+            .checked_mul(CHUNK_SIZE)
+            .map_or(0, |chunk_start| {
+                CHUNK_SIZE.min(self.total_size.saturating_sub(chunk_start))
+            })
+    }
+}
+
+/// Best guess at the MIME type of a file from its name
+pub fn mime_type(filename: &str) -> String {
+    mime_guess::from_path(filename)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+/// Number of chunks a file of `total_size` bytes is split into
+pub fn chunk_count(total_size: u64) -> u64 {
+    total_size.div_ceil(CHUNK_SIZE).max(1)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChunk {
+    pub header: ChunkHeader,
+    pub data: Vec<u8>,
+}
+
+#[derive(Error, Debug)]
+pub enum FrameDecodeError {
+    #[error("Binary frame was shorter than its header")]
+    Truncated,
+
+    #[error("Could not interpret binary frame header")]
+    ParseHeader(#[from] DecodeError),
+}
+
+/// Lay out a binary frame: the header length as a big endian `u32`, the
+/// encoded header and then the raw data
+pub fn encode_frame(
+    wire_format: WireFormat,
+    header: &impl Serialize,
+    data: &[u8],
+) -> Result<Vec<u8>, EncodeError> {
+    let header = wire_format.encode(header)?;
+    let mut frame = Vec::with_capacity(HEADER_LENGTH_BYTES + header.len() + data.len());
+    frame.extend_from_slice(&(header.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(data);
+    Ok(frame)
+}
+
+/// Split a frame made by [encode_frame] back into its header and data
+pub fn decode_frame<H: DeserializeOwned>(
+    wire_format: WireFormat,
+    frame: &[u8],
+) -> Result<(H, &[u8]), FrameDecodeError> {
+    let (header_length, rest) = frame
+        .split_first_chunk::<HEADER_LENGTH_BYTES>()
+        .ok_or(FrameDecodeError::Truncated)?;
+    let header_length = u32::from_be_bytes(*header_length) as usize;
+    if rest.len() < header_length {
+        return Err(FrameDecodeError::Truncated);
+    }
+    let (header, data) = rest.split_at(header_length);
+    Ok((wire_format.decode(header)?, data))
+}
+
+impl FileChunk {
+    pub fn encode(&self, wire_format: WireFormat) -> Result<Vec<u8>, EncodeError> {
+        encode_frame(wire_format, &self.header, &self.data)
+    }
+
+    pub fn decode(wire_format: WireFormat, frame: &[u8]) -> Result<Self, FrameDecodeError> {
+        let (header, data) = decode_frame(wire_format, frame)?;
+        Ok(Self {
+            header,
+            data: data.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,110 @@
+//! # Wire Formats
+//!
+//! Messages are sent as JSON text unless the client asks for a more compact
+//! encoding through the `Sec-WebSocket-Protocol` header of the handshake.
+//! The client lists the formats it wants in order of preference and the
+//! server answers with the first one it supports. Without the header both
+//! sides stay on JSON, so older clients keep working.
+//!
+//! With MessagePack or CBOR every message is a binary frame, laid out like
+//! the frames of [crate::transfer] with the header in the agreed format.
+use std::io;
+
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+/// Header the formats are negotiated through
+pub const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+#[derive(Error, Debug)]
+pub enum EncodeError {
+    #[error("Could not encode message as JSON")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Could not encode message as MessagePack")]
+    MessagePack(#[from] rmp_serde::encode::Error),
+
+    #[error("Could not encode message as CBOR")]
+    Cbor(#[from] ciborium::ser::Error<io::Error>),
+}
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("Could not interpret JSON message")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Could not interpret MessagePack message")]
+    MessagePack(#[from] rmp_serde::decode::Error),
+
+    #[error("Could not interpret CBOR message")]
+    Cbor(#[from] ciborium::de::Error<io::Error>),
+}
+
+impl WireFormat {
+    pub const ALL: [WireFormat; 3] = [WireFormat::Json, WireFormat::MessagePack, WireFormat::Cbor];
+
+    /// Name of the format in the subprotocol header
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            WireFormat::Json => "msger.json",
+            WireFormat::MessagePack => "msger.msgpack",
+            WireFormat::Cbor => "msger.cbor",
+        }
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|wire_format| wire_format.subprotocol() == subprotocol.trim())
+    }
+
+    /// Header value asking for the formats in order of preference
+    pub fn subprotocol_header(wire_formats: &[WireFormat]) -> String {
+        wire_formats
+            .iter()
+            .map(|wire_format| wire_format.subprotocol())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Pick the first format of a subprotocol header that is supported,
+    /// `None` if none of them are
+    pub fn negotiate(subprotocol_header: &str) -> Option<Self> {
+        subprotocol_header
+            .split(',')
+            .find_map(Self::from_subprotocol)
+    }
+
+    /// Non JSON formats can't be sent as text frames
+    pub fn is_binary(self) -> bool {
+        self != WireFormat::Json
+    }
+
+    pub fn encode(self, value: &impl Serialize) -> Result<Vec<u8>, EncodeError> {
+        Ok(match self {
+            WireFormat::Json => serde_json::to_vec(value)?,
+            WireFormat::MessagePack => rmp_serde::to_vec(value)?,
+            WireFormat::Cbor => {
+                let mut encoded = vec![];
+                ciborium::into_writer(value, &mut encoded)?;
+                encoded
+            }
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, encoded: &[u8]) -> Result<T, DecodeError> {
+        Ok(match self {
+            WireFormat::Json => serde_json::from_slice(encoded)?,
+            WireFormat::MessagePack => rmp_serde::from_slice(encoded)?,
+            WireFormat::Cbor => ciborium::from_reader(encoded)?,
+        })
+    }
+}